@@ -0,0 +1,335 @@
+//! Commit the workspace after every successful mutating operation, so reviewers can step
+//! through exactly what the LLM did one commit at a time and cherry-pick the good parts.
+//!
+//! Commits go to a dedicated `<prefix>/<conversation>` branch (started from wherever HEAD is
+//! the first time a conversation changes something) under a bot identity. They're built in a
+//! temporary index and the branch ref is moved directly, so HEAD, the user's index and their
+//! checked out branch are left alone.
+//!
+//! Only the paths an operation changed are committed: the dirty files `git status` reports are
+//! snapshotted before the operation and compared afterwards. Uncommitted edits made by a human
+//! elsewhere in the workspace stay out, though a file both of them edited is committed whole.
+//! Changes a background process makes between operations end up in the commit of whichever
+//! operation is running when they happen, if any.
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
+    error::Error,
+    fs,
+    hash::{Hash, Hasher},
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+};
+
+use rpc::operations::{
+    commands::utils::CommandOptions,
+    fs::utils::AGENT_DIR,
+    git::utils::{run_git, run_git_with},
+};
+
+use crate::settings::Settings;
+
+// The repository's top level directory, which status paths are relative to
+async fn repo_root() -> Result<PathBuf, Box<dyn Error>> {
+    Ok(PathBuf::from(
+        run_git(&["rev-parse", "--show-toplevel"]).await?.trim(),
+    ))
+}
+
+// What `git status` said about each dirty path, with a hash of its content (None if it's gone)
+#[derive(Debug, Default, PartialEq)]
+pub struct Snapshot(BTreeMap<String, (String, Option<u64>)>);
+
+impl Snapshot {
+    pub async fn take() -> Result<Self, Box<dyn Error>> {
+        let root = repo_root().await?;
+        let status = run_git(&[
+            "status",
+            "--porcelain=v1",
+            "-z",
+            "--untracked-files=all",
+            "--no-renames",
+        ])
+        .await?;
+        let mut dirty = Vec::new();
+        for entry in status.split('\0').filter(|e| e.len() > 3) {
+            let (code, path) = entry.split_at(3);
+            // The Agent's own state (e.g. checkpoints) isn't part of the work being reviewed
+            if Path::new(path)
+                .components()
+                .any(|c| c == Component::Normal(AGENT_DIR.as_ref()))
+            {
+                continue;
+            }
+            dirty.push((path.to_string(), code.to_string()));
+        }
+        // Reading every dirty file can take a while in a big workspace
+        let paths = tokio::task::spawn_blocking(move || {
+            dirty
+                .into_iter()
+                .map(|(path, code)| {
+                    let hash = content_hash(&root.join(&path));
+                    (path, (code, hash))
+                })
+                .collect()
+        })
+        .await?;
+        Ok(Self(paths))
+    }
+
+    // Paths whose status or content differs between the snapshots
+    fn changed_since(&self, before: &Snapshot) -> BTreeSet<String> {
+        let paths = before.0.keys().chain(self.0.keys());
+        paths
+            .filter(|path| before.0.get(*path) != self.0.get(*path))
+            .cloned()
+            .collect()
+    }
+}
+
+fn content_hash(path: &Path) -> Option<u64> {
+    let content = match fs::symlink_metadata(path).ok()?.file_type().is_symlink() {
+        true => fs::read_link(path).ok()?.as_os_str().as_bytes().to_vec(),
+        false => fs::read(path).ok()?,
+    };
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+pub struct AutoCommit {
+    branch_prefix: String,
+    author_name: String,
+    author_email: String,
+}
+
+impl AutoCommit {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            branch_prefix: settings.auto_commit_branch_prefix.clone(),
+            author_name: settings.auto_commit_author_name.clone(),
+            author_email: settings.auto_commit_author_email.clone(),
+        }
+    }
+
+    fn branch(&self, conversation_id: Option<&str>) -> String {
+        // Conversation ids come from the LLM integration, keep them to ref-safe characters
+        let name: String = conversation_id
+            .unwrap_or("session")
+            .chars()
+            .map(
+                |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    true => c,
+                    false => '-',
+                },
+            )
+            .collect();
+        format!("{}/{}", self.branch_prefix, name)
+    }
+
+    // Commit hash, or None if the operation didn't change anything since `before`
+    pub async fn commit(
+        &self,
+        conversation_id: Option<&str>,
+        description: &str,
+        before: &Snapshot,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let changed = Snapshot::take().await?.changed_since(before);
+        if changed.is_empty() {
+            return Ok(None);
+        }
+        let reference = format!("refs/heads/{}", self.branch(conversation_id));
+        let branch_head = run_git(&["rev-parse", "--verify", "--quiet", &reference])
+            .await
+            .ok();
+        // HEAD doesn't resolve in a repository without commits, the first commit has no parent
+        let parent = match &branch_head {
+            Some(hash) => Some(hash.trim().to_string()),
+            None => run_git(&["rev-parse", "--verify", "--quiet", "HEAD"])
+                .await
+                .ok()
+                .map(|hash| hash.trim().to_string()),
+        };
+
+        let index = std::env::temp_dir().join(format!("carabiner-index-{}", uuid::Uuid::new_v4()));
+        let root = repo_root().await?;
+        let tree = self
+            .write_tree(&root, &index, parent.as_deref(), &changed)
+            .await;
+        let _ = fs::remove_file(&index);
+        let tree = tree?;
+        if let Some(parent) = &parent {
+            let parent_tree = run_git(&["rev-parse", &format!("{}^{{tree}}", parent)]).await?;
+            if parent_tree.trim() == tree {
+                return Ok(None);
+            }
+        }
+
+        let message = match conversation_id {
+            Some(id) => format!("{}\n\nConversation: {}", description, id),
+            None => description.to_string(),
+        };
+        let mut args = vec!["commit-tree", tree.as_str(), "-m", message.as_str()];
+        if let Some(parent) = &parent {
+            args.extend(["-p", parent.as_str()]);
+        }
+        let identity = CommandOptions {
+            env: [
+                "GIT_AUTHOR_NAME",
+                "GIT_AUTHOR_EMAIL",
+                "GIT_COMMITTER_NAME",
+                "GIT_COMMITTER_EMAIL",
+            ]
+            .iter()
+            .zip([
+                &self.author_name,
+                &self.author_email,
+                &self.author_name,
+                &self.author_email,
+            ])
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect(),
+            ..Default::default()
+        };
+        let hash = run_git_with(&args, &identity).await?.trim().to_string();
+        // Only moves the branch if nothing else did since it was read
+        let mut args = vec!["update-ref", reference.as_str(), hash.as_str()];
+        if let Some(old) = &branch_head {
+            args.push(old.trim());
+        }
+        run_git(&args).await?;
+        Ok(Some(hash))
+    }
+
+    // The parent's tree with the changed paths taken from the working tree
+    async fn write_tree(
+        &self,
+        root: &Path,
+        index: &Path,
+        parent: Option<&str>,
+        changed: &BTreeSet<String>,
+    ) -> Result<String, Box<dyn Error>> {
+        let options = |stdin: Option<Vec<u8>>| CommandOptions {
+            cwd: Some(root.to_path_buf()),
+            env: vec![
+                (
+                    "GIT_INDEX_FILE".to_string(),
+                    index.to_string_lossy().to_string(),
+                ),
+                ("GIT_LITERAL_PATHSPECS".to_string(), "1".to_string()),
+            ],
+            stdin,
+        };
+        match parent {
+            Some(parent) => run_git_with(&["read-tree", parent], &options(None)).await?,
+            None => run_git_with(&["read-tree", "--empty"], &options(None)).await?,
+        };
+        let (present, removed): (Vec<&String>, Vec<&String>) = changed
+            .iter()
+            .partition(|path| fs::symlink_metadata(root.join(path)).is_ok());
+        let nul_separated = |paths: Vec<&String>| -> Vec<u8> {
+            paths
+                .iter()
+                .flat_map(|p| format!("{}\0", p).into_bytes())
+                .collect()
+        };
+        if !present.is_empty() {
+            let args = ["add", "--pathspec-from-file=-", "--pathspec-file-nul"];
+            run_git_with(&args, &options(Some(nul_separated(present)))).await?;
+        }
+        if !removed.is_empty() {
+            let args = ["update-index", "--force-remove", "-z", "--stdin"];
+            run_git_with(&args, &options(Some(nul_separated(removed)))).await?;
+        }
+        Ok(run_git_with(&["write-tree"], &options(None))
+            .await?
+            .trim()
+            .to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rpc::operations::git::utils::current_branch;
+
+    use super::*;
+
+    fn auto_commit() -> AutoCommit {
+        AutoCommit {
+            branch_prefix: "carabiner".to_string(),
+            author_name: "Bot".to_string(),
+            author_email: "bot@example.com".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_branch_name() {
+        let auto_commit = auto_commit();
+        assert_eq!(auto_commit.branch(Some("abc-123")), "carabiner/abc-123");
+        assert_eq!(auto_commit.branch(Some("a b/../c")), "carabiner/a-b----c");
+        assert_eq!(auto_commit.branch(None), "carabiner/session");
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_commit_per_operation() {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        run_git(&["init", "-q", "-b", "main"]).await.unwrap();
+        let auto_commit = auto_commit();
+
+        // A human's uncommitted edit, from before the operation
+        fs::write("notes.txt", "human").unwrap();
+        let before = Snapshot::take().await.unwrap();
+        fs::write("a.txt", "a").unwrap();
+        fs::create_dir(AGENT_DIR).unwrap();
+        fs::write(Path::new(AGENT_DIR).join("state"), "x").unwrap();
+        let first = auto_commit
+            .commit(Some("conv"), "Create a.txt", &before)
+            .await;
+        assert!(first.unwrap().is_some());
+        let before = Snapshot::take().await.unwrap();
+        let nothing = auto_commit
+            .commit(Some("conv"), "Create a.txt", &before)
+            .await;
+        assert!(nothing.unwrap().is_none());
+
+        fs::write("a.txt", "b").unwrap();
+        fs::write("b.txt", "b").unwrap();
+        auto_commit
+            .commit(Some("conv"), "Edit a.txt", &before)
+            .await
+            .unwrap();
+        let before = Snapshot::take().await.unwrap();
+        fs::remove_file("b.txt").unwrap();
+        auto_commit
+            .commit(Some("conv"), "Remove b.txt", &before)
+            .await
+            .unwrap();
+
+        // HEAD and the user's index are untouched
+        assert_eq!(current_branch().await.as_deref(), Some("main"));
+        assert!(run_git(&["rev-parse", "--verify", "--quiet", "HEAD"])
+            .await
+            .is_err());
+        assert_eq!(run_git(&["ls-files"]).await.unwrap(), "");
+
+        let tree = run_git(&["ls-tree", "--name-only", "carabiner/conv"])
+            .await
+            .unwrap();
+        assert_eq!(tree, "a.txt\n");
+        let content = run_git(&["show", "carabiner/conv:a.txt"]).await.unwrap();
+        assert_eq!(content, "b");
+        let log = run_git(&["log", "--format=%an|%s|%b", "carabiner/conv"])
+            .await
+            .unwrap();
+        let log: Vec<&str> = log.lines().filter(|l| !l.is_empty()).collect();
+        assert_eq!(
+            log,
+            vec![
+                "Bot|Remove b.txt|Conversation: conv",
+                "Bot|Edit a.txt|Conversation: conv",
+                "Bot|Create a.txt|Conversation: conv"
+            ]
+        );
+    }
+}
@@ -1,9 +1,18 @@
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tokio::net::TcpStream;
+use tokio::{
+    net::TcpStream,
+    signal::unix::{signal, SignalKind},
+    sync::Mutex,
+};
 use tokio_tungstenite::{connect_async, WebSocketStream};
 use tokio_tungstenite::{tungstenite::protocol::Message, MaybeTlsStream};
+mod auto_commit;
 mod settings;
+mod watch;
+use auto_commit::{AutoCommit, Snapshot};
 use futures_util::{stream::SplitSink, SinkExt, StreamExt};
 use rpc::{RpcMessage, RpcRequest, RpcResponse};
 use settings::get_settings;
@@ -13,31 +22,105 @@ type WebsocketTx = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message
 #[derive(Serialize, Deserialize, Debug)]
 struct PartialRpcMessage {
     id: uuid::Uuid,
+    #[serde(default)]
+    conversation_id: Option<String>,
     payload: Value,
 }
 
-async fn handle_successful_payload(id: uuid::Uuid, payload: RpcRequest, tx: &mut WebsocketTx) {
+async fn handle_successful_payload(
+    id: uuid::Uuid,
+    conversation_id: Option<String>,
+    payload: RpcRequest,
+    tx: &Mutex<WebsocketTx>,
+) {
+    let settings = get_settings();
+    rpc::operations::fs::history::set_session(conversation_id.as_deref());
+    let description = payload.description();
+    // What's dirty before the operation, so only what it changed is committed
+    let snapshot = match (settings.auto_commit, &description) {
+        (true, Some(_)) => match Snapshot::take().await {
+            Ok(snapshot) => Some(snapshot),
+            Err(e) => {
+                println!("Error auto-committing: {}", e);
+                None
+            }
+        },
+        _ => None,
+    };
     let resp = payload.process().await;
-    let resp_msg = RpcMessage { id, payload: resp };
+    if let (Some(snapshot), Some(description)) = (snapshot, description) {
+        if !resp.is_rpc_error() {
+            let auto_commit = AutoCommit::from_settings(settings);
+            match auto_commit
+                .commit(conversation_id.as_deref(), &description, &snapshot)
+                .await
+            {
+                Ok(Some(hash)) => println!("Committed {}: {}", hash, description),
+                Ok(None) => {}
+                Err(e) => println!("Error auto-committing: {}", e),
+            }
+        }
+    }
+    let resp_msg = RpcMessage {
+        id,
+        conversation_id: None,
+        payload: resp,
+    };
     let resp_msg_ser = serde_json::to_string(&resp_msg).unwrap();
-    tx.send(Message::Text(resp_msg_ser)).await.unwrap();
+    tx.lock()
+        .await
+        .send(Message::Text(resp_msg_ser))
+        .await
+        .unwrap();
 }
 
-async fn handle_failed_payload(id: uuid::Uuid, error: serde_json::Error, tx: &mut WebsocketTx) {
+async fn handle_failed_payload(id: uuid::Uuid, error: serde_json::Error, tx: &Mutex<WebsocketTx>) {
     let error_msg = format!("Deserialization error: {:?}", error);
     let resp_msg = RpcMessage {
         id,
+        conversation_id: None,
         payload: RpcResponse::RpcError { e: error_msg },
     };
     let resp_msg_ser = serde_json::to_string(&resp_msg).unwrap();
-    tx.send(Message::Text(resp_msg_ser)).await.unwrap();
+    tx.lock()
+        .await
+        .send(Message::Text(resp_msg_ser))
+        .await
+        .unwrap();
+}
+
+// Commands, background processes and their children run in their own process groups, which a
+// Ctrl-C on the Agent doesn't reach, so they're killed here before exiting
+async fn kill_children_on_exit() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+    rpc::operations::commands::process::kill_all();
+    rpc::operations::commands::utils::kill_process_groups();
+    std::process::exit(130);
 }
 
 #[tokio::main]
 async fn main() {
+    tokio::spawn(kill_children_on_exit());
     let settings = get_settings();
+    rpc::settings::set(settings.operation_settings());
     let (ws_stream, _addr) = connect_async(&settings.rpc_server).await.unwrap();
-    let (mut tx, mut rx) = ws_stream.split();
+    let (tx, mut rx) = ws_stream.split();
+    // Shared with the workspace watcher, which sends notifications outside of the RPC loop
+    let tx = Arc::new(Mutex::new(tx));
+
+    if settings.watch_workspace {
+        let root = std::env::current_dir().unwrap();
+        let watch_tx = tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = watch::watch_workspace(root, watch_tx).await {
+                println!("Error watching workspace: {}", e);
+            }
+        });
+    }
 
     while let Some(msg) = rx.next().await {
         match msg {
@@ -49,9 +132,15 @@ async fn main() {
                     match payload_result {
                         Ok(payload) => {
                             println!("Got RPC message: {:?}", payload);
-                            handle_successful_payload(partial_msg.id, payload, &mut tx).await
+                            handle_successful_payload(
+                                partial_msg.id,
+                                partial_msg.conversation_id,
+                                payload,
+                                &tx,
+                            )
+                            .await
                         }
-                        Err(error) => handle_failed_payload(partial_msg.id, error, &mut tx).await,
+                        Err(error) => handle_failed_payload(partial_msg.id, error, &tx).await,
                     }
                 } else {
                     println!("Got non-RPC message: {}", msg);
@@ -61,4 +150,7 @@ async fn main() {
             _ => println!("Unknown message {:?}", msg),
         }
     }
+    // The session is over, don't leave background processes running without an owner
+    rpc::operations::commands::process::kill_all();
+    rpc::operations::commands::utils::kill_process_groups();
 }
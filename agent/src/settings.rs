@@ -1,4 +1,7 @@
+use std::collections::HashMap;
+
 use lazy_static::lazy_static;
+use rpc::settings::{CommandRule, OperationSettings};
 use serde::{Deserialize, Serialize};
 use url::Url;
 
@@ -14,12 +17,54 @@ pub fn get_settings() -> &'static Settings {
 pub struct Settings {
     #[serde(default = "Settings::default_rpc_server")]
     pub rpc_server: Url,
+    // Push file change notifications for the workspace (the Agent's working directory)
+    #[serde(default)]
+    pub watch_workspace: bool,
+    // Commit every successful mutating operation to a `<prefix>/<conversation>` branch
+    #[serde(default)]
+    pub auto_commit: bool,
+    #[serde(default = "Settings::default_auto_commit_branch_prefix")]
+    pub auto_commit_branch_prefix: String,
+    #[serde(default = "Settings::default_auto_commit_author_name")]
+    pub auto_commit_author_name: String,
+    #[serde(default = "Settings::default_auto_commit_author_email")]
+    pub auto_commit_author_email: String,
+    // Programs RunCommand may run with any arguments, e.g. ALLOWED_PROGRAMS=cargo,pytest
+    #[serde(default)]
+    pub allowed_programs: Vec<String>,
+    // Finer grained rules with argument patterns and the environment variables requests may set,
+    // only settable from the config file
+    #[serde(default)]
+    pub allowed_commands: Vec<CommandRule>,
+    #[serde(default = "Settings::default_max_shells")]
+    pub max_shells: usize,
+    #[serde(default = "Settings::default_shell_idle_timeout_secs")]
+    pub shell_idle_timeout_secs: u64,
+    // e.g. PYTHON_INTERPRETER=.venv/bin/python
+    #[serde(default = "Settings::default_python_interpreter")]
+    pub python_interpreter: String,
+    // Format's command per file extension on top of the defaults, only settable from the config
+    // file, e.g.
+    // [formatters]
+    // py = ["ruff", "format", "--stdin-filename", "{path}", "-"]
+    // An empty command turns off formatting for the extension
+    #[serde(default)]
+    pub formatters: HashMap<String, Vec<String>>,
 }
 
 impl Settings {
     pub fn from_config() -> Self {
-        let builder = config::Config::builder()
-            .add_source(config::Environment::default())
+        let mut builder = config::Config::builder();
+        // Kept outside the workspace on purpose, so the LLM can't edit its own allowlist
+        if let Ok(path) = std::env::var("CARABINER_CONFIG") {
+            builder = builder.add_source(config::File::with_name(&path));
+        }
+        let environment = config::Environment::default()
+            .try_parsing(true)
+            .list_separator(",")
+            .with_list_parse_key("allowed_programs");
+        let builder = builder
+            .add_source(environment)
             .build()
             .expect("Error building settings config from file and env");
         builder
@@ -27,7 +72,54 @@ impl Settings {
             .expect("Error converting config into Settings struct")
     }
 
+    // The part of the settings operations in the rpc crate need
+    pub fn operation_settings(&self) -> OperationSettings {
+        let programs = self.allowed_programs.iter().map(|program| CommandRule {
+            program: program.clone(),
+            args_pattern: None,
+            allowed_env: vec![],
+        });
+        let mut formatters = OperationSettings::default().formatters;
+        formatters.extend(self.formatters.clone());
+        OperationSettings {
+            allowed_commands: self
+                .allowed_commands
+                .iter()
+                .cloned()
+                .chain(programs)
+                .collect(),
+            max_shells: self.max_shells,
+            shell_idle_timeout_secs: self.shell_idle_timeout_secs,
+            python_interpreter: self.python_interpreter.clone(),
+            formatters,
+        }
+    }
+
     pub fn default_rpc_server() -> Url {
         Url::parse("ws://localhost:3000/ws").unwrap()
     }
+
+    pub fn default_max_shells() -> usize {
+        OperationSettings::default().max_shells
+    }
+
+    pub fn default_shell_idle_timeout_secs() -> u64 {
+        OperationSettings::default().shell_idle_timeout_secs
+    }
+
+    pub fn default_python_interpreter() -> String {
+        OperationSettings::default().python_interpreter
+    }
+
+    pub fn default_auto_commit_branch_prefix() -> String {
+        "carabiner".to_string()
+    }
+
+    pub fn default_auto_commit_author_name() -> String {
+        "Carabiner Agent".to_string()
+    }
+
+    pub fn default_auto_commit_author_email() -> String {
+        "agent@carabiner.local".to_string()
+    }
 }
@@ -0,0 +1,463 @@
+//! Watch the workspace for file changes and push them to the server as notifications, so the
+//! LLM can be told when someone edits a file behind its back.
+//!
+//! Events are batched for a short window, which also folds the create / write / rename dance
+//! of atomic saves into a single change. Changes that leave a file in a state the LLM already
+//! knows about (because it read or wrote that content itself), or that happened while a
+//! command it ran was running, are dropped.
+//!
+//! Each directory that isn't ignored gets its own watch, so build output and dependency trees
+//! (target/, node_modules/, .venv/) don't use up watches or flood the Agent with events. Walking
+//! the workspace and reading changed files happens on the blocking thread pool.
+use std::{
+    collections::BTreeSet,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures_util::SinkExt;
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match, Walk, WalkBuilder,
+};
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind, RecursiveMode, Watcher,
+};
+use rpc::{
+    notifications, operations::fs::utils::AGENT_DIR, AgentNotification, FileChangeEvent,
+    FileChangeKind,
+};
+use tokio::sync::{mpsc, Mutex};
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::WebsocketTx;
+
+// How long to keep collecting events after the first one before sending a batch
+const BATCH_WINDOW: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq)]
+struct Change {
+    kind: FileChangeKind,
+    path: PathBuf,
+    old_path: Option<PathBuf>,
+    // Seen while a command the LLM ran was running
+    by_command: bool,
+}
+
+impl Change {
+    fn new(kind: FileChangeKind, path: PathBuf) -> Self {
+        Self {
+            kind,
+            path,
+            old_path: None,
+            by_command: false,
+        }
+    }
+}
+
+// Turn a raw notify event into changes, paths still absolute
+fn changes_from_event((event, by_command): (Event, bool)) -> Vec<Change> {
+    let mut changes = event_changes(event);
+    for change in &mut changes {
+        change.by_command = by_command;
+    }
+    changes
+}
+
+fn event_changes(event: Event) -> Vec<Change> {
+    let kind = match event.kind {
+        EventKind::Create(_) => FileChangeKind::Created,
+        EventKind::Remove(_) => FileChangeKind::Deleted,
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+            return vec![Change {
+                kind: FileChangeKind::Renamed,
+                path: event.paths[1].clone(),
+                old_path: Some(event.paths[0].clone()),
+                by_command: false,
+            }];
+        }
+        // One half of a rename that notify couldn't pair up
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => FileChangeKind::Deleted,
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => FileChangeKind::Created,
+        EventKind::Modify(ModifyKind::Metadata(_)) => return vec![],
+        EventKind::Modify(_) => FileChangeKind::Modified,
+        _ => return vec![],
+    };
+    event
+        .paths
+        .into_iter()
+        .map(|path| Change::new(kind, path))
+        .collect()
+}
+
+// Fold a change into the batch so each path shows up once, e.g. a file created and then
+// written is just "created", and one created and deleted again doesn't show up at all
+fn merge(batch: &mut Vec<Change>, mut change: Change) {
+    if let Some(old_path) = change.old_path.clone() {
+        // Renaming a file that only appeared during this batch (an atomic save's temp file)
+        // is just a write to the destination
+        if let Some(i) = batch.iter().position(|c| c.path == old_path) {
+            if batch[i].kind == FileChangeKind::Created {
+                let by_command = batch.remove(i).by_command || change.by_command;
+                change = Change::new(FileChangeKind::Modified, change.path);
+                change.by_command = by_command;
+            }
+        }
+    }
+    match batch.iter().position(|c| c.path == change.path) {
+        Some(i) => {
+            // Any part of it coming from a command makes the result the LLM's
+            let by_command = batch[i].by_command || change.by_command;
+            match (batch[i].kind, change.kind) {
+                (FileChangeKind::Created, FileChangeKind::Modified) => {}
+                (FileChangeKind::Created, FileChangeKind::Deleted) => {
+                    batch.remove(i);
+                    return;
+                }
+                (FileChangeKind::Deleted, FileChangeKind::Created) => {
+                    batch[i] = Change::new(FileChangeKind::Modified, change.path);
+                }
+                _ => batch[i] = change,
+            }
+            batch[i].by_command = by_command;
+        }
+        None => batch.push(change),
+    }
+}
+
+fn is_ignore_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name == ".gitignore" || name == ".ignore")
+}
+
+struct WorkspaceFilter {
+    root: PathBuf,
+    // One per .gitignore / .ignore file in the workspace, parent directories first
+    ignores: Vec<Gitignore>,
+    // Every directory in the workspace that isn't ignored, the ones to watch
+    directories: BTreeSet<PathBuf>,
+}
+
+// Everything under `dir` that isn't ignored, without .git and the Agent's own directory
+fn walk(dir: &Path) -> Walk {
+    WalkBuilder::new(dir)
+        .hidden(false)
+        .require_git(false)
+        .filter_entry(|entry| entry.file_name() != ".git" && entry.file_name() != AGENT_DIR)
+        .build()
+}
+
+impl WorkspaceFilter {
+    fn new(root: &Path) -> Self {
+        let mut filter = Self {
+            root: root.to_path_buf(),
+            ignores: vec![],
+            directories: BTreeSet::new(),
+        };
+        filter.load_ignores();
+        filter
+    }
+
+    // Find the ignore files and the directories to watch, without looking in directories the
+    // ignore files already ignore
+    fn load_ignores(&mut self) {
+        let mut ignores = Vec::new();
+        let mut directories = BTreeSet::new();
+        for entry in walk(&self.root).flatten() {
+            if entry.file_type().is_some_and(|t| t.is_dir()) {
+                directories.insert(entry.into_path());
+            } else if is_ignore_file(entry.path()) {
+                let dir = entry.path().parent().unwrap_or(&self.root);
+                let mut builder = GitignoreBuilder::new(dir);
+                builder.add(entry.path());
+                if let Ok(gitignore) = builder.build() {
+                    ignores.push(gitignore);
+                }
+            }
+        }
+        ignores.sort_by_key(|gitignore| gitignore.path().components().count());
+        self.ignores = ignores;
+        self.directories = directories;
+    }
+
+    // Keep the directories to watch up to date with directories the batch added or removed
+    fn update_directories(&mut self, batch: &[Change]) {
+        for change in batch {
+            let removed = match change.kind {
+                FileChangeKind::Deleted => Some(&change.path),
+                FileChangeKind::Renamed => change.old_path.as_ref(),
+                _ => None,
+            };
+            if let Some(removed) = removed {
+                self.directories.retain(|dir| !dir.starts_with(removed));
+            }
+            let added = matches!(
+                change.kind,
+                FileChangeKind::Created | FileChangeKind::Renamed
+            ) && change.path.is_dir()
+                && self.relative(&change.path).is_some();
+            if added {
+                let directories = walk(&change.path)
+                    .flatten()
+                    .filter(|entry| entry.file_type().is_some_and(|t| t.is_dir()))
+                    .map(|entry| entry.into_path());
+                self.directories.extend(directories);
+            }
+        }
+    }
+
+    // Update the filter for a batch and build the events to send, on the blocking thread pool
+    fn process(&mut self, batch: Vec<Change>) -> Vec<FileChangeEvent> {
+        match batch.iter().any(|c| is_ignore_file(&c.path)) {
+            true => self.load_ignores(),
+            false => self.update_directories(&batch),
+        }
+        batch.into_iter().filter_map(|c| self.to_event(c)).collect()
+    }
+
+    // The deepest ignore file with a say about the path decides, like git
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for gitignore in self.ignores.iter().rev() {
+            if !path.starts_with(gitignore.path()) {
+                continue;
+            }
+            match gitignore.matched_path_or_any_parents(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+
+    // Path relative to the workspace root, or None if the change should be ignored
+    fn relative(&self, path: &Path) -> Option<PathBuf> {
+        let relative = path.strip_prefix(&self.root).ok()?.to_path_buf();
+        let in_git_dir = relative
+            .components()
+            .any(|c| matches!(c, Component::Normal(name) if name == ".git" || name == AGENT_DIR));
+        let ignored = self.is_ignored(path, path.is_dir());
+        match in_git_dir || ignored {
+            true => None,
+            false => Some(relative),
+        }
+    }
+
+    // Build the event to send, or None if it's ignored or the LLM already knows the result
+    fn to_event(&self, change: Change) -> Option<FileChangeEvent> {
+        let path = self.relative(&change.path)?;
+        let old_path = match &change.old_path {
+            Some(old_path) => Some(self.relative(old_path)?),
+            None => None,
+        };
+        // Files created in a new directory are reported on their own
+        if change.kind != FileChangeKind::Renamed && change.path.is_dir() {
+            return None;
+        }
+        let content = std::fs::read(&change.path).ok();
+        if change.by_command {
+            if let Some(old_path) = &old_path {
+                notifications::remember(old_path, None);
+            }
+            notifications::remember(&path, content.as_deref());
+            return None;
+        }
+        let known = match &old_path {
+            Some(old_path) => {
+                notifications::is_known(old_path, None)
+                    && notifications::is_known(&path, content.as_deref())
+            }
+            None => notifications::is_known(&path, content.as_deref()),
+        };
+        if known {
+            return None;
+        }
+        Some(FileChangeEvent {
+            path: path.to_string_lossy().to_string(),
+            kind: change.kind,
+            old_path: old_path.map(|p| p.to_string_lossy().to_string()),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        })
+    }
+}
+
+// Watch the directories the filter wants watched and stop watching the rest. A directory that
+// was removed in the meantime just fails to be watched.
+fn sync_watches(
+    watcher: &mut impl Watcher,
+    watched: &mut BTreeSet<PathBuf>,
+    directories: &BTreeSet<PathBuf>,
+) {
+    for dir in watched.difference(directories) {
+        let _ = watcher.unwatch(dir);
+    }
+    watched.retain(|dir| directories.contains(dir));
+    let missing: Vec<PathBuf> = directories.difference(watched).cloned().collect();
+    for dir in missing {
+        if watcher.watch(&dir, RecursiveMode::NonRecursive).is_ok() {
+            watched.insert(dir);
+        }
+    }
+}
+
+// Runs until the watcher stops delivering events
+pub async fn watch_workspace(root: PathBuf, tx: Arc<Mutex<WebsocketTx>>) -> notify::Result<()> {
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel::<(Event, bool)>();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+        if let Ok(event) = res {
+            let _ = event_tx.send((event, notifications::command_running()));
+        }
+    })?;
+    let walk_root = root.clone();
+    let mut filter = tokio::task::spawn_blocking(move || WorkspaceFilter::new(&walk_root))
+        .await
+        .map_err(|e| notify::Error::generic(&e.to_string()))?;
+    let mut watched = BTreeSet::new();
+    sync_watches(&mut watcher, &mut watched, &filter.directories);
+    println!(
+        "Watching {:?} for file changes ({} directories)",
+        root,
+        watched.len()
+    );
+
+    while let Some(event) = event_rx.recv().await {
+        let mut batch = Vec::new();
+        changes_from_event(event)
+            .into_iter()
+            .for_each(|c| merge(&mut batch, c));
+        let deadline = tokio::time::sleep(BATCH_WINDOW);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                event = event_rx.recv() => match event {
+                    Some(event) => changes_from_event(event)
+                        .into_iter()
+                        .for_each(|c| merge(&mut batch, c)),
+                    None => break,
+                },
+            }
+        }
+
+        let (processed, events) = tokio::task::spawn_blocking(move || {
+            let events = filter.process(batch);
+            (filter, events)
+        })
+        .await
+        .map_err(|e| notify::Error::generic(&e.to_string()))?;
+        filter = processed;
+        sync_watches(&mut watcher, &mut watched, &filter.directories);
+        if events.is_empty() {
+            continue;
+        }
+        let notification = AgentNotification::FileChanges { events };
+        let msg = serde_json::to_string(&notification).unwrap();
+        if tx.lock().await.send(Message::Text(msg)).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(kind: FileChangeKind, path: &str) -> Change {
+        Change::new(kind, PathBuf::from(path))
+    }
+
+    #[test]
+    fn test_merge_atomic_save() {
+        let mut batch = Vec::new();
+        merge(&mut batch, change(FileChangeKind::Created, ".main.rs.tmp"));
+        merge(&mut batch, change(FileChangeKind::Modified, ".main.rs.tmp"));
+        merge(
+            &mut batch,
+            Change {
+                kind: FileChangeKind::Renamed,
+                path: PathBuf::from("main.rs"),
+                old_path: Some(PathBuf::from(".main.rs.tmp")),
+                by_command: false,
+            },
+        );
+        assert_eq!(batch, vec![change(FileChangeKind::Modified, "main.rs")]);
+    }
+
+    #[test]
+    fn test_merge_created_then_deleted() {
+        let mut batch = vec![change(FileChangeKind::Modified, "a.rs")];
+        merge(&mut batch, change(FileChangeKind::Created, "b.rs"));
+        merge(&mut batch, change(FileChangeKind::Deleted, "b.rs"));
+        merge(&mut batch, change(FileChangeKind::Deleted, "a.rs"));
+        assert_eq!(batch, vec![change(FileChangeKind::Deleted, "a.rs")]);
+    }
+
+    #[test]
+    fn test_filter_ignored_and_known() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::write(root.join(".gitignore"), "target/\n").unwrap();
+        std::fs::create_dir(root.join("target")).unwrap();
+        std::fs::create_dir_all(root.join("web/dist")).unwrap();
+        std::fs::write(root.join("web/.gitignore"), "dist/\n").unwrap();
+        std::fs::write(root.join("main.rs"), "fn main() {}").unwrap();
+        let filter = WorkspaceFilter::new(&root);
+
+        let ignored = change(FileChangeKind::Created, "target/debug");
+        let ignored = Change::new(ignored.kind, root.join(ignored.path));
+        assert!(filter.to_event(ignored).is_none());
+        let git = Change::new(FileChangeKind::Modified, root.join(".git/index"));
+        assert!(filter.to_event(git).is_none());
+        assert_eq!(
+            filter.directories,
+            BTreeSet::from([root.clone(), root.join("web")])
+        );
+        let nested = Change::new(FileChangeKind::Created, root.join("web/dist/app.js"));
+        assert!(filter.to_event(nested).is_none());
+        let directory = Change::new(FileChangeKind::Created, root.join("web"));
+        assert!(filter.to_event(directory).is_none());
+
+        let edited = Change::new(FileChangeKind::Modified, root.join("main.rs"));
+        let event = filter.to_event(edited.clone()).unwrap();
+        assert_eq!(event.path, "main.rs");
+        assert_eq!(event.kind, FileChangeKind::Modified);
+
+        notifications::remember(Path::new("main.rs"), Some(b"fn main() {}"));
+        assert!(filter.to_event(edited).is_none());
+
+        // Made by a command the LLM ran, and known from then on
+        std::fs::write(root.join("built.rs"), "generated").unwrap();
+        let mut built = Change::new(FileChangeKind::Created, root.join("built.rs"));
+        built.by_command = true;
+        assert!(filter.to_event(built.clone()).is_none());
+        built.by_command = false;
+        assert!(filter.to_event(built).is_none());
+    }
+
+    #[test]
+    fn test_filter_tracks_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::write(root.join(".gitignore"), "node_modules/\n").unwrap();
+        let mut filter = WorkspaceFilter::new(&root);
+
+        std::fs::create_dir_all(root.join("src/nested")).unwrap();
+        std::fs::create_dir_all(root.join("node_modules/dep")).unwrap();
+        let events = filter.process(vec![
+            Change::new(FileChangeKind::Created, root.join("src")),
+            Change::new(FileChangeKind::Created, root.join("node_modules")),
+        ]);
+        assert!(events.is_empty());
+        assert_eq!(
+            filter.directories,
+            BTreeSet::from([root.clone(), root.join("src"), root.join("src/nested")])
+        );
+
+        std::fs::remove_dir_all(root.join("src")).unwrap();
+        filter.process(vec![Change::new(FileChangeKind::Deleted, root.join("src"))]);
+        assert_eq!(filter.directories, BTreeSet::from([root.clone()]));
+    }
+}
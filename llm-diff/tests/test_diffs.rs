@@ -1,9 +1,5 @@
 use llm_diff::FileDiff;
-use std::{
-    fs::File,
-    io::{BufRead, BufReader},
-};
-use std::{io::Cursor, path::PathBuf};
+use std::io::{BufRead, Cursor};
 
 #[test]
 fn test_normal_diff() {
@@ -9,9 +9,19 @@
 //! so they can be used as the body for a POST request and automatically documented
 //! in the OpenAPI schema.
 
-use poem::{web::Data, Error};
-use poem_openapi::{param::Path, payload::PlainText, OpenApi};
-use rpc::{ListFilesRequest, ListFilesResponse, ReadFileRequest, ReadFileResponse};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use poem::{web::Data, Body, Error};
+use poem_openapi::{
+    param::{Path, Query},
+    payload::{Attachment, AttachmentType, Binary, PlainText},
+    Object, OpenApi,
+};
+use rpc::{
+    operations::fs::archive::{default_max_bytes, default_max_entries},
+    ExportPatchesRequest, FileChangeEvent, ListFilesRequest, ListFilesResponse, OverwritePolicy,
+    PackArchiveRequest, PatchFormat, ReadFileRequest, ReadFileResponse, UnpackArchiveRequest,
+    UnpackArchiveResponse,
+};
 
 use crate::{
     dependencies::{Conversation, ConversationHeader},
@@ -25,6 +35,11 @@ use crate::{
 // &self), because the ws endpoint needs to get access to them too.
 pub struct Api;
 
+#[derive(Debug, Object)]
+struct FileChangesResponse {
+    events: Vec<FileChangeEvent>,
+}
+
 fn rpc_error(e: rpc::RpcResponse) -> Error {
     let rpc_error = e.into_rpc_error().unwrap();
     Error::from_string(rpc_error.to_string(), poem::http::StatusCode::BAD_REQUEST)
@@ -69,7 +84,7 @@ impl Api {
         conversation: Conversation,
     ) -> poem::Result<RpcPayload<ListFilesResponse>> {
         let req = body.0;
-        let resp = conversation.session.send_rpc(req.into()).await;
+        let resp = conversation.send_rpc(req.into()).await;
         match resp.into_list_files() {
             Ok(resp) => Ok(RpcPayload(resp)),
             Err(e) => Err(rpc_error(e)),
@@ -84,10 +99,97 @@ impl Api {
         conversation: Conversation,
     ) -> poem::Result<RpcPayload<ReadFileResponse>> {
         let req = body.0;
-        let resp = conversation.session.send_rpc(req.into()).await;
+        let resp = conversation.send_rpc(req.into()).await;
         match resp.into_read_file() {
             Ok(resp) => Ok(RpcPayload(resp)),
             Err(e) => Err(rpc_error(e)),
         }
     }
+
+    /// Files created, modified, deleted or renamed on the Agent by something other than the
+    /// LLM, since this was last called. Check it before editing a file you read a while ago.
+    #[oai(path = "/file_changes", method = "post", operation_id = "file_changes")]
+    async fn file_changes(
+        &self,
+        conversation: Conversation,
+    ) -> poem::Result<RpcPayload<FileChangesResponse>> {
+        let events = conversation.session.take_file_changes().await;
+        Ok(RpcPayload(FileChangesResponse { events }))
+    }
+
+    /// Download everything the Agent changed this session as a patch series, one patch per
+    /// operation: a git mbox (apply with `git am`) when git is available, otherwise a unified diff
+    #[oai(path = "/patches", method = "get", operation_id = "export_patches")]
+    async fn export_patches(
+        &self,
+        format: Query<Option<PatchFormat>>,
+        conversation: Conversation,
+    ) -> poem::Result<Attachment<Vec<u8>>> {
+        let req = ExportPatchesRequest {
+            format: format.0.unwrap_or_default(),
+        };
+        let resp = conversation.send_rpc(req.into()).await;
+        let resp = resp.into_export_patches().map_err(rpc_error)?;
+        let filename = match resp.format {
+            PatchFormat::Mbox => "conversation.mbox",
+            _ => "conversation.patch",
+        };
+        Ok(Attachment::new(resp.patch.into_bytes())
+            .attachment_type(AttachmentType::Attachment)
+            .filename(filename))
+    }
+
+    /// Download a directory on the Agent (default the whole workspace) as a tar.gz, without
+    /// .git or anything .gitignore'd
+    #[oai(path = "/archive", method = "get", operation_id = "download_archive")]
+    async fn download_archive(
+        &self,
+        path: Query<Option<String>>,
+        max_bytes: Query<Option<u64>>,
+        conversation: Conversation,
+    ) -> poem::Result<Attachment<Vec<u8>>> {
+        let req = PackArchiveRequest {
+            path: path.0.unwrap_or_else(|| ".".to_string()),
+            max_bytes: max_bytes.0.unwrap_or_else(default_max_bytes),
+        };
+        let resp = conversation.send_rpc(req.into()).await;
+        let resp = resp.into_pack_archive().map_err(rpc_error)?;
+        let archive = STANDARD.decode(resp.archive).map_err(|e| {
+            let s = format!("Agent sent an invalid archive: {}", e);
+            Error::from_string(s, poem::http::StatusCode::BAD_GATEWAY)
+        })?;
+        Ok(Attachment::new(archive)
+            .attachment_type(AttachmentType::Attachment)
+            .filename("workspace.tar.gz"))
+    }
+
+    /// Upload a tar.gz and unpack it into a directory on the Agent (default the workspace root).
+    /// Entries that aren't plain files or directories, or would land outside the destination
+    /// or in .git, reject the whole archive
+    #[oai(path = "/archive", method = "post", operation_id = "upload_archive")]
+    async fn upload_archive(
+        &self,
+        body: Binary<Body>,
+        dest_path: Query<Option<String>>,
+        overwrite: Query<Option<OverwritePolicy>>,
+        conversation: Conversation,
+    ) -> poem::Result<RpcPayload<UnpackArchiveResponse>> {
+        // Read with a limit, the Agent would refuse anything bigger anyway
+        let archive = body
+            .0
+            .into_bytes_limit(default_max_bytes() as usize)
+            .await?;
+        let req = UnpackArchiveRequest {
+            archive: STANDARD.encode(archive),
+            dest_path: dest_path.0.unwrap_or_else(|| ".".to_string()),
+            overwrite: overwrite.0.unwrap_or_default(),
+            max_bytes: default_max_bytes(),
+            max_entries: default_max_entries(),
+        };
+        let resp = conversation.send_rpc(req.into()).await;
+        match resp.into_unpack_archive() {
+            Ok(resp) => Ok(RpcPayload(resp)),
+            Err(e) => Err(rpc_error(e)),
+        }
+    }
 }
@@ -1,5 +1,6 @@
 //! Dependencies used in API route handlers
 use poem::{http::StatusCode, Error, FromRequest, Request, RequestBody, Result};
+use rpc::{RpcRequest, RpcResponse};
 
 use crate::{
     ws::{manager::WsSessionManager, session::WsSession},
@@ -71,3 +72,10 @@ impl<'a> FromRequest<'a> for Conversation {
         })
     }
 }
+
+impl Conversation {
+    // Send an RPC request to the conversation's Agent, tagged with the conversation id
+    pub async fn send_rpc(&self, req: RpcRequest) -> RpcResponse {
+        self.session.send_rpc(Some(self.id.clone()), req).await
+    }
+}
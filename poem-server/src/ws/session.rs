@@ -4,13 +4,19 @@ use std::{collections::HashMap, sync::Arc};
 
 use futures_util::{stream::SplitSink, SinkExt};
 use poem::web::websocket::{Message, WebSocketStream};
-use rpc::{RpcMessage, RpcRequest, RpcResponse};
+use rpc::{AgentNotification, FileChangeEvent, RpcMessage, RpcRequest, RpcResponse};
 use tokio::sync::{oneshot, Mutex};
+
+// Oldest file change events are dropped past this, if nothing is collecting them
+const MAX_FILE_CHANGES: usize = 1000;
+
 #[derive(Clone)]
 pub struct WsSession {
     pub id: uuid::Uuid,
     pub tx: Arc<Mutex<SplitSink<WebSocketStream, Message>>>,
     callbacks: Arc<Mutex<HashMap<uuid::Uuid, oneshot::Sender<RpcResponse>>>>,
+    // File changes the Agent has reported since they were last collected
+    file_changes: Arc<Mutex<Vec<FileChangeEvent>>>,
 }
 
 impl WsSession {
@@ -18,7 +24,13 @@ impl WsSession {
         let id = uuid::Uuid::new_v4();
         let tx = Arc::new(Mutex::new(ws_tx));
         let callbacks = Arc::new(Mutex::new(HashMap::new()));
-        Self { id, tx, callbacks }
+        let file_changes = Arc::new(Mutex::new(Vec::new()));
+        Self {
+            id,
+            tx,
+            callbacks,
+            file_changes,
+        }
     }
 
     pub async fn handle_message(&self, msg: String) {
@@ -35,20 +47,40 @@ impl WsSession {
                     println!("No callback for message: {}", msg.id);
                 }
             }
-            Err(e) => {
-                println!("Error parsing message: {}", e);
+            Err(e) => match serde_json::from_str::<AgentNotification>(&msg) {
+                Ok(notification) => self.handle_notification(notification).await,
+                Err(_) => println!("Error parsing message: {}", e),
+            },
+        }
+    }
+
+    async fn handle_notification(&self, notification: AgentNotification) {
+        match notification {
+            AgentNotification::FileChanges { events } => {
+                let mut file_changes = self.file_changes.lock().await;
+                file_changes.extend(events);
+                if file_changes.len() > MAX_FILE_CHANGES {
+                    let excess = file_changes.len() - MAX_FILE_CHANGES;
+                    file_changes.drain(..excess);
+                }
             }
         }
     }
 
+    // Return the file changes reported since the last call, oldest first
+    pub async fn take_file_changes(&self) -> Vec<FileChangeEvent> {
+        std::mem::take(&mut *self.file_changes.lock().await)
+    }
+
     pub async fn send(&self, text: String) {
         let mut tx = self.tx.lock().await;
         let _ = tx.send(Message::Text(text)).await;
     }
 
-    pub async fn send_rpc(&self, req: RpcRequest) -> RpcResponse {
+    pub async fn send_rpc(&self, conversation_id: Option<String>, req: RpcRequest) -> RpcResponse {
         let msg = RpcMessage {
             id: uuid::Uuid::new_v4(),
+            conversation_id,
             payload: req,
         };
         let msg_se = serde_json::to_string(&msg).unwrap();
@@ -2,23 +2,72 @@
 mod macros;
 use enum_as_inner::EnumAsInner;
 use serde::{Deserialize, Serialize};
+pub mod notifications;
 pub mod operations;
+pub mod settings;
 
+pub use notifications::{AgentNotification, FileChangeEvent, FileChangeKind};
 // re-export of request/responses
 pub use operations::{
+    commands::cargo_check::{
+        CargoCheckRequest, CargoCheckResponse, CargoTool, CompilerDiagnostic, SourceSpan,
+        Suggestion,
+    },
+    commands::format::{FormatRequest, FormatResponse, FormattedFile},
+    commands::process::{
+        ProcessInfo, ProcessKillRequest, ProcessKillResponse, ProcessListRequest,
+        ProcessListResponse, ProcessReadRequest, ProcessReadResponse, ProcessStartRequest,
+        ProcessStartResponse, ProcessStatusRequest, ProcessStatusResponse, ProcessWriteRequest,
+        ProcessWriteResponse,
+    },
+    commands::run_command::{RunCommandRequest, RunCommandResponse},
     commands::run_python::{RunPythonRequest, RunPythonResponse},
-    commands::rustlings::{RustlingsVerifyRequest, RustlingsVerifyResponse},
+    commands::run_tests::{
+        RunTestsRequest, RunTestsResponse, TestCaseResult, TestRunner, TestStatus,
+    },
+    commands::rustlings::{
+        RustlingsExercise, RustlingsHintRequest, RustlingsHintResponse, RustlingsListRequest,
+        RustlingsListResponse, RustlingsRunRequest, RustlingsRunResponse, RustlingsStage,
+        RustlingsVerifyRequest, RustlingsVerifyResponse,
+    },
+    commands::shell::{
+        ShellCloseRequest, ShellCloseResponse, ShellExecRequest, ShellExecResponse,
+        ShellOpenRequest, ShellOpenResponse,
+    },
     fs::{
+        apply_edits::{ApplyEditsRequest, ApplyEditsResponse, FileEdit, FileEditResult},
+        archive::{
+            PackArchiveRequest, PackArchiveResponse, UnpackArchiveRequest, UnpackArchiveResponse,
+        },
+        checkpoint::{
+            CheckpointInfo, CheckpointRequest, CheckpointResponse, ListCheckpointsRequest,
+            ListCheckpointsResponse, RestoreRequest, RestoreResponse,
+        },
+        copy_path::{CopyPathRequest, CopyPathResponse, OverwritePolicy},
         create_directory::{CreateDirectoryRequest, CreateDirectoryResponse},
         create_file::{CreateFileRequest, CreateFileResponse},
         delete_content::{DeleteContentRequest, DeleteContentResponse},
         diff::{DiffRequest, DiffResponse},
+        export_patches::{ExportPatchesRequest, ExportPatchesResponse, PatchFormat},
         insert_content::{InsertContentRequest, InsertContentResponse},
         list_files::{ListFilesRequest, ListFilesResponse},
         move_file::{MoveFileRequest, MoveFileResponse},
+        outline::{FileOutline, OutlineRequest, OutlineResponse, Symbol},
         read_file::{ReadFileRequest, ReadFileResponse},
+        remove_directory::{RemoveDirectoryRequest, RemoveDirectoryResponse},
         remove_file::{RemoveFileRequest, RemoveFileResponse},
         replace_content::{ReplaceContentRequest, ReplaceContentResponse},
+        stat::{PathStat, StatRequest, StatResponse},
+        str_replace::{StrReplaceRequest, StrReplaceResponse},
+        undo::{HistoryItem, RedoRequest, RedoResponse, UndoRequest, UndoResponse},
+    },
+    git::{
+        branch::{GitBranchInfo, GitBranchRequest, GitBranchResponse},
+        checkout::{GitCheckoutRequest, GitCheckoutResponse},
+        commit::{GitCommitRequest, GitCommitResponse},
+        diff::{GitDiffFile, GitDiffRequest, GitDiffResponse},
+        log::{GitCommitInfo, GitLogRequest, GitLogResponse},
+        status::{GitFileStatus, GitStatusEntry, GitStatusRequest, GitStatusResponse},
     },
     time::{SystemTimeRequest, SystemTimeResponse},
 };
@@ -26,6 +75,9 @@ pub use operations::{
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RpcMessage<T> {
     pub id: uuid::Uuid,
+    // Set by the server on requests, so the Agent can tell conversations apart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation_id: Option<String>,
     pub payload: T,
 }
 
@@ -33,19 +85,159 @@ define_rpc!(
     // Directory operations
     ListFiles(ListFilesRequest, ListFilesResponse),
     CreateDirectory(CreateDirectoryRequest, CreateDirectoryResponse),
+    RemoveDirectory(RemoveDirectoryRequest, RemoveDirectoryResponse),
     // File CRUD
     CreateFile(CreateFileRequest, CreateFileResponse),
     ReadFile(ReadFileRequest, ReadFileResponse),
     MoveFile(MoveFileRequest, MoveFileResponse),
+    CopyPath(CopyPathRequest, CopyPathResponse),
     RemoveFile(RemoveFileRequest, RemoveFileResponse),
+    Stat(StatRequest, StatResponse),
+    PackArchive(PackArchiveRequest, PackArchiveResponse),
+    UnpackArchive(UnpackArchiveRequest, UnpackArchiveResponse),
+    Outline(OutlineRequest, OutlineResponse),
     // edit file content
     Diff(DiffRequest, DiffResponse),
     InsertContent(InsertContentRequest, InsertContentResponse),
     ReplaceContent(ReplaceContentRequest, ReplaceContentResponse),
     DeleteContent(DeleteContentRequest, DeleteContentResponse),
+    StrReplace(StrReplaceRequest, StrReplaceResponse),
+    ApplyEdits(ApplyEditsRequest, ApplyEditsResponse),
+    // history
+    Undo(UndoRequest, UndoResponse),
+    Redo(RedoRequest, RedoResponse),
+    Checkpoint(CheckpointRequest, CheckpointResponse),
+    ListCheckpoints(ListCheckpointsRequest, ListCheckpointsResponse),
+    Restore(RestoreRequest, RestoreResponse),
+    ExportPatches(ExportPatchesRequest, ExportPatchesResponse),
+    // git
+    GitStatus(GitStatusRequest, GitStatusResponse),
+    GitDiff(GitDiffRequest, GitDiffResponse),
+    GitLog(GitLogRequest, GitLogResponse),
+    GitCommit(GitCommitRequest, GitCommitResponse),
+    GitCheckout(GitCheckoutRequest, GitCheckoutResponse),
+    GitBranch(GitBranchRequest, GitBranchResponse),
     // debug / demo
     SystemTime(SystemTimeRequest, SystemTimeResponse),
     // commands
+    RunCommand(RunCommandRequest, RunCommandResponse),
     RunPython(RunPythonRequest, RunPythonResponse),
+    RunTests(RunTestsRequest, RunTestsResponse),
+    CargoCheck(CargoCheckRequest, CargoCheckResponse),
+    Format(FormatRequest, FormatResponse),
+    ShellOpen(ShellOpenRequest, ShellOpenResponse),
+    ShellExec(ShellExecRequest, ShellExecResponse),
+    ShellClose(ShellCloseRequest, ShellCloseResponse),
+    ProcessStart(ProcessStartRequest, ProcessStartResponse),
+    ProcessRead(ProcessReadRequest, ProcessReadResponse),
+    ProcessWrite(ProcessWriteRequest, ProcessWriteResponse),
+    ProcessStatus(ProcessStatusRequest, ProcessStatusResponse),
+    ProcessKill(ProcessKillRequest, ProcessKillResponse),
+    ProcessList(ProcessListRequest, ProcessListResponse),
+    RustlingsList(RustlingsListRequest, RustlingsListResponse),
+    RustlingsHint(RustlingsHintRequest, RustlingsHintResponse),
+    RustlingsRun(RustlingsRunRequest, RustlingsRunResponse),
     RustlingsVerify(RustlingsVerifyRequest, RustlingsVerifyResponse)
 );
+
+impl RpcRequest {
+    // Summary of what a mutating operation does, used as the commit message when the Agent
+    // auto-commits. None for operations that don't change the workspace. Operations running a
+    // program get a summary of what they ran, since what it changed isn't known up front.
+    pub fn description(&self) -> Option<String> {
+        let description = match self {
+            RpcRequest::CreateFile(req) => format!("Create {}", req.path),
+            RpcRequest::MoveFile(req) => format!("Move {} to {}", req.src_path, req.dest_path),
+            RpcRequest::CopyPath(req) => format!("Copy {} to {}", req.src_path, req.dest_path),
+            RpcRequest::RemoveFile(req) => format!("Remove {}", req.path),
+            RpcRequest::RemoveDirectory(req) => format!("Remove directory {}", req.path),
+            RpcRequest::UnpackArchive(req) => format!("Unpack archive into {}", req.dest_path),
+            RpcRequest::Diff(req) => match req.commit_msg.trim() {
+                "" => format!("Apply diff to {}", req.path),
+                commit_msg => commit_msg.to_string(),
+            },
+            RpcRequest::InsertContent(req) => {
+                format!("Insert content at line {} of {}", req.line, req.path)
+            }
+            RpcRequest::ReplaceContent(req) => {
+                format!(
+                    "Replace content from line {} of {}",
+                    req.start_line, req.path
+                )
+            }
+            RpcRequest::DeleteContent(req) => {
+                format!(
+                    "Delete content from line {} of {}",
+                    req.start_line, req.path
+                )
+            }
+            RpcRequest::StrReplace(req) => format!("Replace text in {}", req.path),
+            RpcRequest::ApplyEdits(req) => format!("Apply {} edits", req.edits.len()),
+            RpcRequest::Undo(req) => format!("Undo {} operation(s)", req.steps),
+            RpcRequest::Redo(req) => format!("Redo {} operation(s)", req.steps),
+            RpcRequest::Restore(req) => format!("Restore checkpoint {}", req.id),
+            RpcRequest::CargoCheck(req) if req.fix => match &req.path {
+                Some(path) => format!("Apply compiler suggestions in {}", path),
+                None => "Apply compiler suggestions".to_string(),
+            },
+            RpcRequest::RunCommand(req) => {
+                format!(
+                    "Run {}",
+                    [std::slice::from_ref(&req.program), &req.args[..]]
+                        .concat()
+                        .join(" ")
+                )
+            }
+            RpcRequest::RunPython(req) => match &req.path {
+                Some(path) => format!("Run {}", path),
+                None => "Run Python code".to_string(),
+            },
+            RpcRequest::ShellExec(req) => format!("Run `{}` in a shell", req.command),
+            RpcRequest::ProcessStart(req) => format!("Start {}", req.program),
+            RpcRequest::GitCheckout(req) => format!("Check out {}", req.target),
+            RpcRequest::RustlingsRun(req) => format!("Run rustlings exercise {}", req.name),
+            RpcRequest::Format(req) if !req.check => format!("Format {}", req.paths.join(", ")),
+            _ => return None,
+        };
+        Some(description)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_description() {
+        let diff = DiffRequest {
+            commit_msg: "Fix off by one in parser".to_string(),
+            path: "src/parser.rs".to_string(),
+            diff_str: String::new(),
+            expected_hash: None,
+        };
+        let request = RpcRequest::from(diff);
+        assert_eq!(
+            request.description().as_deref(),
+            Some("Fix off by one in parser")
+        );
+
+        let remove = RpcRequest::from(RemoveFileRequest {
+            path: "notes.txt".to_string(),
+        });
+        assert_eq!(remove.description().as_deref(), Some("Remove notes.txt"));
+
+        let read = RpcRequest::from(ReadFileRequest {
+            path: "notes.txt".to_string(),
+        });
+        assert_eq!(read.description(), None);
+
+        let run = RpcRequest::from(RunCommandRequest {
+            program: "cargo".to_string(),
+            args: vec!["fmt".to_string()],
+            cwd: None,
+            env: Default::default(),
+            timeout_secs: 60,
+        });
+        assert_eq!(run.description().as_deref(), Some("Run cargo fmt"));
+    }
+}
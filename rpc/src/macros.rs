@@ -16,6 +16,7 @@ macro_rules! define_rpc {
 
         impl RpcRequest {
             pub async fn process(self) -> RpcResponse {
+                $crate::operations::fs::history::set_description(self.description());
                 match self {
                     $(
                         RpcRequest::$variant(req) => {
@@ -0,0 +1,177 @@
+//! Unsolicited messages the Agent pushes to the server, outside of the RPC request / response
+//! flow. Currently just workspace file changes, so the server can warn the LLM that a file it
+//! read has since been edited by someone else.
+//!
+//! To keep the Agent's own edits out of those notifications, every file state the LLM has seen
+//! (through a read) or produced (through a mutating operation) is remembered here. A change
+//! that leaves a file in a known state isn't reported.
+//!
+//! Commands the LLM runs (RunCommand, GitCheckout, ShellExec, ...) can change any file, so
+//! changes seen while one is running are taken to be the LLM's too. A human's edit made at
+//! the same time is missed. Background processes aren't covered, their changes are reported.
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+use crate::operations::fs::utils::content_hash;
+
+// The least recently remembered half is forgotten past this, a forgotten file's next change
+// is reported even if the LLM made it
+const MAX_KNOWN: usize = 10_000;
+
+// How long after a command finishes its changes may still be arriving from the file watcher
+const COMMAND_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Known {
+    // Relative path -> hash of the content the LLM knows about (None if it knows the file is
+    // gone), and when it was remembered
+    files: HashMap<PathBuf, (Option<String>, u64)>,
+    counter: u64,
+}
+
+#[derive(Default)]
+struct Commands {
+    running: usize,
+    last_finished: Option<Instant>,
+}
+
+lazy_static! {
+    static ref KNOWN: Mutex<Known> = Mutex::new(Known::default());
+    static ref COMMANDS: Mutex<Commands> = Mutex::new(Commands::default());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum FileChangeKind {
+    Created,
+    Modified,
+    Deleted,
+    Renamed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Object)]
+pub struct FileChangeEvent {
+    // Relative to the workspace root
+    pub path: String,
+    pub kind: FileChangeKind,
+    // Only set for renames
+    pub old_path: Option<String>,
+    pub timestamp: String,
+}
+
+// Tagged with "notification" rather than "type", so the server can't mistake these for RPC
+// responses
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "notification")]
+pub enum AgentNotification {
+    FileChanges { events: Vec<FileChangeEvent> },
+}
+
+// Drop `.` components so "./src/main.rs" and "src/main.rs" share a key
+fn key(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect()
+}
+
+// Record that the LLM has seen, or produced, this content (None for a missing file)
+pub fn remember(path: &Path, content: Option<&[u8]>) {
+    let mut known = KNOWN.lock().unwrap();
+    known.counter += 1;
+    let entry = (content.map(content_hash), known.counter);
+    known.files.insert(key(path), entry);
+    if known.files.len() > MAX_KNOWN {
+        let oldest = known.counter - (MAX_KNOWN / 2) as u64;
+        known
+            .files
+            .retain(|_, (_, remembered)| *remembered > oldest);
+    }
+}
+
+// Whether the file's current content (None for a missing file) is what the LLM already knows
+pub fn is_known(path: &Path, content: Option<&[u8]>) -> bool {
+    match KNOWN.lock().unwrap().files.get(&key(path)) {
+        Some((known, _)) => known.as_deref() == content.map(content_hash).as_deref(),
+        None => false,
+    }
+}
+
+pub fn forget_all() {
+    KNOWN.lock().unwrap().files.clear();
+}
+
+// Held while a command the LLM asked for is running, see `command_running`
+pub struct CommandGuard(());
+
+impl Drop for CommandGuard {
+    fn drop(&mut self) {
+        let mut commands = COMMANDS.lock().unwrap();
+        commands.running -= 1;
+        commands.last_finished = Some(Instant::now());
+    }
+}
+
+pub fn command_started() -> CommandGuard {
+    COMMANDS.lock().unwrap().running += 1;
+    CommandGuard(())
+}
+
+// Whether a change seen now could have been made by a command the LLM ran
+pub fn command_running() -> bool {
+    let commands = COMMANDS.lock().unwrap();
+    let recent = commands
+        .last_finished
+        .is_some_and(|finished| finished.elapsed() < COMMAND_GRACE_PERIOD);
+    commands.running > 0 || recent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[serial_test::serial]
+    fn test_known_state() {
+        forget_all();
+        assert!(!is_known(Path::new("a.txt"), Some(b"a")));
+
+        remember(Path::new("./a.txt"), Some(b"a"));
+        assert!(is_known(Path::new("a.txt"), Some(b"a")));
+        assert!(!is_known(Path::new("a.txt"), Some(b"b")));
+        assert!(!is_known(Path::new("a.txt"), None));
+
+        remember(Path::new("a.txt"), None);
+        assert!(is_known(Path::new("a.txt"), None));
+
+        for i in 0..MAX_KNOWN {
+            remember(&PathBuf::from(format!("{}.txt", i)), None);
+        }
+        assert!(!is_known(Path::new("a.txt"), None));
+        assert!(is_known(Path::new("9999.txt"), None));
+        assert!(KNOWN.lock().unwrap().files.len() <= MAX_KNOWN);
+    }
+
+    #[test]
+    fn test_notification_format() {
+        let notification = AgentNotification::FileChanges {
+            events: vec![FileChangeEvent {
+                path: "src/main.rs".to_string(),
+                kind: FileChangeKind::Modified,
+                old_path: None,
+                timestamp: "2023-10-01T00:00:00+00:00".to_string(),
+            }],
+        };
+        let value = serde_json::to_value(&notification).unwrap();
+        assert_eq!(value["notification"], "FileChanges");
+        assert_eq!(value["events"][0]["kind"], "modified");
+    }
+}
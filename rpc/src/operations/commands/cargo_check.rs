@@ -0,0 +1,500 @@
+//! `cargo check` / `cargo clippy` with the compiler's JSON diagnostics, so the LLM gets each
+//! error's location and suggested fix as data and can edit the code directly.
+//!
+//! With `fix` the suggestions rustc marks machine-applicable are applied here rather than with
+//! `cargo fix`, so they go through the history journal and can be undone like any other edit.
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+
+use crate::operations::{
+    commands::{
+        run_command::{resolve_cwd, MAX_TIMEOUT_SECS},
+        utils::{
+            run_command, tail, CommandOptions, CommandOutcome, CommandResult, MAX_OUTPUT_BYTES,
+        },
+    },
+    fs::{
+        history::{self, FileChange},
+        utils::{ensure_not_protected, write_atomic},
+    },
+};
+
+fn default_timeout_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum CargoTool {
+    #[default]
+    Check,
+    Clippy,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct CargoCheckRequest {
+    #[oai(default)]
+    #[serde(default)]
+    pub tool: CargoTool,
+    // Project directory inside the workspace, the workspace root if not set
+    pub path: Option<String>,
+    // Also check tests, examples and benches
+    #[oai(default)]
+    #[serde(default)]
+    pub all_targets: bool,
+    // Apply the machine-applicable suggestions, then check again
+    #[oai(default)]
+    #[serde(default)]
+    pub fix: bool,
+    #[oai(default = "default_timeout_secs")]
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+// Relative to the workspace when the file is inside it, e.g. not for dependencies' sources
+#[derive(Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct SourceSpan {
+    pub file: String,
+    pub line_start: u32,
+    pub column_start: u32,
+    pub line_end: u32,
+    pub column_end: u32,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct Suggestion {
+    pub span: SourceSpan,
+    pub replacement: String,
+    // MachineApplicable, MaybeIncorrect, HasPlaceholders or Unspecified
+    pub applicability: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct CompilerDiagnostic {
+    // error, warning, ...
+    pub level: String,
+    // E0308, unused_variables, clippy::needless_return, ...
+    pub code: Option<String>,
+    pub message: String,
+    // The primary span
+    pub span: Option<SourceSpan>,
+    pub suggestions: Vec<Suggestion>,
+    // The diagnostic as the compiler prints it, with the source snippet
+    pub rendered: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct CargoCheckResponse {
+    pub success: bool,
+    pub errors: usize,
+    pub warnings: usize,
+    pub diagnostics: Vec<CompilerDiagnostic>,
+    // Suggestions applied with fix, diagnostics are from checking again afterwards
+    pub applied: usize,
+    pub fixed_files: Vec<String>,
+    pub outcome: CommandOutcome,
+    pub exit_status: Option<i32>,
+    pub signal: Option<i32>,
+    // End of cargo's stderr, for failures that aren't compiler diagnostics
+    pub output: String,
+}
+
+// The parts of cargo's --message-format=json output that are used
+#[derive(Debug, Deserialize)]
+struct CargoMessage {
+    reason: String,
+    message: Option<RustcDiagnostic>,
+    success: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RustcDiagnostic {
+    message: String,
+    code: Option<RustcCode>,
+    level: String,
+    spans: Vec<RustcSpan>,
+    children: Vec<RustcDiagnostic>,
+    rendered: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RustcCode {
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RustcSpan {
+    file_name: String,
+    byte_start: usize,
+    byte_end: usize,
+    line_start: u32,
+    line_end: u32,
+    column_start: u32,
+    column_end: u32,
+    is_primary: bool,
+    suggested_replacement: Option<String>,
+    suggestion_applicability: Option<String>,
+}
+
+// Where rustc's (cargo workspace relative) file names are resolved against the Agent's workspace
+struct Paths {
+    cargo_root: PathBuf,
+    workspace: PathBuf,
+}
+
+impl Paths {
+    fn absolute(&self, file_name: &str) -> PathBuf {
+        self.cargo_root.join(file_name)
+    }
+
+    fn relative(&self, file_name: &str) -> Option<PathBuf> {
+        let path = self.absolute(file_name);
+        path.strip_prefix(&self.workspace)
+            .ok()
+            .map(|p| p.to_path_buf())
+    }
+
+    fn display(&self, file_name: &str) -> String {
+        match self.relative(file_name) {
+            Some(path) => path.to_string_lossy().to_string(),
+            None => file_name.to_string(),
+        }
+    }
+
+    fn span(&self, span: &RustcSpan) -> SourceSpan {
+        SourceSpan {
+            file: self.display(&span.file_name),
+            line_start: span.line_start,
+            column_start: span.column_start,
+            line_end: span.line_end,
+            column_end: span.column_end,
+        }
+    }
+}
+
+// Suggestions are spread over the diagnostic's own spans and its children's (the "help: ..."
+// notes). Each group's edits only make sense applied together.
+fn suggestion_groups(diagnostic: &RustcDiagnostic) -> Vec<Vec<&RustcSpan>> {
+    let mut groups = Vec::new();
+    for d in std::iter::once(diagnostic).chain(diagnostic.children.iter()) {
+        let spans: Vec<&RustcSpan> = d
+            .spans
+            .iter()
+            .filter(|s| s.suggested_replacement.is_some())
+            .collect();
+        if !spans.is_empty() {
+            groups.push(spans);
+        }
+    }
+    groups
+}
+
+fn diagnostic(paths: &Paths, diagnostic: &RustcDiagnostic) -> CompilerDiagnostic {
+    let suggestions = suggestion_groups(diagnostic)
+        .into_iter()
+        .flatten()
+        .map(|span| Suggestion {
+            span: paths.span(span),
+            replacement: span.suggested_replacement.clone().unwrap_or_default(),
+            applicability: span.suggestion_applicability.clone(),
+        })
+        .collect();
+    CompilerDiagnostic {
+        level: diagnostic.level.clone(),
+        code: diagnostic.code.as_ref().map(|c| c.code.clone()),
+        message: diagnostic.message.clone(),
+        span: diagnostic
+            .spans
+            .iter()
+            .find(|s| s.is_primary)
+            .map(|s| paths.span(s)),
+        suggestions,
+        rendered: diagnostic.rendered.clone(),
+    }
+}
+
+// Compiler diagnostics and cargo's overall result. The "aborting due to" / "N warnings emitted"
+// summaries are left out, as are repeats from checking the same file for several targets.
+fn parse_messages(stdout: &str) -> (Vec<RustcDiagnostic>, Option<bool>) {
+    let mut diagnostics: Vec<RustcDiagnostic> = Vec::new();
+    let mut success = None;
+    for line in stdout.lines() {
+        let message: CargoMessage = match serde_json::from_str(line) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        match (message.reason.as_str(), message.message) {
+            ("compiler-message", Some(d)) => {
+                if d.spans.is_empty() && d.code.is_none() {
+                    continue;
+                }
+                if diagnostics.iter().any(|other| other.rendered == d.rendered) {
+                    continue;
+                }
+                diagnostics.push(d);
+            }
+            ("build-finished", _) => success = message.success,
+            _ => {}
+        }
+    }
+    (diagnostics, success)
+}
+
+struct Edit {
+    start: usize,
+    end: usize,
+    replacement: String,
+}
+
+impl Edit {
+    // Two insertions at the same point conflict too
+    fn overlaps(&self, span: &RustcSpan) -> bool {
+        (self.start < span.byte_end && span.byte_start < self.end) || self.start == span.byte_start
+    }
+}
+
+// Apply the machine-applicable suggestions inside the workspace, skipping any that overlap one
+// already taken. Returns how many suggestions were applied and the files changed.
+fn apply_suggestions(
+    paths: &Paths,
+    diagnostics: &[RustcDiagnostic],
+) -> Result<(usize, Vec<String>), Box<dyn Error>> {
+    let mut edits: BTreeMap<PathBuf, Vec<Edit>> = BTreeMap::new();
+    let mut applied = 0;
+    for group in diagnostics.iter().flat_map(suggestion_groups) {
+        let machine_applicable = group
+            .iter()
+            .all(|s| s.suggestion_applicability.as_deref() == Some("MachineApplicable"));
+        if !machine_applicable || group.iter().any(|s| paths.relative(&s.file_name).is_none()) {
+            continue;
+        }
+        let overlaps = group.iter().any(|s| {
+            let file_edits = edits.get(&paths.relative(&s.file_name).unwrap());
+            file_edits.is_some_and(|e| e.iter().any(|e| e.overlaps(s)))
+        });
+        if overlaps {
+            continue;
+        }
+        for span in group {
+            let path = paths.relative(&span.file_name).unwrap();
+            edits.entry(path).or_default().push(Edit {
+                start: span.byte_start,
+                end: span.byte_end,
+                replacement: span.suggested_replacement.clone().unwrap_or_default(),
+            });
+        }
+        applied += 1;
+    }
+
+    // Every file is edited in memory before anything is written
+    let mut edited = Vec::new();
+    for (path, mut file_edits) in edits {
+        ensure_not_protected(&path)?;
+        let before = history::snapshot(&path);
+        let mut content = fs::read(&path)?;
+        file_edits.sort_by_key(|e| std::cmp::Reverse(e.start));
+        for edit in file_edits {
+            if edit.end > content.len() {
+                return Err(format!("{:?} changed while it was being checked", path).into());
+            }
+            content.splice(edit.start..edit.end, edit.replacement.into_bytes());
+        }
+        edited.push((path, before, content));
+    }
+    let mut changes = Vec::new();
+    let mut written = Ok(());
+    for (path, before, content) in edited {
+        if let Err(e) = write_atomic(&path, &content) {
+            written = Err(format!("Couldn't write {:?}: {}", path, e));
+            break;
+        }
+        changes.push(FileChange::new(&path, before));
+    }
+    let fixed_files = changes
+        .iter()
+        .map(|c| c.path.to_string_lossy().to_string())
+        .collect();
+    // Recorded even if a write failed, so the files already changed can be undone
+    if !changes.is_empty() {
+        history::record("CargoCheck", changes);
+    }
+    written?;
+    Ok((applied, fixed_files))
+}
+
+impl CargoCheckRequest {
+    fn args(&self) -> Vec<&str> {
+        let mut args = match self.tool {
+            CargoTool::Check => vec!["check"],
+            CargoTool::Clippy => vec!["clippy"],
+        };
+        args.push("--message-format=json");
+        if self.all_targets {
+            args.push("--all-targets");
+        }
+        args
+    }
+
+    pub async fn process(self) -> Result<CargoCheckResponse, Box<dyn Error>> {
+        if self.timeout_secs == 0 || self.timeout_secs > MAX_TIMEOUT_SECS {
+            return Err(format!("timeout_secs must be between 1 and {}", MAX_TIMEOUT_SECS).into());
+        }
+        let options = CommandOptions {
+            cwd: match &self.path {
+                Some(path) => Some(resolve_cwd(path).await?),
+                None => None,
+            },
+            ..Default::default()
+        };
+        let timeout_duration = Duration::from_secs(self.timeout_secs);
+        let located = run_command(
+            "cargo",
+            &["locate-project", "--workspace", "--message-format", "plain"],
+            &options,
+            timeout_duration,
+        )
+        .await?;
+        if located.exit_status != Some(0) {
+            return Err(format!("No Cargo project found: {}", located.stderr.trim()).into());
+        }
+        let manifest = PathBuf::from(located.stdout.trim());
+        let paths = Paths {
+            cargo_root: manifest.parent().unwrap_or(Path::new("/")).canonicalize()?,
+            workspace: std::env::current_dir()?.canonicalize()?,
+        };
+
+        let mut result = run_command("cargo", &self.args(), &options, timeout_duration).await?;
+        let (mut diagnostics, mut success) = parse_messages(&result.stdout);
+        let (mut applied, mut fixed_files) = (0, vec![]);
+        if self.fix {
+            (applied, fixed_files) = apply_suggestions(&paths, &diagnostics)?;
+            if applied > 0 {
+                result = run_command("cargo", &self.args(), &options, timeout_duration).await?;
+                (diagnostics, success) = parse_messages(&result.stdout);
+            }
+        }
+
+        let CommandResult {
+            stderr,
+            outcome,
+            exit_status,
+            signal,
+            ..
+        } = result;
+        let count = |level: &str| diagnostics.iter().filter(|d| d.level == level).count();
+        let (errors, warnings) = (count("error"), count("warning"));
+        let success = success.unwrap_or(exit_status == Some(0));
+        Ok(CargoCheckResponse {
+            success,
+            errors,
+            warnings,
+            diagnostics: diagnostics.iter().map(|d| diagnostic(&paths, d)).collect(),
+            applied,
+            fixed_files,
+            outcome,
+            exit_status,
+            signal,
+            output: match success {
+                true => String::new(),
+                false => tail(&stderr, MAX_OUTPUT_BYTES),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        history::clear();
+        fs::create_dir_all("demo/src").unwrap();
+        fs::write(
+            "demo/Cargo.toml",
+            "[package]\nname = \"demo\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    fn request(fix: bool) -> CargoCheckRequest {
+        CargoCheckRequest {
+            tool: CargoTool::Check,
+            path: Some("demo".to_string()),
+            all_targets: false,
+            fix,
+            timeout_secs: default_timeout_secs(),
+        }
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_cargo_check_error(_tmp_dir: TempDir) {
+        fs::write("demo/src/lib.rs", "pub fn one() -> u32 {\n    \"one\"\n}\n").unwrap();
+        let response = request(false).process().await.unwrap();
+        assert!(!response.success);
+        assert_eq!((response.errors, response.warnings), (1, 0));
+        let diagnostic = &response.diagnostics[0];
+        assert_eq!(diagnostic.level, "error");
+        assert_eq!(diagnostic.code.as_deref(), Some("E0308"));
+        assert_eq!(
+            diagnostic.span,
+            Some(SourceSpan {
+                file: "demo/src/lib.rs".to_string(),
+                line_start: 2,
+                column_start: 5,
+                line_end: 2,
+                column_end: 10,
+            })
+        );
+        assert!(diagnostic.rendered.as_ref().unwrap().contains("\"one\""));
+
+        let mut request = request(false);
+        request.timeout_secs = MAX_TIMEOUT_SECS + 1;
+        assert!(request.process().await.is_err());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_cargo_check_fix(_tmp_dir: TempDir) {
+        let lib = "pub fn f() {\n    let x = 1;\n}\n";
+        fs::write("demo/src/lib.rs", lib).unwrap();
+        let response = request(false).process().await.unwrap();
+        assert!(response.success);
+        assert_eq!(response.warnings, 1);
+        let diagnostic = &response.diagnostics[0];
+        assert_eq!(diagnostic.code.as_deref(), Some("unused_variables"));
+        assert_eq!(diagnostic.suggestions[0].replacement, "_x");
+        assert_eq!(
+            diagnostic.suggestions[0].applicability.as_deref(),
+            Some("MachineApplicable")
+        );
+        assert_eq!(fs::read_to_string("demo/src/lib.rs").unwrap(), lib);
+
+        let response = request(true).process().await.unwrap();
+        assert_eq!(response.applied, 1);
+        assert_eq!(response.fixed_files, vec!["demo/src/lib.rs"]);
+        assert!(response.diagnostics.is_empty());
+        assert_eq!(
+            fs::read_to_string("demo/src/lib.rs").unwrap(),
+            "pub fn f() {\n    let _x = 1;\n}\n"
+        );
+        assert_eq!(history::entries().len(), 1);
+    }
+}
@@ -0,0 +1,240 @@
+//! Run the formatter configured for each file's extension, so the LLM doesn't have to get
+//! whitespace right by hand. Files are piped through the formatter rather than formatted in
+//! place, which lets check mode report a diff without touching disk and keeps the write on the
+//! atomic, encoding preserving path the edit operations use (and so undoable).
+//!
+//! Every file is formatted before any is written, a formatter failing on one (usually a syntax
+//! error) leaves all of them unchanged.
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use tokio::time::Duration;
+
+use crate::operations::{
+    commands::utils::{run_command, tail, CommandOptions, CommandOutcome, MAX_OUTPUT_BYTES},
+    fs::{
+        history::{self, FileChange},
+        utils::{ensure_not_protected, ensure_relative, read_text, write_text, TextFormat},
+    },
+};
+use crate::settings;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct FormatRequest {
+    pub paths: Vec<String>,
+    // Only report what would change, as a unified diff per file
+    #[oai(default)]
+    #[serde(default)]
+    pub check: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct FormattedFile {
+    pub path: String,
+    // Program that formatted it
+    pub formatter: String,
+    pub changed: bool,
+    // Formatted content, unless in check mode
+    pub content: Option<String>,
+    // Check mode only, empty when the file is already formatted
+    pub diff: Option<String>,
+    // Hash of the written file, when it was changed
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct FormatResponse {
+    pub files: Vec<FormattedFile>,
+    pub changed: usize,
+}
+
+struct Formatted {
+    path: PathBuf,
+    formatter: String,
+    original: String,
+    content: String,
+    format: TextFormat,
+}
+
+fn formatter_for(path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match settings::get().formatters.remove(&extension) {
+        Some(command) if !command.is_empty() => Ok(command),
+        _ => Err(format!("No formatter configured for {:?}", path).into()),
+    }
+}
+
+async fn format_file(path: PathBuf) -> Result<Formatted, Box<dyn Error>> {
+    let command = formatter_for(&path)?;
+    let (original, format) = read_text(&path).await?;
+    let display_path = path.to_string_lossy().to_string();
+    let args: Vec<String> = command[1..]
+        .iter()
+        .map(|a| a.replace("{path}", &display_path))
+        .collect();
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    let formatter = command[0].clone();
+    let options = CommandOptions {
+        stdin: Some(original.clone().into_bytes()),
+        ..Default::default()
+    };
+    let result = run_command(&formatter, &args, &options, TIMEOUT)
+        .await
+        .map_err(|e| format!("Couldn't run {}: {}", formatter, e))?;
+    match (result.outcome, result.exit_status) {
+        (CommandOutcome::Exited, Some(0)) if !result.invalid_utf8 => {}
+        (CommandOutcome::TimedOut, _) => {
+            return Err(format!("{} timed out on {}", formatter, display_path).into())
+        }
+        _ => {
+            let output = tail(&result.stderr, MAX_OUTPUT_BYTES);
+            return Err(format!("{} failed on {}:\n{}", formatter, display_path, output).into());
+        }
+    }
+    Ok(Formatted {
+        path,
+        formatter,
+        original,
+        content: result.stdout,
+        format,
+    })
+}
+
+impl FormatRequest {
+    pub async fn process(self) -> Result<FormatResponse, Box<dyn Error>> {
+        if self.paths.is_empty() {
+            return Err("No paths to format".into());
+        }
+        let mut formatted = Vec::new();
+        for path in &self.paths {
+            let path = ensure_relative(PathBuf::from(path)).await?;
+            ensure_not_protected(&path)?;
+            formatted.push(format_file(path).await?);
+        }
+
+        let mut files = Vec::new();
+        let mut changes = Vec::new();
+        for file in formatted {
+            let path = file.path.to_string_lossy().to_string();
+            let changed = file.content != file.original;
+            let mut response = FormattedFile {
+                path: path.clone(),
+                formatter: file.formatter,
+                changed,
+                content: None,
+                diff: None,
+                hash: None,
+            };
+            if self.check {
+                let diff = TextDiff::from_lines(&file.original, &file.content)
+                    .unified_diff()
+                    .header(&format!("a/{}", path), &format!("b/{}", path))
+                    .to_string();
+                response.diff = Some(if changed { diff } else { String::new() });
+            } else if changed {
+                let before = history::snapshot(&file.path);
+                let written = write_text(&file.path, &file.content, &file.format).await?;
+                changes.push(FileChange::new(&file.path, before));
+                response.content = Some(written.content);
+                response.hash = Some(written.hash);
+            } else {
+                response.content = Some(file.content);
+            }
+            files.push(response);
+        }
+        if !changes.is_empty() {
+            history::record("Format", changes);
+        }
+        Ok(FormatResponse {
+            changed: files.iter().filter(|f| f.changed).count(),
+            files,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::settings::OperationSettings;
+
+    const UNFORMATTED: &str = "fn main(){\nlet x=1;\n    println!(\"{}\",x);}\n";
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        history::clear();
+        settings::set(OperationSettings::default());
+        dir
+    }
+
+    fn request(paths: &[&str], check: bool) -> FormatRequest {
+        FormatRequest {
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+            check,
+        }
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_format(_tmp_dir: TempDir) {
+        fs::write("main.rs", UNFORMATTED).unwrap();
+        fs::write("lib.rs", "pub fn f() {}\n").unwrap();
+
+        let response = request(&["main.rs", "lib.rs"], true)
+            .process()
+            .await
+            .unwrap();
+        assert_eq!(response.changed, 1);
+        let diff = response.files[0].diff.as_deref().unwrap();
+        assert!(diff.starts_with("--- a/main.rs\n+++ b/main.rs\n"));
+        assert!(diff.contains("+    let x = 1;\n"));
+        assert_eq!(response.files[1].diff.as_deref(), Some(""));
+        assert_eq!(fs::read_to_string("main.rs").unwrap(), UNFORMATTED);
+        assert!(history::entries().is_empty());
+
+        let response = request(&["main.rs", "lib.rs"], false)
+            .process()
+            .await
+            .unwrap();
+        let formatted = "fn main() {\n    let x = 1;\n    println!(\"{}\", x);\n}\n";
+        assert_eq!(response.files[0].content.as_deref(), Some(formatted));
+        assert_eq!(fs::read_to_string("main.rs").unwrap(), formatted);
+        assert_eq!(history::entries().len(), 1);
+        assert_eq!(history::entries()[0].paths(), vec!["main.rs".to_string()]);
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_format_errors(_tmp_dir: TempDir) {
+        fs::write("main.rs", UNFORMATTED).unwrap();
+        fs::write("broken.rs", "fn main( {\n").unwrap();
+        fs::write("notes.txt", "notes\n").unwrap();
+
+        let error = request(&["main.rs", "broken.rs"], false)
+            .process()
+            .await
+            .unwrap_err();
+        assert!(error.to_string().starts_with("rustfmt failed on broken.rs"));
+        assert_eq!(fs::read_to_string("main.rs").unwrap(), UNFORMATTED);
+
+        let error = request(&["notes.txt"], false).process().await.unwrap_err();
+        assert!(error.to_string().contains("No formatter configured"));
+    }
+}
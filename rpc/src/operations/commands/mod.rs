@@ -1,3 +1,9 @@
+pub mod cargo_check;
+pub mod format;
+pub mod process;
+pub mod run_command;
 pub mod run_python;
+pub mod run_tests;
 pub mod rustlings;
+pub mod shell;
 pub mod utils;
@@ -0,0 +1,636 @@
+//! Long running processes in the background, e.g. a dev server the LLM starts and then sends
+//! requests to, or a watcher whose output it checks now and then.
+//!
+//! The Agent owns them: each runs in its own process group so killing it takes its children
+//! too, and `kill_all` is called when the session ends. The last `MAX_BUFFER_BYTES` of stdout
+//! and stderr are kept, read incrementally with byte cursors.
+//!
+//! At most `MAX_PROCESSES` are kept. A process that exited is forgotten once ProcessRead has
+//! returned the last of its output, after which its process_id is no longer valid.
+use std::{
+    collections::HashMap,
+    error::Error,
+    os::unix::process::ExitStatusExt,
+    process::{ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+};
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    process::{Child, ChildStdin, Command},
+    time::{sleep, timeout, Duration, Instant},
+};
+
+use crate::operations::{
+    commands::{
+        run_command::{ensure_allowed, ensure_env_allowed, resolve_cwd},
+        utils::ProcessGroup,
+    },
+    fs::history,
+};
+
+// Per stream, older output is dropped
+const MAX_BUFFER_BYTES: usize = 256 * 1024;
+
+// How long ProcessKill waits after SIGTERM before sending SIGKILL
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+// How long ProcessWrite waits for a process that isn't reading its stdin
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Running, or exited with output that hasn't all been read
+const MAX_PROCESSES: usize = 16;
+
+lazy_static! {
+    static ref PROCESSES: Mutex<HashMap<String, Arc<ManagedProcess>>> = Mutex::new(HashMap::new());
+}
+
+// Output of a stream, with `start` the total bytes dropped from the front so cursors stay valid
+#[derive(Debug, Default)]
+struct OutputBuffer {
+    data: Vec<u8>,
+    start: usize,
+    // The stream was closed, nothing more will be pushed
+    closed: bool,
+}
+
+impl OutputBuffer {
+    fn push(&mut self, chunk: &[u8]) {
+        self.data.extend_from_slice(chunk);
+        if self.data.len() > MAX_BUFFER_BYTES {
+            let excess = self.data.len() - MAX_BUFFER_BYTES;
+            self.data.drain(..excess);
+            self.start += excess;
+        }
+    }
+
+    // Output since the cursor, the next cursor and whether output before the cursor was dropped.
+    // A UTF-8 character split at the end is left for the next read, unless the stream is
+    // closed and the rest of it will never come.
+    fn read(&self, cursor: usize) -> (String, usize, bool) {
+        let truncated = cursor < self.start;
+        let from = cursor.saturating_sub(self.start).min(self.data.len());
+        let mut bytes = &self.data[from..];
+        if let Err(e) = std::str::from_utf8(bytes) {
+            if e.error_len().is_none() && !self.closed {
+                bytes = &bytes[..e.valid_up_to()];
+            }
+        }
+        let next = self.start + from + bytes.len();
+        (String::from_utf8_lossy(bytes).to_string(), next, truncated)
+    }
+}
+
+#[derive(Debug)]
+struct ManagedProcess {
+    process_id: String,
+    pid: Option<u32>,
+    program: String,
+    args: Vec<String>,
+    started_at: String,
+    child: Mutex<Child>,
+    stdin: tokio::sync::Mutex<Option<ChildStdin>>,
+    stdout: Arc<Mutex<OutputBuffer>>,
+    stderr: Arc<Mutex<OutputBuffer>>,
+    // Killed when the process is dropped, and if the Agent is stopped
+    group: Option<ProcessGroup>,
+}
+
+impl ManagedProcess {
+    fn exit_status(&self) -> Option<ExitStatus> {
+        self.child.lock().unwrap().try_wait().ok().flatten()
+    }
+
+    fn info(&self) -> ProcessInfo {
+        let status = self.exit_status();
+        ProcessInfo {
+            process_id: self.process_id.clone(),
+            pid: self.pid,
+            program: self.program.clone(),
+            args: self.args.clone(),
+            started_at: self.started_at.clone(),
+            running: status.is_none(),
+            exit_status: status.and_then(|s| s.code()),
+            signal: status.and_then(|s| s.signal()),
+        }
+    }
+
+    // Signal the whole process group, which may outlive the process itself
+    fn signal(&self, signal: i32) {
+        if let Some(group) = &self.group {
+            group.signal(signal);
+        }
+    }
+}
+
+async fn read_into<R: AsyncRead + Unpin>(mut reader: R, buffer: Arc<Mutex<OutputBuffer>>) {
+    let mut chunk = [0; 4096];
+    while let Ok(size) = reader.read(&mut chunk).await {
+        if size == 0 {
+            break;
+        }
+        buffer.lock().unwrap().push(&chunk[..size]);
+    }
+    buffer.lock().unwrap().closed = true;
+}
+
+fn get_process(process_id: &str) -> Result<Arc<ManagedProcess>, Box<dyn Error>> {
+    match PROCESSES.lock().unwrap().get(process_id) {
+        Some(process) => Ok(process.clone()),
+        None => Err(format!("No process {:?}", process_id).into()),
+    }
+}
+
+// Kill every process started this session, for when the Agent disconnects
+pub fn kill_all() {
+    for (_, process) in PROCESSES.lock().unwrap().drain() {
+        process.signal(libc::SIGKILL);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ProcessInfo {
+    // The handle the other Process operations take
+    pub process_id: String,
+    pub pid: Option<u32>,
+    pub program: String,
+    pub args: Vec<String>,
+    // RFC 3339
+    pub started_at: String,
+    pub running: bool,
+    pub exit_status: Option<i32>,
+    // Signal that terminated the process, if that's how it ended
+    pub signal: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ProcessStartRequest {
+    // Has to be allowlisted in the Agent config, the same as RunCommand
+    pub program: String,
+    #[oai(default)]
+    #[serde(default)]
+    pub args: Vec<String>,
+    // Directory to run in, inside the workspace, the workspace root if not set
+    pub cwd: Option<String>,
+    // Only variables the command's rule in the Agent config lists in `allowed_env`
+    #[oai(default)]
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ProcessStartResponse {
+    pub process: ProcessInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ProcessReadRequest {
+    pub process_id: String,
+    // Cursors from the previous ProcessRead, 0 to read from the start
+    #[oai(default)]
+    #[serde(default)]
+    pub stdout_cursor: usize,
+    #[oai(default)]
+    #[serde(default)]
+    pub stderr_cursor: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ProcessReadResponse {
+    pub stdout: String,
+    pub stderr: String,
+    // Pass these to the next ProcessRead to only get newer output
+    pub stdout_cursor: usize,
+    pub stderr_cursor: usize,
+    // Output between the cursors and what is returned was dropped, the buffer was full
+    pub truncated: bool,
+    // The process exited and this is the last of its output, its process_id is no longer valid
+    pub finished: bool,
+    pub process: ProcessInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ProcessWriteRequest {
+    pub process_id: String,
+    // Written to stdin as is, include the trailing newline for line-based programs
+    pub input: String,
+    // Close stdin after writing, to send EOF
+    #[oai(default)]
+    #[serde(default)]
+    pub close_stdin: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ProcessWriteResponse {
+    pub bytes_written: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ProcessStatusRequest {
+    pub process_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ProcessStatusResponse {
+    pub process: ProcessInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ProcessKillRequest {
+    pub process_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ProcessKillResponse {
+    pub process: ProcessInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ProcessListRequest {}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ProcessListResponse {
+    // Oldest first, including processes that have exited
+    pub processes: Vec<ProcessInfo>,
+}
+
+impl ProcessStartRequest {
+    pub async fn process(self) -> Result<ProcessStartResponse, Box<dyn Error>> {
+        ensure_allowed(&self.program, &self.args)?;
+        ensure_env_allowed(&self.program, &self.args, &self.env)?;
+        if PROCESSES.lock().unwrap().len() >= MAX_PROCESSES {
+            return Err(format!(
+                "At most {} processes are kept, kill one and read the rest of its output first",
+                MAX_PROCESSES
+            )
+            .into());
+        }
+        let mut cmd = Command::new(&self.program);
+        cmd.args(&self.args)
+            .envs(&self.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .kill_on_drop(true);
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(resolve_cwd(cwd).await?);
+        }
+        let mut child = cmd.spawn()?;
+        history::record_unjournaled("ProcessStart");
+
+        let stdout = Arc::new(Mutex::new(OutputBuffer::default()));
+        let stderr = Arc::new(Mutex::new(OutputBuffer::default()));
+        if let Some(reader) = child.stdout.take() {
+            tokio::spawn(read_into(reader, stdout.clone()));
+        }
+        if let Some(reader) = child.stderr.take() {
+            tokio::spawn(read_into(reader, stderr.clone()));
+        }
+        let process = ManagedProcess {
+            process_id: uuid::Uuid::new_v4().to_string(),
+            pid: child.id(),
+            group: child.id().map(ProcessGroup::track),
+            program: self.program,
+            args: self.args,
+            started_at: Utc::now().to_rfc3339(),
+            stdin: tokio::sync::Mutex::new(child.stdin.take()),
+            child: Mutex::new(child),
+            stdout,
+            stderr,
+        };
+        let info = process.info();
+        PROCESSES
+            .lock()
+            .unwrap()
+            .insert(process.process_id.clone(), Arc::new(process));
+        Ok(ProcessStartResponse { process: info })
+    }
+}
+
+impl ProcessReadRequest {
+    pub async fn process(self) -> Result<ProcessReadResponse, Box<dyn Error>> {
+        let process = get_process(&self.process_id)?;
+        let info = process.info();
+        let (stdout, stdout_cursor, stdout_truncated, stdout_done) = {
+            let buffer = process.stdout.lock().unwrap();
+            let (text, cursor, truncated) = buffer.read(self.stdout_cursor);
+            let done = buffer.closed && cursor == buffer.start + buffer.data.len();
+            (text, cursor, truncated, done)
+        };
+        let (stderr, stderr_cursor, stderr_truncated, stderr_done) = {
+            let buffer = process.stderr.lock().unwrap();
+            let (text, cursor, truncated) = buffer.read(self.stderr_cursor);
+            let done = buffer.closed && cursor == buffer.start + buffer.data.len();
+            (text, cursor, truncated, done)
+        };
+        // Everything the process will ever print has been returned
+        let finished = !info.running && stdout_done && stderr_done;
+        if finished {
+            PROCESSES.lock().unwrap().remove(&self.process_id);
+        }
+        Ok(ProcessReadResponse {
+            stdout,
+            stderr,
+            stdout_cursor,
+            stderr_cursor,
+            truncated: stdout_truncated || stderr_truncated,
+            finished,
+            process: info,
+        })
+    }
+}
+
+impl ProcessWriteRequest {
+    pub async fn process(self) -> Result<ProcessWriteResponse, Box<dyn Error>> {
+        let process = get_process(&self.process_id)?;
+        let mut stdin = process.stdin.lock().await;
+        let pipe = stdin.as_mut().ok_or("stdin of the process was closed")?;
+        let write = async {
+            pipe.write_all(self.input.as_bytes()).await?;
+            pipe.flush().await
+        };
+        timeout(WRITE_TIMEOUT, write).await.map_err(|_| {
+            format!(
+                "Timed out after {}s, the process isn't reading its stdin",
+                WRITE_TIMEOUT.as_secs()
+            )
+        })??;
+        if self.close_stdin {
+            *stdin = None;
+        }
+        Ok(ProcessWriteResponse {
+            bytes_written: self.input.len(),
+        })
+    }
+}
+
+impl ProcessStatusRequest {
+    pub async fn process(self) -> Result<ProcessStatusResponse, Box<dyn Error>> {
+        let process = get_process(&self.process_id)?;
+        Ok(ProcessStatusResponse {
+            process: process.info(),
+        })
+    }
+}
+
+impl ProcessKillRequest {
+    pub async fn process(self) -> Result<ProcessKillResponse, Box<dyn Error>> {
+        let process = get_process(&self.process_id)?;
+        process.signal(libc::SIGTERM);
+        let deadline = Instant::now() + KILL_GRACE_PERIOD;
+        while process.exit_status().is_none() {
+            if Instant::now() >= deadline {
+                process.signal(libc::SIGKILL);
+                process.child.lock().unwrap().start_kill()?;
+                break;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        while process.exit_status().is_none() {
+            sleep(Duration::from_millis(50)).await;
+        }
+        Ok(ProcessKillResponse {
+            process: process.info(),
+        })
+    }
+}
+
+impl ProcessListRequest {
+    pub async fn process(self) -> Result<ProcessListResponse, Box<dyn Error>> {
+        let processes: Vec<Arc<ManagedProcess>> =
+            PROCESSES.lock().unwrap().values().cloned().collect();
+        let mut processes: Vec<ProcessInfo> = processes.iter().map(|p| p.info()).collect();
+        processes.sort_by(|a, b| a.started_at.cmp(&b.started_at));
+        Ok(ProcessListResponse { processes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::settings::{self, CommandRule, OperationSettings};
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        kill_all();
+        settings::set(OperationSettings {
+            allowed_commands: vec![CommandRule {
+                program: "sh".to_string(),
+                args_pattern: None,
+                allowed_env: vec![],
+            }],
+            ..Default::default()
+        });
+        dir
+    }
+
+    async fn start(script: &str) -> ProcessInfo {
+        let request = ProcessStartRequest {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            cwd: None,
+            env: HashMap::new(),
+        };
+        request.process().await.unwrap().process
+    }
+
+    // Read until the stdout so far ends with `expected`
+    async fn read_until(process_id: &str, cursor: usize, expected: &str) -> ProcessReadResponse {
+        for _ in 0..100 {
+            let request = ProcessReadRequest {
+                process_id: process_id.to_string(),
+                stdout_cursor: cursor,
+                stderr_cursor: 0,
+            };
+            let response = request.process().await.unwrap();
+            if response.stdout.ends_with(expected) {
+                return response;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        panic!("Process never printed {:?}", expected);
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_process_lifecycle(_tmp_dir: TempDir) {
+        let process =
+            start("echo ready; read line; echo \"got $line\"; echo oops >&2; sleep 30").await;
+        assert!(process.running);
+
+        let response = read_until(&process.process_id, 0, "ready\n").await;
+        assert_eq!(response.stdout, "ready\n");
+        let cursor = response.stdout_cursor;
+
+        let request = ProcessWriteRequest {
+            process_id: process.process_id.clone(),
+            input: "hello\n".to_string(),
+            close_stdin: false,
+        };
+        assert_eq!(request.process().await.unwrap().bytes_written, 6);
+        let response = read_until(&process.process_id, cursor, "got hello\n").await;
+        assert_eq!(response.stdout, "got hello\n");
+
+        let list = ProcessListRequest {}.process().await.unwrap();
+        assert_eq!(list.processes.len(), 1);
+        assert!(list.processes[0].running);
+
+        let request = ProcessKillRequest {
+            process_id: process.process_id.clone(),
+        };
+        let killed = request.process().await.unwrap().process;
+        assert!(!killed.running);
+        assert_eq!(killed.signal, Some(libc::SIGTERM));
+
+        let request = ProcessReadRequest {
+            process_id: process.process_id,
+            stdout_cursor: 0,
+            stderr_cursor: 0,
+        };
+        assert_eq!(request.process().await.unwrap().stderr, "oops\n");
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_process_exit(_tmp_dir: TempDir) {
+        let process = start("exit 3").await;
+        let mut status = process;
+        for _ in 0..100 {
+            if !status.running {
+                break;
+            }
+            sleep(Duration::from_millis(50)).await;
+            let request = ProcessStatusRequest {
+                process_id: status.process_id.clone(),
+            };
+            status = request.process().await.unwrap().process;
+        }
+        assert_eq!((status.running, status.exit_status), (false, Some(3)));
+
+        let request = ProcessStartRequest {
+            program: "python".to_string(),
+            args: vec![],
+            cwd: None,
+            env: HashMap::new(),
+        };
+        let error = request.process().await.unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("not allowed by the Agent config"));
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_process_limits(_tmp_dir: TempDir) {
+        // Exited, but its output hasn't been read yet
+        let done = start("echo done").await;
+        let mut running = Vec::new();
+        for _ in 1..MAX_PROCESSES {
+            running.push(start("sleep 30").await);
+        }
+        let request = ProcessStartRequest {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), "true".to_string()],
+            cwd: None,
+            env: HashMap::new(),
+        };
+        let error = request.process().await.unwrap_err();
+        assert!(error.to_string().starts_with("At most 16 processes"));
+
+        let mut response = read_until(&done.process_id, 0, "done\n").await;
+        for _ in 0..100 {
+            if response.finished {
+                break;
+            }
+            sleep(Duration::from_millis(50)).await;
+            response = read_until(&done.process_id, 0, "done\n").await;
+        }
+        assert!(response.finished);
+        let request = ProcessStatusRequest {
+            process_id: done.process_id,
+        };
+        assert!(request.process().await.is_err());
+        start("true").await;
+
+        // Doesn't read its stdin, so the pipe fills up
+        let request = ProcessWriteRequest {
+            process_id: running[0].process_id.clone(),
+            input: "x".repeat(1024 * 1024),
+            close_stdin: false,
+        };
+        let error = request.process().await.unwrap_err();
+        assert!(error.to_string().contains("isn't reading its stdin"));
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_kill_after_exit(_tmp_dir: TempDir) {
+        // The shell exits right away, leaving its child behind in the group
+        let process = start("sleep 30 & echo $!").await;
+        let response = read_until(&process.process_id, 0, "\n").await;
+        let pid: i32 = response.stdout.trim().parse().unwrap();
+        let request = ProcessKillRequest {
+            process_id: process.process_id,
+        };
+        request.process().await.unwrap();
+        for _ in 0..100 {
+            if unsafe { libc::kill(pid, 0) } != 0 {
+                return;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        panic!("The process left behind was never killed");
+    }
+
+    #[test]
+    fn test_output_buffer() {
+        let mut buffer = OutputBuffer::default();
+        buffer.push("é".repeat(MAX_BUFFER_BYTES).as_bytes());
+        let (text, cursor, truncated) = buffer.read(0);
+        assert!(truncated);
+        assert_eq!(text, "é".repeat(MAX_BUFFER_BYTES / 2));
+        assert_eq!(cursor, MAX_BUFFER_BYTES * 2);
+
+        let mut buffer = OutputBuffer::default();
+        buffer.push(&"é".as_bytes()[..1]);
+        assert_eq!(buffer.read(0), (String::new(), 0, false));
+        buffer.push(&"é".as_bytes()[1..]);
+        assert_eq!(buffer.read(0), ("é".to_string(), 2, false));
+
+        let mut buffer = OutputBuffer::default();
+        buffer.push(&"é".as_bytes()[..1]);
+        buffer.closed = true;
+        assert_eq!(buffer.read(0), ("\u{FFFD}".to_string(), 1, false));
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_incomplete_utf8_at_exit(_tmp_dir: TempDir) {
+        let process = start("printf '\\342'").await;
+        let mut response = read_until(&process.process_id, 0, "").await;
+        for _ in 0..100 {
+            if response.finished {
+                break;
+            }
+            sleep(Duration::from_millis(50)).await;
+            response = read_until(&process.process_id, 0, "").await;
+        }
+        assert!(response.finished);
+        assert_eq!(response.stdout, "\u{FFFD}");
+        assert_eq!(response.stdout_cursor, 1);
+    }
+}
@@ -0,0 +1,226 @@
+use std::{collections::HashMap, error::Error, path::PathBuf};
+
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+
+use crate::{
+    operations::{
+        commands::utils::{run_command, CommandOptions, CommandOutcome, CommandResult},
+        fs::{history, utils::ensure_relative},
+    },
+    settings,
+};
+
+pub const MAX_TIMEOUT_SECS: u64 = 600;
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RunCommandRequest {
+    // Has to be allowlisted in the Agent config
+    pub program: String,
+    #[oai(default)]
+    #[serde(default)]
+    pub args: Vec<String>,
+    // Directory to run in, inside the workspace, the workspace root if not set
+    pub cwd: Option<String>,
+    #[oai(default = "default_timeout_secs")]
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    // Added to the Agent's environment, only variables the command's rule in the Agent config
+    // lists in `allowed_env`
+    #[oai(default)]
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RunCommandResponse {
+    pub stdout: String,
+    pub stderr: String,
+    // Output had bytes that aren't valid UTF-8, they're replaced with U+FFFD
+    pub invalid_utf8: bool,
+    pub outcome: CommandOutcome,
+    pub exit_status: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+pub fn ensure_allowed(program: &str, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let rules = settings::get().allowed_commands;
+    if rules.is_empty() {
+        return Err(
+            "Running commands is disabled, no commands are allowlisted in the Agent config".into(),
+        );
+    }
+    match rules.iter().any(|rule| rule.matches(program, args)) {
+        true => Ok(()),
+        false => {
+            let allowed: Vec<&str> = rules.iter().map(|r| r.program.as_str()).collect();
+            Err(format!(
+                "{} {} is not allowed by the Agent config, allowed programs: {}",
+                program,
+                args.join(" "),
+                allowed.join(", ")
+            )
+            .into())
+        }
+    }
+}
+
+// Request env is rejected unless a rule allowing the command lists the variable, since plenty
+// of variables (PATH, LD_PRELOAD, RUSTC_WRAPPER, PYTHONPATH, ...) swap out what actually runs
+pub fn ensure_env_allowed(
+    program: &str,
+    args: &[String],
+    env: &HashMap<String, String>,
+) -> Result<(), Box<dyn Error>> {
+    let rules = settings::get().allowed_commands;
+    let allowed = |key: &String| {
+        rules
+            .iter()
+            .any(|rule| rule.matches(program, args) && rule.allowed_env.contains(key))
+    };
+    let mut keys: Vec<&String> = env.keys().collect();
+    keys.sort();
+    match keys.into_iter().find(|key| !allowed(key)) {
+        Some(key) => Err(format!(
+            "Setting {} isn't allowed for {}, add it to the command's allowed_env in the Agent config",
+            key, program
+        )
+        .into()),
+        None => Ok(()),
+    }
+}
+
+// Canonical cwd, refusing anything that resolves outside the workspace (`..`, symlinks)
+pub async fn resolve_cwd(cwd: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let root = std::env::current_dir()?.canonicalize()?;
+    let outside = || format!("cwd {:?} must be a directory inside the workspace", cwd);
+    let relative = ensure_relative(PathBuf::from(cwd))
+        .await
+        .map_err(|_| outside())?;
+    let path = root
+        .join(relative)
+        .canonicalize()
+        .map_err(|_| format!("cwd {:?} doesn't exist", cwd))?;
+    match path.starts_with(&root) && path.is_dir() {
+        true => Ok(path),
+        false => Err(outside().into()),
+    }
+}
+
+impl RunCommandRequest {
+    pub async fn process(self) -> Result<RunCommandResponse, Box<dyn Error>> {
+        ensure_allowed(&self.program, &self.args)?;
+        ensure_env_allowed(&self.program, &self.args, &self.env)?;
+        if self.timeout_secs == 0 || self.timeout_secs > MAX_TIMEOUT_SECS {
+            return Err(format!("timeout_secs must be between 1 and {}", MAX_TIMEOUT_SECS).into());
+        }
+        let options = CommandOptions {
+            cwd: match &self.cwd {
+                Some(cwd) => Some(resolve_cwd(cwd).await?),
+                None => None,
+            },
+            env: self.env.into_iter().collect(),
+            ..Default::default()
+        };
+        let args: Vec<&str> = self.args.iter().map(|a| a.as_str()).collect();
+        let timeout_duration = Duration::from_secs(self.timeout_secs);
+        // Whatever the command changes can't be undone or exported
+        history::record_unjournaled("RunCommand");
+        let CommandResult {
+            stdout,
+            stderr,
+            invalid_utf8,
+            outcome,
+            exit_status,
+            signal,
+        } = run_command(&self.program, &args, &options, timeout_duration).await?;
+        Ok(RunCommandResponse {
+            stdout,
+            stderr,
+            invalid_utf8,
+            outcome,
+            exit_status,
+            signal,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::settings::{CommandRule, OperationSettings};
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        settings::set(OperationSettings {
+            allowed_commands: vec![
+                CommandRule {
+                    program: "pwd".to_string(),
+                    args_pattern: None,
+                    allowed_env: vec![],
+                },
+                CommandRule {
+                    program: "printenv".to_string(),
+                    args_pattern: Some("[A-Z_]+".to_string()),
+                    allowed_env: vec!["GREETING".to_string()],
+                },
+            ],
+            ..Default::default()
+        });
+        dir
+    }
+
+    fn request(program: &str, args: &[&str]) -> RunCommandRequest {
+        RunCommandRequest {
+            program: program.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            cwd: None,
+            timeout_secs: default_timeout_secs(),
+            env: HashMap::new(),
+        }
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_run_command(_tmp_dir: TempDir) {
+        std::fs::create_dir("sub").unwrap();
+        let mut req = request("pwd", &[]);
+        req.cwd = Some("sub".to_string());
+        let response = req.process().await.unwrap();
+        assert!(response.stdout.trim_end().ends_with("/sub"));
+        assert_eq!(response.exit_status, Some(0));
+
+        let mut req = request("printenv", &["GREETING"]);
+        req.env.insert("GREETING".to_string(), "hello".to_string());
+        let response = req.process().await.unwrap();
+        assert_eq!(response.stdout, "hello\n");
+    }
+
+    #[rstest::rstest]
+    #[case(request("rm", &["-rf", "."]), "not allowed by the Agent config")]
+    #[case(request("printenv", &["--null", "HOME"]), "not allowed by the Agent config")]
+    #[case(RunCommandRequest { cwd: Some("..".to_string()), ..request("pwd", &[]) }, "inside the workspace")]
+    #[case(RunCommandRequest { env: HashMap::from([("PATH".to_string(), ".".to_string())]), ..request("pwd", &[]) }, "isn't allowed")]
+    #[case(RunCommandRequest { env: HashMap::from([("RUSTC_WRAPPER".to_string(), "sh".to_string())]), ..request("printenv", &["GREETING"]) }, "isn't allowed")]
+    #[case(RunCommandRequest { env: HashMap::from([("GREETING".to_string(), "hi".to_string())]), ..request("pwd", &[]) }, "isn't allowed")]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_run_command_rejected(
+        _tmp_dir: TempDir,
+        #[case] req: RunCommandRequest,
+        #[case] error: &str,
+    ) {
+        let response = req.process().await;
+        assert!(response.unwrap_err().to_string().contains(error));
+    }
+}
@@ -1,37 +1,160 @@
-use crate::operations::commands::utils::{run_command_with_timeout, CommandResult};
-use crate::operations::fs::utils::ensure_relative;
+use crate::operations::commands::run_command::MAX_TIMEOUT_SECS;
+use crate::operations::commands::utils::{
+    run_command, CommandOptions, CommandOutcome, CommandResult,
+};
+use crate::operations::fs::{history, utils::ensure_relative};
+use crate::settings;
 use poem_openapi::Object;
 use serde::{Deserialize, Serialize};
 use std::{error::Error, path::PathBuf};
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
+
+fn default_timeout_secs() -> u64 {
+    5
+}
 
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct RunPythonRequest {
-    pub path: String,
+    // Script to run, or pass `code` instead
+    pub path: Option<String>,
+    // Source to run with `python -c`
+    pub code: Option<String>,
+    // sys.argv[1:]
+    #[oai(default)]
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub stdin: Option<String>,
+    #[oai(default = "default_timeout_secs")]
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct RunPythonResponse {
     pub stdout: String,
     pub stderr: String,
+    // Output had bytes that aren't valid UTF-8, they're replaced with U+FFFD
+    pub invalid_utf8: bool,
+    pub outcome: CommandOutcome,
     pub exit_status: Option<i32>,
+    pub signal: Option<i32>,
+    pub timed_out: bool,
+    pub duration_secs: f64,
 }
 
 impl RunPythonRequest {
     pub async fn process(self) -> Result<RunPythonResponse, Box<dyn Error>> {
-        let path = ensure_relative(PathBuf::from(self.path)).await?;
-        let cmd = "python";
-        let args = vec!["-u", path.to_str().unwrap()];
-        let timeout_duration = Duration::from_secs(5);
+        if self.timeout_secs == 0 || self.timeout_secs > MAX_TIMEOUT_SECS {
+            return Err(format!("timeout_secs must be between 1 and {}", MAX_TIMEOUT_SECS).into());
+        }
+        let mut args = vec!["-u".to_string()];
+        match (self.path, self.code) {
+            (Some(path), None) => {
+                let path = ensure_relative(PathBuf::from(path)).await?;
+                args.push(path.to_string_lossy().to_string());
+            }
+            (None, Some(code)) => args.extend(["-c".to_string(), code]),
+            _ => return Err("Pass exactly one of path and code".into()),
+        }
+        args.extend(self.args);
+        let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+        let options = CommandOptions {
+            stdin: self.stdin.map(|s| s.into_bytes()),
+            ..Default::default()
+        };
+        let interpreter = settings::get().python_interpreter;
+        let timeout_duration = Duration::from_secs(self.timeout_secs);
+        history::record_unjournaled("RunPython");
+        let started = Instant::now();
         let CommandResult {
             stdout,
             stderr,
+            invalid_utf8,
+            outcome,
             exit_status,
-        } = run_command_with_timeout(cmd, &args, timeout_duration).await?;
+            signal,
+        } = run_command(&interpreter, &args, &options, timeout_duration)
+            .await
+            .map_err(|e| format!("Couldn't run {}: {}", interpreter, e))?;
         Ok(RunPythonResponse {
             stdout,
             stderr,
+            invalid_utf8,
+            outcome,
             exit_status,
+            signal,
+            timed_out: outcome == CommandOutcome::TimedOut,
+            duration_secs: started.elapsed().as_secs_f64(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::settings::OperationSettings;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        settings::set(OperationSettings {
+            python_interpreter: "python3".to_string(),
+            ..Default::default()
+        });
+        dir
+    }
+
+    fn request(path: Option<&str>, code: Option<&str>) -> RunPythonRequest {
+        RunPythonRequest {
+            path: path.map(|p| p.to_string()),
+            code: code.map(|c| c.to_string()),
+            args: vec![],
+            stdin: None,
+            timeout_secs: default_timeout_secs(),
+        }
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_run_python(_tmp_dir: TempDir) {
+        std::fs::write(
+            "script.py",
+            "import sys\nprint(sys.argv[1:])\nsys.exit(2)\n",
+        )
+        .unwrap();
+        let mut req = request(Some("script.py"), None);
+        req.args = vec!["a".to_string(), "b c".to_string()];
+        let response = req.process().await.unwrap();
+        assert_eq!(response.stdout, "['a', 'b c']\n");
+        assert_eq!(response.exit_status, Some(2));
+        assert!(!response.timed_out);
+
+        let mut req = request(None, Some("import sys; print(sys.stdin.read().upper())"));
+        req.stdin = Some("hello".to_string());
+        let response = req.process().await.unwrap();
+        assert_eq!(response.stdout, "HELLO\n");
+        assert_eq!(response.exit_status, Some(0));
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_run_python_timeout(_tmp_dir: TempDir) {
+        let mut req = request(None, Some("import time; print('started'); time.sleep(5)"));
+        req.timeout_secs = 1;
+        let response = req.process().await.unwrap();
+        assert!(response.timed_out);
+        assert_eq!(response.exit_status, None);
+        assert_eq!(response.stdout, "started\n");
+        assert!(response.duration_secs >= 1.0);
+
+        let response = request(Some("script.py"), Some("print()")).process().await;
+        assert!(response.is_err());
+        let response = request(None, None).process().await;
+        assert!(response.is_err());
+    }
+}
@@ -0,0 +1,536 @@
+//! Run a project's tests and report structured results, so the LLM can see exactly which tests
+//! failed and where instead of digging through a wall of output.
+//!
+//! Each runner's machine readable output is used: libtest JSON for cargo (unstable, so it's
+//! enabled with RUSTC_BOOTSTRAP for the test binaries), JUnit XML for pytest and `go test -json` for go.
+use std::{error::Error, path::Path};
+
+use lazy_static::lazy_static;
+use poem_openapi::{Enum, Object};
+use quick_xml::events::{BytesStart, Event};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::Duration;
+
+use crate::{
+    operations::commands::{
+        run_command::{resolve_cwd, MAX_TIMEOUT_SECS},
+        utils::{
+            run_command, tail, CommandOptions, CommandOutcome, CommandResult, MAX_OUTPUT_BYTES,
+        },
+    },
+    settings,
+};
+
+lazy_static! {
+    // "panicked at src/lib.rs:10:5:" and the older "panicked at 'msg', src/lib.rs:10:5"
+    static ref RUST_LOCATION: Regex =
+        Regex::new(r"panicked at (?:'.*', )?([^\s:']+\.rs):(\d+)").unwrap();
+    // "tests/test_math.py:4: AssertionError"
+    static ref PYTHON_LOCATION: Regex = Regex::new(r"(?m)^([^\s:]+\.py):(\d+):").unwrap();
+    // "    math_test.go:12: expected 2, got 3"
+    static ref GO_LOCATION: Regex = Regex::new(r"([\w./-]+\.go):(\d+):").unwrap();
+}
+
+// Runs each test binary as `env RUSTC_BOOTSTRAP=1 <binary>`
+const CARGO_TEST_RUNNER: &str = r#"target.'cfg(all())'.runner=["env", "RUSTC_BOOTSTRAP=1"]"#;
+
+fn default_timeout_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum TestRunner {
+    Cargo,
+    Pytest,
+    Go,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum TestStatus {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RunTestsRequest {
+    pub runner: TestRunner,
+    // Project directory inside the workspace, the workspace root if not set
+    pub path: Option<String>,
+    // Only run tests matching this (cargo test filter, pytest -k, go test -run)
+    pub filter: Option<String>,
+    #[oai(default = "default_timeout_secs")]
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct TestCaseResult {
+    pub name: String,
+    pub status: TestStatus,
+    pub duration_secs: Option<f64>,
+    // Failure message / output, for failed tests
+    pub message: Option<String>,
+    // Where the failure happened, when it could be found in the message
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl TestCaseResult {
+    fn new(name: String, status: TestStatus) -> Self {
+        Self {
+            name,
+            status,
+            duration_secs: None,
+            message: None,
+            file: None,
+            line: None,
+        }
+    }
+
+    fn fail(&mut self, message: String, location: &Regex) {
+        self.status = TestStatus::Failed;
+        // The last location is usually the innermost frame in test code
+        if let Some(captures) = location.captures_iter(&message).last() {
+            self.file = Some(captures[1].to_string());
+            self.line = captures[2].parse().ok();
+        }
+        self.message = Some(tail(message.trim(), MAX_OUTPUT_BYTES));
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RunTestsResponse {
+    pub passed: usize,
+    pub failed: usize,
+    pub ignored: usize,
+    pub tests: Vec<TestCaseResult>,
+    pub outcome: CommandOutcome,
+    pub exit_status: Option<i32>,
+    pub signal: Option<i32>,
+    // End of stderr, where build errors end up when no tests could run
+    pub output: String,
+}
+
+// libtest's JSON output, one event per line, for every test binary cargo runs
+pub fn parse_libtest_json(output: &str) -> Vec<TestCaseResult> {
+    let mut tests = Vec::new();
+    for line in output.lines() {
+        let event: Value = match serde_json::from_str(line) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        if event["type"] != "test" {
+            continue;
+        }
+        let name = event["name"].as_str().unwrap_or_default().to_string();
+        let mut test = match event["event"].as_str() {
+            Some("ok") => TestCaseResult::new(name, TestStatus::Passed),
+            Some("ignored") => TestCaseResult::new(name, TestStatus::Ignored),
+            Some("failed") | Some("timeout") => {
+                let mut test = TestCaseResult::new(name, TestStatus::Failed);
+                let stdout = event["stdout"].as_str().unwrap_or_default();
+                test.fail(stdout.to_string(), &RUST_LOCATION);
+                test
+            }
+            _ => continue,
+        };
+        test.duration_secs = event["exec_time"].as_f64();
+        tests.push(test);
+    }
+    tests
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Option<String> {
+    let value = element.try_get_attribute(name).ok()??;
+    Some(value.unescape_value().ok()?.to_string())
+}
+
+fn testcase(element: &BytesStart) -> TestCaseResult {
+    let name = attribute(element, "name").unwrap_or_default();
+    let name = match attribute(element, "classname") {
+        Some(classname) => format!("{}::{}", classname, name),
+        None => name,
+    };
+    let mut test = TestCaseResult::new(name, TestStatus::Passed);
+    test.duration_secs = attribute(element, "time").and_then(|t| t.parse().ok());
+    test
+}
+
+// pytest's JUnit XML report
+pub fn parse_junit_xml(xml: &str) -> Result<Vec<TestCaseResult>, Box<dyn Error>> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut tests = Vec::new();
+    let mut current: Option<TestCaseResult> = None;
+    // Message attribute and body text of the failure being read
+    let mut failure: Option<(String, String)> = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => match element.name().as_ref() {
+                b"testcase" => current = Some(testcase(&element)),
+                b"failure" | b"error" => {
+                    let message = attribute(&element, "message").unwrap_or_default();
+                    failure = Some((message, String::new()));
+                }
+                b"skipped" => {
+                    if let Some(test) = current.as_mut() {
+                        test.status = TestStatus::Ignored;
+                        test.message = attribute(&element, "message");
+                    }
+                }
+                _ => {}
+            },
+            // A testcase without children passed
+            Event::Empty(element) => match element.name().as_ref() {
+                b"testcase" => tests.push(testcase(&element)),
+                b"failure" | b"error" => {
+                    if let Some(test) = current.as_mut() {
+                        let message = attribute(&element, "message").unwrap_or_default();
+                        test.fail(message, &PYTHON_LOCATION);
+                    }
+                }
+                b"skipped" => {
+                    if let Some(test) = current.as_mut() {
+                        test.status = TestStatus::Ignored;
+                        test.message = attribute(&element, "message");
+                    }
+                }
+                _ => {}
+            },
+            Event::Text(text) => {
+                if let Some((_, body)) = failure.as_mut() {
+                    body.push_str(&text.unescape()?);
+                }
+            }
+            Event::End(element) => match element.name().as_ref() {
+                b"failure" | b"error" => {
+                    if let (Some(test), Some((message, body))) = (current.as_mut(), failure.take())
+                    {
+                        let text = match body.trim().is_empty() {
+                            true => message,
+                            false => body,
+                        };
+                        test.fail(text, &PYTHON_LOCATION);
+                    }
+                }
+                b"testcase" => tests.extend(current.take()),
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(tests)
+}
+
+// `go test -json` events, output lines are collected per test
+pub fn parse_go_json(output: &str) -> Vec<TestCaseResult> {
+    let mut tests: Vec<TestCaseResult> = Vec::new();
+    let mut outputs: Vec<String> = Vec::new();
+    for line in output.lines() {
+        let event: Value = match serde_json::from_str(line) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        let name = match event["Test"].as_str() {
+            Some(name) => format!("{}/{}", event["Package"].as_str().unwrap_or_default(), name),
+            None => continue,
+        };
+        let index = match tests.iter().position(|t| t.name == name) {
+            Some(index) => index,
+            None => {
+                tests.push(TestCaseResult::new(name, TestStatus::Passed));
+                outputs.push(String::new());
+                tests.len() - 1
+            }
+        };
+        match event["Action"].as_str() {
+            Some("output") => outputs[index].push_str(event["Output"].as_str().unwrap_or_default()),
+            Some("pass") => tests[index].duration_secs = event["Elapsed"].as_f64(),
+            Some("skip") => tests[index].status = TestStatus::Ignored,
+            Some("fail") => {
+                tests[index].duration_secs = event["Elapsed"].as_f64();
+                let output = std::mem::take(&mut outputs[index]);
+                tests[index].fail(output, &GO_LOCATION);
+            }
+            _ => {}
+        }
+    }
+    tests
+}
+
+// The interpreter RunPython uses. A path like .venv/bin/python is relative to the workspace
+// root, which isn't where the tests run when a cwd is given.
+fn python_interpreter() -> Result<String, Box<dyn Error>> {
+    let interpreter = settings::get().python_interpreter;
+    if !interpreter.contains('/') || Path::new(&interpreter).is_absolute() {
+        return Ok(interpreter);
+    }
+    let path = std::env::current_dir()?.join(&interpreter);
+    Ok(path.to_string_lossy().to_string())
+}
+
+impl RunTestsRequest {
+    pub async fn process(self) -> Result<RunTestsResponse, Box<dyn Error>> {
+        if self.timeout_secs == 0 || self.timeout_secs > MAX_TIMEOUT_SECS {
+            return Err(format!("timeout_secs must be between 1 and {}", MAX_TIMEOUT_SECS).into());
+        }
+        // A filter is always a value, never an option to the runner
+        if self.filter.as_ref().is_some_and(|f| f.starts_with('-')) {
+            return Err("filter must not start with '-'".into());
+        }
+        let cwd = match &self.path {
+            Some(path) => Some(resolve_cwd(path).await?),
+            None => None,
+        };
+        let timeout_duration = Duration::from_secs(self.timeout_secs);
+        // pytest can only write JUnit XML to a file
+        let junit_path = std::env::temp_dir().join(format!("pytest-{}.xml", uuid::Uuid::new_v4()));
+        let junit_arg = format!("--junitxml={}", junit_path.to_string_lossy());
+
+        let mut args = match self.runner {
+            // libtest only prints JSON with `-Z unstable-options`, which it allows when it runs
+            // with RUSTC_BOOTSTRAP set. It's set through a runner for the test binaries alone:
+            // the build itself, with build scripts that look for nightly features, stays as
+            // it normally is. Doc tests are skipped (`--tests`), rustdoc runs them in-process
+            // where the runner can't reach.
+            TestRunner::Cargo => vec![
+                "test",
+                "--no-fail-fast",
+                "--tests",
+                "--config",
+                CARGO_TEST_RUNNER,
+                "--",
+                "-Z",
+                "unstable-options",
+                "--format",
+                "json",
+                "--report-time",
+            ],
+            TestRunner::Pytest => vec!["-m", "pytest", "-q", junit_arg.as_str()],
+            TestRunner::Go => vec!["test", "-json"],
+        };
+        if let Some(filter) = &self.filter {
+            match self.runner {
+                // After the `--`, as libtest's own argument
+                TestRunner::Cargo => args.push(filter),
+                TestRunner::Pytest => args.extend(["-k", filter.as_str()]),
+                TestRunner::Go => args.extend(["-run", filter.as_str()]),
+            }
+        }
+        if self.runner == TestRunner::Go {
+            args.push("./...");
+        }
+        let program = match self.runner {
+            TestRunner::Cargo => "cargo".to_string(),
+            TestRunner::Pytest => python_interpreter()?,
+            TestRunner::Go => "go".to_string(),
+        };
+
+        let options = CommandOptions {
+            cwd,
+            ..Default::default()
+        };
+        let CommandResult {
+            stdout,
+            stderr,
+            outcome,
+            exit_status,
+            signal,
+            ..
+        } = run_command(&program, &args, &options, timeout_duration).await?;
+        let tests = match self.runner {
+            TestRunner::Cargo => parse_libtest_json(&stdout),
+            TestRunner::Pytest => {
+                let xml = tokio::fs::read_to_string(&junit_path).await;
+                let _ = tokio::fs::remove_file(&junit_path).await;
+                match xml {
+                    Ok(xml) => parse_junit_xml(&xml)?,
+                    Err(_) => vec![],
+                }
+            }
+            TestRunner::Go => parse_go_json(&stdout),
+        };
+        // pytest and go report collection / build errors on stdout
+        let output = match self.runner {
+            TestRunner::Cargo => stderr,
+            _ if tests.is_empty() => format!("{}{}", stdout, stderr),
+            _ => stderr,
+        };
+        let count = |status| tests.iter().filter(|t| t.status == status).count();
+        Ok(RunTestsResponse {
+            passed: count(TestStatus::Passed),
+            failed: count(TestStatus::Failed),
+            ignored: count(TestStatus::Ignored),
+            tests,
+            outcome,
+            exit_status,
+            signal,
+            output: tail(&output, MAX_OUTPUT_BYTES),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_libtest_json() {
+        let output = r#"{ "type": "suite", "event": "started", "test_count": 3 }
+{ "type": "test", "event": "started", "name": "tests::adds" }
+{ "type": "test", "name": "tests::adds", "event": "ok", "exec_time": 0.001 }
+{ "type": "test", "name": "tests::slow", "event": "ignored" }
+{ "type": "test", "name": "tests::subtracts", "event": "failed", "exec_time": 0.002, "stdout": "\nthread 'tests::subtracts' panicked at src/lib.rs:12:9:\nassertion `left == right` failed\n  left: 1\n right: 2\n" }
+{ "type": "suite", "event": "failed", "passed": 1, "failed": 1, "ignored": 1 }
+"#;
+        let tests = parse_libtest_json(output);
+        assert_eq!(tests.len(), 3);
+        assert_eq!(tests[0].status, TestStatus::Passed);
+        assert_eq!(tests[0].duration_secs, Some(0.001));
+        assert_eq!(tests[1].status, TestStatus::Ignored);
+        assert_eq!(tests[2].status, TestStatus::Failed);
+        assert_eq!(tests[2].file.as_deref(), Some("src/lib.rs"));
+        assert_eq!(tests[2].line, Some(12));
+        assert!(tests[2].message.as_ref().unwrap().contains("left: 1"));
+    }
+
+    #[test]
+    fn test_parse_junit_xml() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?><testsuites><testsuite name="pytest" errors="0" failures="1" skipped="1" tests="3" time="0.05"><testcase classname="tests.test_math" name="test_add" time="0.001" /><testcase classname="tests.test_math" name="test_sub" time="0.002"><failure message="assert 1 == 2">def test_sub():
+&gt;       assert 1 == 2
+E       assert 1 == 2
+
+tests/test_math.py:5: AssertionError</failure></testcase><testcase classname="tests.test_math" name="test_skip" time="0.000"><skipped type="pytest.skip" message="not ready">tests/test_math.py:7: not ready</skipped></testcase></testsuite></testsuites>"#;
+        let tests = parse_junit_xml(xml).unwrap();
+        assert_eq!(tests.len(), 3);
+        assert_eq!(tests[0].name, "tests.test_math::test_add");
+        assert_eq!(tests[0].status, TestStatus::Passed);
+        assert_eq!(tests[1].status, TestStatus::Failed);
+        assert_eq!(tests[1].file.as_deref(), Some("tests/test_math.py"));
+        assert_eq!(tests[1].line, Some(5));
+        assert!(tests[1]
+            .message
+            .as_ref()
+            .unwrap()
+            .contains(">       assert 1 == 2"));
+        assert_eq!(tests[2].status, TestStatus::Ignored);
+        assert_eq!(tests[2].message.as_deref(), Some("not ready"));
+    }
+
+    #[test]
+    fn test_parse_go_json() {
+        let output = r#"{"Action":"run","Package":"example.com/math","Test":"TestAdd"}
+{"Action":"output","Package":"example.com/math","Test":"TestAdd","Output":"=== RUN   TestAdd\n"}
+{"Action":"pass","Package":"example.com/math","Test":"TestAdd","Elapsed":0.01}
+{"Action":"run","Package":"example.com/math","Test":"TestSub"}
+{"Action":"output","Package":"example.com/math","Test":"TestSub","Output":"    math_test.go:14: expected 2, got 3\n"}
+{"Action":"fail","Package":"example.com/math","Test":"TestSub","Elapsed":0.02}
+{"Action":"skip","Package":"example.com/math","Test":"TestSkip","Elapsed":0}
+{"Action":"fail","Package":"example.com/math","Elapsed":0.5}
+"#;
+        let tests = parse_go_json(output);
+        assert_eq!(tests.len(), 3);
+        assert_eq!(tests[0].name, "example.com/math/TestAdd");
+        assert_eq!(tests[0].status, TestStatus::Passed);
+        assert_eq!(tests[1].status, TestStatus::Failed);
+        assert_eq!(tests[1].file.as_deref(), Some("math_test.go"));
+        assert_eq!(tests[1].line, Some(14));
+        assert_eq!(tests[2].status, TestStatus::Ignored);
+    }
+
+    #[rstest::rstest]
+    #[serial_test::serial]
+    fn test_python_interpreter(_tmp_dir: TempDir) {
+        for (interpreter, expected) in [
+            ("python3", "python3".to_string()),
+            ("/usr/bin/python3", "/usr/bin/python3".to_string()),
+            (
+                ".venv/bin/python",
+                std::env::current_dir()
+                    .unwrap()
+                    .join(".venv/bin/python")
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+        ] {
+            settings::set(settings::OperationSettings {
+                python_interpreter: interpreter.to_string(),
+                ..Default::default()
+            });
+            assert_eq!(python_interpreter().unwrap(), expected);
+        }
+        settings::set(settings::OperationSettings::default());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_run_cargo_tests(_tmp_dir: TempDir) {
+        fs::create_dir_all("demo/src").unwrap();
+        fs::write(
+            "demo/Cargo.toml",
+            "[package]\nname = \"demo\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        let lib = "#[cfg(test)]\nmod tests {\n    #[test]\n    fn passes() {}\n\n    #[test]\n    fn fails() {\n        assert_eq!(1, 2);\n    }\n}\n";
+        fs::write("demo/src/lib.rs", lib).unwrap();
+        // RUSTC_BOOTSTRAP is only for the test binaries, not the build
+        let build = "fn main() {\n    assert!(std::env::var(\"RUSTC_BOOTSTRAP\").is_err());\n}\n";
+        fs::write("demo/build.rs", build).unwrap();
+
+        let request = RunTestsRequest {
+            runner: TestRunner::Cargo,
+            path: Some("demo".to_string()),
+            filter: None,
+            timeout_secs: default_timeout_secs(),
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!((response.passed, response.failed), (1, 1));
+        let failed = response
+            .tests
+            .iter()
+            .find(|t| t.status == TestStatus::Failed)
+            .unwrap();
+        assert_eq!(failed.name, "tests::fails");
+        assert_eq!(failed.file.as_deref(), Some("src/lib.rs"));
+        assert_eq!(failed.line, Some(8));
+        assert_eq!(response.exit_status, Some(101));
+
+        let request = RunTestsRequest {
+            runner: TestRunner::Cargo,
+            path: Some("demo".to_string()),
+            filter: Some("passes".to_string()),
+            timeout_secs: default_timeout_secs(),
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!((response.passed, response.failed), (1, 0));
+
+        for (filter, timeout_secs) in [("--config=build.rustc-wrapper=x", 300), ("passes", 0)] {
+            let request = RunTestsRequest {
+                runner: TestRunner::Cargo,
+                path: Some("demo".to_string()),
+                filter: Some(filter.to_string()),
+                timeout_secs,
+            };
+            assert!(request.process().await.is_err());
+        }
+    }
+}
@@ -1,28 +1,359 @@
-use crate::operations::commands::utils::{run_command_with_timeout, CommandResult};
-use crate::operations::fs::utils::ensure_relative;
-use poem_openapi::Object;
+//! Rustlings, with the workspace being a rustlings checkout. Exercises and hints come from its
+//! `info.toml`, an exercise is done once its `I AM NOT DONE` marker is removed (as rustlings
+//! itself decides), and `rustlings verify` / `run` output is picked apart into the failing
+//! exercise and the compiler or test output.
+use std::{error::Error, fs};
+
+use lazy_static::lazy_static;
+use poem_openapi::{Enum, Object};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{error::Error, path::PathBuf};
 use tokio::time::Duration;
 
+use crate::operations::commands::utils::{run_command_with_timeout, CommandOutcome, CommandResult};
+
+const INFO_FILE: &str = "info.toml";
+const NOT_DONE_MARKER: &str = "I AM NOT DONE";
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref ANSI_ESCAPE: Regex = Regex::new(r"\x1b\[[0-9;]*[A-Za-z]").unwrap();
+    static ref FAILED: Regex =
+        Regex::new(r"(Compiling|Compilation|Testing|Running) of (\S+) failed!").unwrap();
+    static ref SUCCEEDED: Regex =
+        Regex::new(r"Successfully (?:ran|tested|compiled) (\S+)!").unwrap();
+}
+
+#[derive(Debug, Deserialize)]
+struct Info {
+    exercises: Vec<InfoExercise>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InfoExercise {
+    name: String,
+    path: String,
+    mode: String,
+    hint: String,
+}
+
+fn load_info() -> Result<Vec<InfoExercise>, Box<dyn Error>> {
+    let content = fs::read_to_string(INFO_FILE).map_err(|_| {
+        format!(
+            "No {} in the workspace, is it a rustlings project?",
+            INFO_FILE
+        )
+    })?;
+    let info: Info = toml::from_str(&content)?;
+    Ok(info.exercises)
+}
+
+fn find_exercise(name: &str) -> Result<InfoExercise, Box<dyn Error>> {
+    load_info()?
+        .into_iter()
+        .find(|e| e.name == name)
+        .ok_or_else(|| format!("No rustlings exercise named {:?}", name).into())
+}
+
+fn is_done(exercise: &InfoExercise) -> bool {
+    fs::read_to_string(&exercise.path).is_ok_and(|content| {
+        !content
+            .lines()
+            .any(|line| line.trim_start().starts_with("//") && line.contains(NOT_DONE_MARKER))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum RustlingsStage {
+    Compile,
+    Test,
+    Run,
+    Clippy,
+    // Passes, but still has its `I AM NOT DONE` marker
+    NotDone,
+}
+
+// Where `rustlings verify` / `run` stopped, and the compiler or test output explaining why
+#[derive(Debug, PartialEq)]
+struct Failure {
+    path: String,
+    stage: RustlingsStage,
+    output: String,
+}
+
+fn parse_failure(output: &str) -> Option<Failure> {
+    let output = ANSI_ESCAPE.replace_all(output, "");
+    if let Some(captures) = FAILED.captures(&output) {
+        let stage = match &captures[1] {
+            "Compiling" => RustlingsStage::Compile,
+            "Compilation" => RustlingsStage::Clippy,
+            "Testing" => RustlingsStage::Test,
+            _ => RustlingsStage::Run,
+        };
+        let end = captures.get(0).unwrap().end();
+        let rest = &output[end..];
+        // Everything after "Here's the output:"
+        let details = match rest.find('\n') {
+            Some(newline) => rest[newline + 1..].trim(),
+            None => "",
+        };
+        return Some(Failure {
+            path: captures[2].to_string(),
+            stage,
+            output: details.to_string(),
+        });
+    }
+    match (
+        SUCCEEDED.captures(&output),
+        output.contains(NOT_DONE_MARKER),
+    ) {
+        (Some(captures), true) => Some(Failure {
+            path: captures[1].to_string(),
+            stage: RustlingsStage::NotDone,
+            output: String::new(),
+        }),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RustlingsExercise {
+    pub name: String,
+    pub path: String,
+    // compile, test or clippy
+    pub mode: String,
+    pub done: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RustlingsListRequest {}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RustlingsListResponse {
+    // In the order rustlings goes through them
+    pub exercises: Vec<RustlingsExercise>,
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RustlingsHintRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RustlingsHintResponse {
+    pub name: String,
+    pub hint: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RustlingsRunRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RustlingsRunResponse {
+    pub name: String,
+    pub path: String,
+    pub success: bool,
+    // Where it failed, if it did
+    pub stage: Option<RustlingsStage>,
+    // Compiler or test output for a failure
+    pub output: String,
+    pub stdout: String,
+    pub stderr: String,
+    pub outcome: CommandOutcome,
+    pub exit_status: Option<i32>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct RustlingsVerifyRequest {}
 
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct RustlingsVerifyResponse {
+    // Every exercise passes and is marked done
+    pub success: bool,
+    // The first exercise that isn't
+    pub exercise: Option<String>,
+    pub path: Option<String>,
+    pub stage: Option<RustlingsStage>,
+    // Compiler or test output for that exercise
+    pub output: String,
     pub stdout: String,
+    pub stderr: String,
+    pub outcome: CommandOutcome,
+    pub exit_status: Option<i32>,
+}
+
+impl RustlingsListRequest {
+    pub async fn process(self) -> Result<RustlingsListResponse, Box<dyn Error>> {
+        let exercises: Vec<RustlingsExercise> = load_info()?
+            .into_iter()
+            .map(|e| RustlingsExercise {
+                done: is_done(&e),
+                name: e.name,
+                path: e.path,
+                mode: e.mode,
+            })
+            .collect();
+        Ok(RustlingsListResponse {
+            done: exercises.iter().filter(|e| e.done).count(),
+            total: exercises.len(),
+            exercises,
+        })
+    }
+}
+
+impl RustlingsHintRequest {
+    pub async fn process(self) -> Result<RustlingsHintResponse, Box<dyn Error>> {
+        let exercise = find_exercise(&self.name)?;
+        Ok(RustlingsHintResponse {
+            name: exercise.name,
+            hint: exercise.hint.trim().to_string(),
+        })
+    }
+}
+
+impl RustlingsRunRequest {
+    pub async fn process(self) -> Result<RustlingsRunResponse, Box<dyn Error>> {
+        // Only names from info.toml reach the command line
+        let exercise = find_exercise(&self.name)?;
+        let args = vec!["run", exercise.name.as_str()];
+        let CommandResult {
+            stdout,
+            stderr,
+            outcome,
+            exit_status,
+            ..
+        } = run_command_with_timeout("rustlings", &args, TIMEOUT).await?;
+        let failure = parse_failure(&format!("{}\n{}", stdout, stderr));
+        Ok(RustlingsRunResponse {
+            name: exercise.name,
+            path: exercise.path,
+            success: exit_status == Some(0),
+            stage: failure.as_ref().map(|f| f.stage),
+            output: failure.map(|f| f.output).unwrap_or_default(),
+            stdout,
+            stderr,
+            outcome,
+            exit_status,
+        })
+    }
 }
 
 impl RustlingsVerifyRequest {
     pub async fn process(self) -> Result<RustlingsVerifyResponse, Box<dyn Error>> {
         let cmd = "rustlings";
         let args = vec!["verify"];
-        let timeout_duration = Duration::from_secs(5);
         let CommandResult {
             stdout,
             stderr,
+            outcome,
             exit_status,
-        } = run_command_with_timeout(cmd, &args, timeout_duration).await?;
-        Ok(RustlingsVerifyResponse { stdout })
+            ..
+        } = run_command_with_timeout(cmd, &args, TIMEOUT).await?;
+        let failure = parse_failure(&format!("{}\n{}", stdout, stderr));
+        let exercise = match &failure {
+            Some(failure) => load_info()
+                .unwrap_or_default()
+                .into_iter()
+                .find(|e| e.path == failure.path)
+                .map(|e| e.name),
+            None => None,
+        };
+        Ok(RustlingsVerifyResponse {
+            success: exit_status == Some(0) && failure.is_none(),
+            exercise,
+            path: failure.as_ref().map(|f| f.path.clone()),
+            stage: failure.as_ref().map(|f| f.stage),
+            output: failure.map(|f| f.output).unwrap_or_default(),
+            stdout,
+            stderr,
+            outcome,
+            exit_status,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        dir
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_list_and_hint(_tmp_dir: TempDir) {
+        let info = r#"
+[[exercises]]
+name = "intro1"
+path = "exercises/intro/intro1.rs"
+mode = "compile"
+hint = "No hints this time ;)"
+
+[[exercises]]
+name = "intro2"
+path = "exercises/intro/intro2.rs"
+mode = "compile"
+hint = """
+Add an argument after the format string."""
+"#;
+        fs::write(INFO_FILE, info).unwrap();
+        fs::create_dir_all("exercises/intro").unwrap();
+        fs::write("exercises/intro/intro1.rs", "fn main() {}\n").unwrap();
+        fs::write(
+            "exercises/intro/intro2.rs",
+            "// I AM NOT DONE\n\nfn main() {}\n",
+        )
+        .unwrap();
+
+        let response = RustlingsListRequest {}.process().await.unwrap();
+        assert_eq!((response.done, response.total), (1, 2));
+        assert!(response.exercises[0].done);
+        assert_eq!(response.exercises[1].name, "intro2");
+        assert!(!response.exercises[1].done);
+
+        let request = RustlingsHintRequest {
+            name: "intro2".to_string(),
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.hint, "Add an argument after the format string.");
+
+        let request = RustlingsRunRequest {
+            name: "--help".to_string(),
+        };
+        let error = request.process().await.unwrap_err();
+        assert!(error.to_string().contains("No rustlings exercise"));
+    }
+
+    #[test]
+    fn test_parse_failure() {
+        let output = "Progress: [>-----] 0/94\n\u{1b}[1m\u{1b}[31m⚠️  Compiling of exercises/intro/intro2.rs failed! Please try again. Here's the output:\u{1b}[0m\nerror: 1 positional argument in format string, but no arguments were given\n --> exercises/intro/intro2.rs:8:21\n\nerror: aborting due to previous error\n\n";
+        let failure = parse_failure(output).unwrap();
+        assert_eq!(failure.path, "exercises/intro/intro2.rs");
+        assert_eq!(failure.stage, RustlingsStage::Compile);
+        assert!(failure.output.starts_with("error: 1 positional argument"));
+        assert!(failure.output.ends_with("aborting due to previous error"));
+
+        let output = "⚠️  Testing of exercises/variables/variables1.rs failed! Please try again. Here's the output:\nthread 'tests::it_works' panicked\n";
+        assert_eq!(parse_failure(output).unwrap().stage, RustlingsStage::Test);
+
+        let output = "✅ Successfully ran exercises/intro/intro1.rs!\n\n🎉 🎉  The code is compiling! 🎉 🎉\n\nYou can keep working on this exercise,\nor jump into the next one by removing the `I AM NOT DONE` comment:\n";
+        let failure = parse_failure(output).unwrap();
+        assert_eq!(failure.path, "exercises/intro/intro1.rs");
+        assert_eq!(failure.stage, RustlingsStage::NotDone);
+
+        assert_eq!(parse_failure("🎉 All exercises completed! 🎉\n"), None);
     }
 }
@@ -0,0 +1,458 @@
+//! Persistent shells, so `cd`, exported variables and activated virtualenvs carry over from one
+//! command to the next the way they would in a terminal.
+//!
+//! Each shell is bash on a PTY. Every command is followed by a sentinel line carrying an id for
+//! that command and `$?`, which is how the end of its output and its exit code are found.
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{Read, Write},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use lazy_static::lazy_static;
+use poem_openapi::Object;
+use portable_pty::{native_pty_system, Child, CommandBuilder, MasterPty, PtySize};
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc,
+    time::{timeout_at, Duration},
+};
+
+use crate::{
+    notifications,
+    operations::{
+        commands::{
+            run_command::{resolve_cwd, MAX_TIMEOUT_SECS},
+            utils::MAX_CAPTURE_BYTES,
+        },
+        fs::history,
+    },
+    settings,
+};
+
+// How long to wait for the shell to start, or to settle after interrupting a command
+const SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Longest a sentinel line can be: newlines, marker, 32 character id and a 32 bit status
+const SENTINEL_MAX_LEN: usize = 64;
+
+lazy_static! {
+    static ref SHELLS: Mutex<HashMap<String, Arc<tokio::sync::Mutex<Shell>>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+struct Shell {
+    // Taken when the shell is dropped, to reap it
+    child: Option<Box<dyn Child + Send + Sync>>,
+    // Closing the master side would hang up the shell
+    _master: Box<dyn MasterPty + Send>,
+    writer: Box<dyn Write + Send>,
+    // Filled by a thread reading the PTY, which ends when the shell exits
+    output: mpsc::UnboundedReceiver<Vec<u8>>,
+    // Output read but not yet returned, at most the last MAX_CAPTURE_BYTES of it plus room for
+    // the sentinel
+    pending: Vec<u8>,
+    // Output of the current command was dropped from the front of `pending`
+    truncated: bool,
+    last_used: Instant,
+}
+
+impl Drop for Shell {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            // SIGHUP, which bash passes on to its jobs
+            let _ = child.kill();
+            std::thread::spawn(move || child.wait());
+        }
+    }
+}
+
+impl Shell {
+    async fn open(cwd: std::path::PathBuf) -> Result<Self, Box<dyn Error>> {
+        let size = PtySize {
+            rows: 24,
+            cols: 200,
+            pixel_width: 0,
+            pixel_height: 0,
+        };
+        let pair = native_pty_system()
+            .openpty(size)
+            .map_err(|e| e.to_string())?;
+        let mut cmd = CommandBuilder::new("bash");
+        // No rc files or readline, so nothing but the commands' own output ends up on the PTY
+        cmd.args(["--noprofile", "--norc", "--noediting", "-i"]);
+        cmd.cwd(cwd);
+        cmd.env("TERM", "dumb");
+        cmd.env("PS1", "");
+        cmd.env("PS2", "");
+        let child = pair.slave.spawn_command(cmd).map_err(|e| e.to_string())?;
+        let mut reader = pair.master.try_clone_reader().map_err(|e| e.to_string())?;
+        let writer = pair.master.take_writer().map_err(|e| e.to_string())?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            let mut chunk = [0; 4096];
+            while let Ok(size) = reader.read(&mut chunk) {
+                if size == 0 || tx.send(chunk[..size].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut shell = Shell {
+            child: Some(child),
+            _master: pair.master,
+            writer,
+            output: rx,
+            pending: Vec::new(),
+            truncated: false,
+            last_used: Instant::now(),
+        };
+        // Stop the PTY echoing input and turning \n into \r\n
+        match shell.run("stty -echo -onlcr", SYNC_TIMEOUT).await? {
+            Some(_) => Ok(shell),
+            None => Err("Shell didn't start".into()),
+        }
+    }
+
+    // Output and exit code of the command, None if it didn't finish in time
+    async fn run(
+        &mut self,
+        command: &str,
+        timeout: Duration,
+    ) -> Result<Option<(String, i32)>, Box<dyn Error>> {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        // The format string keeps the id and status apart, so an echo of this line can't match
+        let sentinel = Regex::new(&format!(r"\n?__CARABINER_{}_(\d+)__\n", id))?;
+        let input = format!(
+            "{}\nprintf '\\n__CARABINER_%s_%s__\\n' {} \"$?\"\n",
+            command, id
+        );
+        self.writer.write_all(input.as_bytes())?;
+        self.writer.flush()?;
+        self.truncated = false;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        // Only the output that arrived since the last search is searched again, plus enough
+        // before it to catch a sentinel split across chunks
+        let mut searched: usize = 0;
+        loop {
+            let from = searched.saturating_sub(SENTINEL_MAX_LEN);
+            if let Some(captures) = sentinel.captures(&self.pending[from..]) {
+                let end = captures.get(0).unwrap();
+                let output = last_bytes(&self.pending[..from + end.start()]);
+                let exit_code = std::str::from_utf8(&captures[1])?.parse()?;
+                self.pending.drain(..from + end.end());
+                return Ok(Some((output, exit_code)));
+            }
+            searched = self.pending.len();
+            match timeout_at(deadline, self.output.recv()).await {
+                Ok(Some(chunk)) => self.pending.extend(chunk),
+                Ok(None) => return Err("The shell exited".into()),
+                Err(_) => return Ok(None),
+            }
+            if self.pending.len() > MAX_CAPTURE_BYTES + SENTINEL_MAX_LEN {
+                let excess = self.pending.len() - MAX_CAPTURE_BYTES - SENTINEL_MAX_LEN;
+                self.pending.drain(..excess);
+                searched = searched.saturating_sub(excess);
+                self.truncated = true;
+            }
+        }
+    }
+
+    // Ctrl-C the running command and wait for the shell to be ready for the next one
+    async fn interrupt(&mut self) -> String {
+        let output = last_bytes(&self.pending);
+        self.pending.clear();
+        if self.writer.write_all(b"\x03").is_ok() && self.writer.flush().is_ok() {
+            let _ = self.run(":", SYNC_TIMEOUT).await;
+        }
+        output
+    }
+}
+
+// The last MAX_CAPTURE_BYTES of the output, as text
+fn last_bytes(output: &[u8]) -> String {
+    let start = output.len().saturating_sub(MAX_CAPTURE_BYTES);
+    String::from_utf8_lossy(&output[start..]).to_string()
+}
+
+// Close shells that have been idle for longer than the configured timeout
+fn reap_idle() {
+    let idle_timeout = Duration::from_secs(settings::get().shell_idle_timeout_secs);
+    let mut shells = SHELLS.lock().unwrap();
+    // A shell that's locked is running a command, so it isn't idle
+    shells.retain(|_, shell| match shell.try_lock() {
+        Ok(shell) => shell.last_used.elapsed() < idle_timeout,
+        Err(_) => true,
+    });
+}
+
+fn get_shell(session_id: &str) -> Result<Arc<tokio::sync::Mutex<Shell>>, Box<dyn Error>> {
+    reap_idle();
+    match SHELLS.lock().unwrap().get(session_id) {
+        Some(shell) => Ok(shell.clone()),
+        None => Err(format!(
+            "No shell session {:?}, it may have been closed as idle",
+            session_id
+        )
+        .into()),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ShellOpenRequest {
+    // Directory to start in, inside the workspace, the workspace root if not set
+    pub cwd: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ShellOpenResponse {
+    pub session_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ShellExecRequest {
+    pub session_id: String,
+    // Run as if typed at the prompt
+    pub command: String,
+    // Past this the command is interrupted with Ctrl-C, the shell stays open
+    #[oai(default = "default_timeout_secs")]
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ShellExecResponse {
+    // stdout and stderr interleaved, as a terminal shows them
+    pub output: String,
+    // None if the command timed out
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    // Only the last MAX_CAPTURE_BYTES of the output were kept
+    pub truncated: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ShellCloseRequest {
+    pub session_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ShellCloseResponse {}
+
+impl ShellOpenRequest {
+    pub async fn process(self) -> Result<ShellOpenResponse, Box<dyn Error>> {
+        reap_idle();
+        let max_shells = settings::get().max_shells;
+        // A shell runs anything, unlike the allowlisted RunCommand / ProcessStart
+        if max_shells == 0 {
+            return Err(
+                "Shells are disabled, set MAX_SHELLS in the Agent config to allow them".into(),
+            );
+        }
+        if SHELLS.lock().unwrap().len() >= max_shells {
+            return Err(format!(
+                "Already {} shells open, close one with ShellClose first",
+                max_shells
+            )
+            .into());
+        }
+        let cwd = match &self.cwd {
+            Some(cwd) => resolve_cwd(cwd).await?,
+            None => std::env::current_dir()?,
+        };
+        let shell = Shell::open(cwd).await?;
+        let session_id = uuid::Uuid::new_v4().to_string();
+        SHELLS
+            .lock()
+            .unwrap()
+            .insert(session_id.clone(), Arc::new(tokio::sync::Mutex::new(shell)));
+        Ok(ShellOpenResponse { session_id })
+    }
+}
+
+impl ShellExecRequest {
+    pub async fn process(self) -> Result<ShellExecResponse, Box<dyn Error>> {
+        if self.timeout_secs == 0 || self.timeout_secs > MAX_TIMEOUT_SECS {
+            return Err(format!("timeout_secs must be between 1 and {}", MAX_TIMEOUT_SECS).into());
+        }
+        let shell = get_shell(&self.session_id)?;
+        let mut shell = shell.lock().await;
+        let _command = notifications::command_started();
+        history::record_unjournaled("ShellExec");
+        let result = shell
+            .run(&self.command, Duration::from_secs(self.timeout_secs))
+            .await;
+        shell.last_used = Instant::now();
+        let truncated = shell.truncated;
+        match result {
+            Ok(Some((output, exit_code))) => Ok(ShellExecResponse {
+                output,
+                exit_code: Some(exit_code),
+                timed_out: false,
+                truncated,
+            }),
+            Ok(None) => Ok(ShellExecResponse {
+                output: shell.interrupt().await,
+                exit_code: None,
+                timed_out: true,
+                truncated,
+            }),
+            Err(e) => {
+                // e.g. the command was `exit`
+                SHELLS.lock().unwrap().remove(&self.session_id);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl ShellCloseRequest {
+    pub async fn process(self) -> Result<ShellCloseResponse, Box<dyn Error>> {
+        match SHELLS.lock().unwrap().remove(&self.session_id) {
+            Some(_) => Ok(ShellCloseResponse {}),
+            None => Err(format!("No shell session {:?}", self.session_id).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::settings::OperationSettings;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        SHELLS.lock().unwrap().clear();
+        settings::set(OperationSettings {
+            max_shells: 4,
+            ..Default::default()
+        });
+        dir
+    }
+
+    async fn exec(session_id: &str, command: &str) -> ShellExecResponse {
+        let request = ShellExecRequest {
+            session_id: session_id.to_string(),
+            command: command.to_string(),
+            timeout_secs: 1,
+        };
+        request.process().await.unwrap()
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_shell_session(_tmp_dir: TempDir) {
+        std::fs::create_dir("sub").unwrap();
+        let session_id = ShellOpenRequest { cwd: None }
+            .process()
+            .await
+            .unwrap()
+            .session_id;
+
+        let response = exec(&session_id, "cd sub && export GREETING=hello").await;
+        assert_eq!(
+            (response.output.as_str(), response.exit_code),
+            ("", Some(0))
+        );
+        let response = exec(&session_id, "basename \"$PWD\"; echo $GREETING").await;
+        assert_eq!(response.output, "sub\nhello\n");
+        let response = exec(&session_id, "printf partial; false").await;
+        assert_eq!(
+            (response.output.as_str(), response.exit_code),
+            ("partial", Some(1))
+        );
+
+        let response = exec(&session_id, "echo started; sleep 10").await;
+        assert!(response.timed_out);
+        assert_eq!(response.exit_code, None);
+        assert!(response.output.starts_with("started\n"));
+        let response = exec(&session_id, "echo still here").await;
+        assert_eq!(response.output, "still here\n");
+
+        let request = ShellCloseRequest {
+            session_id: session_id.clone(),
+        };
+        request.process().await.unwrap();
+        let request = ShellExecRequest {
+            session_id,
+            command: "true".to_string(),
+            timeout_secs: 1,
+        };
+        assert!(request.process().await.is_err());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_large_output(_tmp_dir: TempDir) {
+        let session_id = ShellOpenRequest { cwd: None }
+            .process()
+            .await
+            .unwrap()
+            .session_id;
+        let request = ShellExecRequest {
+            session_id: session_id.clone(),
+            command: format!(
+                "head -c {} /dev/zero | tr '\\0' x",
+                MAX_CAPTURE_BYTES + 1000
+            ),
+            timeout_secs: 60,
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.exit_code, Some(0));
+        assert!(response.truncated);
+        assert_eq!(response.output.len(), MAX_CAPTURE_BYTES);
+
+        let response = exec(&session_id, "echo small").await;
+        assert_eq!(response.output, "small\n");
+        assert!(!response.truncated);
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_shell_limits(_tmp_dir: TempDir) {
+        settings::set(OperationSettings {
+            max_shells: 1,
+            shell_idle_timeout_secs: 0,
+            ..Default::default()
+        });
+        let first = ShellOpenRequest { cwd: None }.process().await.unwrap();
+        // The first shell is idle straight away with a zero timeout, so it's closed to make room
+        let second = ShellOpenRequest { cwd: None }.process().await.unwrap();
+        assert!(get_shell(&first.session_id).is_err());
+        assert!(get_shell(&second.session_id).is_err());
+
+        settings::set(OperationSettings {
+            max_shells: 1,
+            ..Default::default()
+        });
+        let open = ShellOpenRequest { cwd: None }.process().await.unwrap();
+        let response = ShellOpenRequest { cwd: None }.process().await;
+        assert!(response.unwrap_err().to_string().contains("shells open"));
+
+        let request = ShellExecRequest {
+            session_id: open.session_id,
+            command: "true".to_string(),
+            timeout_secs: MAX_TIMEOUT_SECS + 1,
+        };
+        assert!(request.process().await.is_err());
+
+        settings::set(OperationSettings::default());
+        let response = ShellOpenRequest { cwd: None }.process().await;
+        assert!(response.unwrap_err().to_string().contains("disabled"));
+    }
+}
@@ -1,67 +1,272 @@
+use std::collections::HashSet;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
 use std::process::Stdio;
-use tokio::io::{AsyncRead, AsyncReadExt};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use poem_openapi::Enum;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::process::Command;
-use tokio::time::{timeout, Duration};
+use tokio::sync::Notify;
+use tokio::time::{timeout_at, Duration, Instant};
+
+use crate::notifications;
+
+// Output kept per stream, a process printing more than this is killed
+pub const MAX_CAPTURE_BYTES: usize = 16 * 1024 * 1024;
+
+// How long output still in the pipes is read for once a command has ended, when it's already
+// past its deadline
+const DRAIN_GRACE_PERIOD: Duration = Duration::from_millis(100);
+
+lazy_static! {
+    static ref PROCESS_GROUPS: Mutex<HashSet<i32>> = Mutex::new(HashSet::new());
+}
+
+// The process group of a command, background process or shell the Agent started. Those run in
+// their own group so that killing it takes anything they started too, which also means a
+// Ctrl-C on the Agent doesn't reach them, so the groups are tracked for `kill_process_groups`.
+// The whole group is killed when this is dropped.
+#[derive(Debug)]
+pub struct ProcessGroup(i32);
+
+impl ProcessGroup {
+    // For a process spawned with `process_group(0)` (or `setsid`), so its pid is the group id
+    pub fn track(pid: u32) -> Self {
+        let pgid = pid as i32;
+        PROCESS_GROUPS.lock().unwrap().insert(pgid);
+        Self(pgid)
+    }
+
+    // Signal every process in the group, including any left behind after the leader exited
+    pub fn signal(&self, signal: i32) {
+        unsafe { libc::kill(-self.0, signal) };
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if PROCESS_GROUPS.lock().unwrap().remove(&self.0) {
+            self.signal(libc::SIGKILL);
+        }
+    }
+}
+
+// Kill every process group the Agent started, for when the Agent itself is stopped
+pub fn kill_process_groups() {
+    for pgid in PROCESS_GROUPS.lock().unwrap().drain() {
+        unsafe { libc::kill(-pgid, libc::SIGKILL) };
+    }
+}
+
+// How a process ended
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum CommandOutcome {
+    // Exited by itself, with exit_status
+    Exited,
+    // Terminated by a signal it didn't get from us, e.g. a segfault
+    Signaled,
+    // Killed for running past its timeout
+    TimedOut,
+    // Killed for printing more than MAX_CAPTURE_BYTES
+    KilledByLimit,
+}
 
 #[derive(Debug)]
 pub struct CommandResult {
     pub stdout: String,
     pub stderr: String,
-    pub exit_status: Option<i32>, // None if the process was killed due to timeout
+    // Output had bytes that aren't valid UTF-8, they're replaced with U+FFFD
+    pub invalid_utf8: bool,
+    pub outcome: CommandOutcome,
+    // Only set when the process exited by itself
+    pub exit_status: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+// Set by whichever stream first goes past MAX_CAPTURE_BYTES. The flag is checked after the
+// process ended too: the reader closing its pipe makes the process die of SIGPIPE, which can
+// be noticed before the notification.
+#[derive(Default)]
+struct OutputLimit {
+    reached: AtomicBool,
+    notify: Notify,
 }
 
 // Used for reading stdout / stderr from process, even if process is killed due to timeout
-// (normally .read_to_end is used but that won't work if process is killed)
-async fn read_stream<R: AsyncRead + Unpin>(mut reader: R) -> Vec<u8> {
-    let mut buffer = Vec::new();
+// (normally .read_to_end is used but that won't work if process is killed). Output goes into a
+// shared buffer so whatever was read is kept if the reader is given up on.
+async fn read_stream<R: AsyncRead + Unpin>(
+    mut reader: R,
+    buffer: Arc<Mutex<Vec<u8>>>,
+    limit: Arc<OutputLimit>,
+) {
     let mut chunk = [0; 1024];
     while let Ok(size) = reader.read(&mut chunk).await {
         if size == 0 {
             break;
         }
+        let mut buffer = buffer.lock().unwrap();
         buffer.extend_from_slice(&chunk[..size]);
+        if buffer.len() > MAX_CAPTURE_BYTES {
+            buffer.truncate(MAX_CAPTURE_BYTES);
+            limit.reached.store(true, Ordering::SeqCst);
+            limit.notify.notify_one();
+            break;
+        }
+    }
+}
+
+fn decode(bytes: Vec<u8>) -> (String, bool) {
+    match String::from_utf8(bytes) {
+        Ok(text) => (text, false),
+        Err(e) => (String::from_utf8_lossy(e.as_bytes()).to_string(), true),
+    }
+}
+
+// Optional extras for the spawned process, the Agent's own working directory and environment
+// are used otherwise
+#[derive(Debug, Default)]
+pub struct CommandOptions {
+    pub cwd: Option<PathBuf>,
+    pub env: Vec<(String, String)>,
+    // Written to the process's stdin, which is closed afterwards
+    pub stdin: Option<Vec<u8>>,
+}
+
+// Test failure messages and compiler output are cut down to their last this many bytes
+pub const MAX_OUTPUT_BYTES: usize = 8 * 1024;
+
+pub fn tail(text: &str, max_bytes: usize) -> String {
+    if text.len() <= max_bytes {
+        return text.to_string();
+    }
+    let mut start = text.len() - max_bytes;
+    while !text.is_char_boundary(start) {
+        start += 1;
     }
-    buffer
+    format!("...{}", &text[start..])
 }
 
 pub async fn run_command_with_timeout(
     command: &str,
     args: &[&str],
     timeout_duration: Duration,
+) -> Result<CommandResult, std::io::Error> {
+    run_command(command, args, &CommandOptions::default(), timeout_duration).await
+}
+
+pub async fn run_command(
+    command: &str,
+    args: &[&str],
+    options: &CommandOptions,
+    timeout_duration: Duration,
 ) -> Result<CommandResult, std::io::Error> {
     // Setup the command to spawn
     let mut cmd = Command::new(command);
     cmd.args(args);
+    if let Some(cwd) = &options.cwd {
+        cmd.current_dir(cwd);
+    }
+    cmd.envs(options.env.iter().map(|(k, v)| (k, v)));
+    // Without input it mustn't read the Agent's own stdin
+    match options.stdin {
+        Some(_) => cmd.stdin(Stdio::piped()),
+        None => cmd.stdin(Stdio::null()),
+    };
     cmd.stdout(Stdio::piped());
     cmd.stderr(Stdio::piped());
+    // Its own process group, so killing it also kills anything it started that could keep the
+    // output pipes open
+    cmd.process_group(0);
     cmd.kill_on_drop(true);
 
+    // It may change files in the workspace, which shouldn't be reported back as someone else's
+    let _command = notifications::command_started();
     let mut child = cmd.spawn()?;
+    let group = child.id().map(ProcessGroup::track);
+    let deadline = Instant::now() + timeout_duration;
 
-    let stdout_handle = tokio::spawn(read_stream(child.stdout.take().unwrap()));
-    let stderr_handle = tokio::spawn(read_stream(child.stderr.take().unwrap()));
-
-    match timeout(timeout_duration, child.wait()).await {
-        Ok(exit_status) => {
-            let stdout = String::from_utf8(stdout_handle.await.unwrap()).unwrap();
-            let stderr = String::from_utf8(stderr_handle.await.unwrap()).unwrap();
-            Ok(CommandResult {
-                stdout,
-                stderr,
-                exit_status: Some(exit_status.unwrap().code().unwrap()),
-            })
-        }
-        Err(_) => {
-            child.kill().await?;
-            let stdout = String::from_utf8(stdout_handle.await.unwrap()).unwrap();
-            let stderr = String::from_utf8(stderr_handle.await.unwrap()).unwrap();
-            Ok(CommandResult {
-                stdout,
-                stderr,
-                exit_status: None,
-            })
-        }
+    // Written from a task, a process that doesn't read all of its input would block us otherwise
+    if let (Some(input), Some(mut pipe)) = (options.stdin.clone(), child.stdin.take()) {
+        tokio::spawn(async move {
+            let _ = pipe.write_all(&input).await;
+        });
+    }
+
+    let limit = Arc::new(OutputLimit::default());
+    let buffers = [
+        Arc::new(Mutex::new(Vec::new())),
+        Arc::new(Mutex::new(Vec::new())),
+    ];
+    let mut handles = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        handles.push(tokio::spawn(read_stream(
+            stdout,
+            buffers[0].clone(),
+            limit.clone(),
+        )));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        handles.push(tokio::spawn(read_stream(
+            stderr,
+            buffers[1].clone(),
+            limit.clone(),
+        )));
+    }
+
+    let (mut outcome, mut exit_status, mut signal) = tokio::select! {
+        biased;
+        _ = limit.notify.notified() => (CommandOutcome::KilledByLimit, None, None),
+        result = timeout_at(deadline, child.wait()) => match result {
+            Ok(status) => {
+                let status = status?;
+                match status.code() {
+                    Some(code) => (CommandOutcome::Exited, Some(code), None),
+                    None => (CommandOutcome::Signaled, None, status.signal()),
+                }
+            }
+            Err(_) => (CommandOutcome::TimedOut, None, None),
+        },
+    };
+    if limit.reached.load(Ordering::SeqCst) {
+        (outcome, exit_status, signal) = (CommandOutcome::KilledByLimit, None, None);
+    }
+    // Also when the command exited by itself, anything it left running in the background would
+    // keep the output pipes open
+    drop(group);
+    if outcome == CommandOutcome::TimedOut || outcome == CommandOutcome::KilledByLimit {
+        child.kill().await?;
+    }
+
+    // A process that left the group (e.g. with setsid) can still hold the pipes open, so the
+    // readers are only waited on until the deadline
+    let drain_deadline = deadline.max(Instant::now() + DRAIN_GRACE_PERIOD);
+    for handle in handles.iter_mut() {
+        let _ = timeout_at(drain_deadline, handle).await;
     }
+    for handle in handles {
+        handle.abort();
+    }
+    let mut invalid_utf8 = false;
+    let [stdout, stderr] = buffers.map(|buffer| {
+        let (text, invalid) = decode(std::mem::take(&mut *buffer.lock().unwrap()));
+        invalid_utf8 |= invalid;
+        text
+    });
+    Ok(CommandResult {
+        stdout,
+        stderr,
+        invalid_utf8,
+        outcome,
+        exit_status,
+        signal,
+    })
 }
 
 #[cfg(test)]
@@ -94,6 +299,7 @@ mod tests {
             stdout,
             stderr,
             exit_status,
+            ..
         } = run_command_with_timeout(cmd, &args, timeout_duration)
             .await
             .unwrap();
@@ -115,12 +321,78 @@ mod tests {
         let CommandResult {
             stdout,
             stderr,
+            outcome,
             exit_status,
+            ..
         } = run_command_with_timeout(cmd, &args, timeout_duration)
             .await
             .unwrap();
         assert_eq!(stdout, "Started\n");
         assert_eq!(stderr, "");
+        assert_eq!(outcome, CommandOutcome::TimedOut);
         assert_eq!(exit_status, None);
     }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_invalid_utf8_and_signal(_tmp_dir: TempDir) {
+        let timeout_duration = Duration::from_secs(1);
+        let args = vec!["-c", r"printf 'ok \377\n'"];
+        let result = run_command_with_timeout("sh", &args, timeout_duration)
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "ok \u{FFFD}\n");
+        assert!(result.invalid_utf8);
+        assert_eq!(result.outcome, CommandOutcome::Exited);
+
+        let args = vec!["-c", "kill -SEGV $$"];
+        let result = run_command_with_timeout("sh", &args, timeout_duration)
+            .await
+            .unwrap();
+        assert_eq!(result.outcome, CommandOutcome::Signaled);
+        assert_eq!(result.signal, Some(libc::SIGSEGV));
+        assert_eq!(result.exit_status, None);
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_no_stdin(_tmp_dir: TempDir) {
+        let args = vec!["-c", "readlink /proc/$$/fd/0"];
+        let result = run_command_with_timeout("sh", &args, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "/dev/null\n");
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_output_limit(_tmp_dir: TempDir) {
+        // The background job is in the same process group, so it's killed too and doesn't keep
+        // the output pipe open
+        let args = vec!["-c", "sleep 30 & yes"];
+        let timeout_duration = Duration::from_secs(10);
+        let result = run_command_with_timeout("sh", &args, timeout_duration)
+            .await
+            .unwrap();
+        assert_eq!(result.outcome, CommandOutcome::KilledByLimit);
+        assert_eq!(result.stdout.len(), MAX_CAPTURE_BYTES);
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_background_job_killed(_tmp_dir: TempDir) {
+        // The command exits right away, the job it leaves behind mustn't hold up the result
+        let args = vec!["-c", "sleep 30 & echo started"];
+        let started = Instant::now();
+        let result = run_command_with_timeout("sh", &args, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(result.outcome, CommandOutcome::Exited);
+        assert_eq!(result.stdout, "started\n");
+    }
 }
@@ -0,0 +1,406 @@
+//! Apply a batch of edits across one or more files as a single transaction.
+//!
+//! Every edit is first applied in memory, in order, on top of the edits before it. Only if all
+//! of them succeed are the files written to disk. If a write fails part way through, the files
+//! already written are restored, so the workspace is never left half-modified.
+use std::{
+    error::Error,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use poem_openapi::{Object, Union};
+use serde::{Deserialize, Serialize};
+
+use crate::operations::fs::{
+    create_file::CreateFileRequest,
+    delete_content::DeleteContentRequest,
+    diff::DiffRequest,
+    history::{self, FileChange, FileState},
+    insert_content::InsertContentRequest,
+    remove_file::RemoveFileRequest,
+    replace_content::ReplaceContentRequest,
+    str_replace::StrReplaceRequest,
+    utils::{content_hash, ensure_relative, split_lines, write_atomic, HashConflict, TextFormat},
+};
+
+// One edit in the batch, tagged with the name of the matching standalone operation
+#[derive(Debug, Serialize, Deserialize, Union)]
+#[serde(tag = "type")]
+#[oai(discriminator_name = "type")]
+pub enum FileEdit {
+    #[oai(mapping = "Diff")]
+    Diff(DiffRequest),
+    #[oai(mapping = "InsertContent")]
+    InsertContent(InsertContentRequest),
+    #[oai(mapping = "ReplaceContent")]
+    ReplaceContent(ReplaceContentRequest),
+    #[oai(mapping = "DeleteContent")]
+    DeleteContent(DeleteContentRequest),
+    #[oai(mapping = "StrReplace")]
+    StrReplace(StrReplaceRequest),
+    #[oai(mapping = "CreateFile")]
+    CreateFile(CreateFileRequest),
+    #[oai(mapping = "RemoveFile")]
+    RemoveFile(RemoveFileRequest),
+}
+
+impl FileEdit {
+    fn name(&self) -> &'static str {
+        match self {
+            FileEdit::Diff(_) => "Diff",
+            FileEdit::InsertContent(_) => "InsertContent",
+            FileEdit::ReplaceContent(_) => "ReplaceContent",
+            FileEdit::DeleteContent(_) => "DeleteContent",
+            FileEdit::StrReplace(_) => "StrReplace",
+            FileEdit::CreateFile(_) => "CreateFile",
+            FileEdit::RemoveFile(_) => "RemoveFile",
+        }
+    }
+
+    fn path(&self) -> &str {
+        match self {
+            FileEdit::Diff(edit) => &edit.path,
+            FileEdit::InsertContent(edit) => &edit.path,
+            FileEdit::ReplaceContent(edit) => &edit.path,
+            FileEdit::DeleteContent(edit) => &edit.path,
+            FileEdit::StrReplace(edit) => &edit.path,
+            FileEdit::CreateFile(edit) => &edit.path,
+            FileEdit::RemoveFile(edit) => &edit.path,
+        }
+    }
+
+    fn expected_hash(&self) -> Option<&str> {
+        match self {
+            FileEdit::Diff(edit) => edit.expected_hash.as_deref(),
+            FileEdit::InsertContent(edit) => edit.expected_hash.as_deref(),
+            FileEdit::ReplaceContent(edit) => edit.expected_hash.as_deref(),
+            FileEdit::DeleteContent(edit) => edit.expected_hash.as_deref(),
+            FileEdit::StrReplace(edit) => edit.expected_hash.as_deref(),
+            FileEdit::CreateFile(edit) => edit.expected_hash.as_deref(),
+            FileEdit::RemoveFile(_) => None,
+        }
+    }
+
+    // Apply the edit to a file's staged bytes (None if it doesn't exist)
+    fn apply(&self, bytes: Option<Vec<u8>>) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match self {
+            FileEdit::CreateFile(edit) => return Ok(Some(edit.content.clone().into_bytes())),
+            FileEdit::RemoveFile(_) if bytes.is_none() => return Err("File does not exist".into()),
+            FileEdit::RemoveFile(_) => return Ok(None),
+            _ => {}
+        }
+        let bytes = bytes.ok_or("File does not exist")?;
+        // Text edits work on LF content, same as the standalone operations
+        let format = TextFormat::detect(&bytes);
+        let content = format.decode(&bytes)?;
+        let edited = match self {
+            FileEdit::Diff(edit) => {
+                let lines: Vec<String> = content.lines().map(|l| l.to_string()).collect();
+                edit.edit(&lines)?.join("\n")
+            }
+            FileEdit::InsertContent(edit) => edit.edit(split_lines(&content))?.join("\n"),
+            FileEdit::ReplaceContent(edit) => edit.edit(split_lines(&content))?.join("\n"),
+            FileEdit::DeleteContent(edit) => edit.edit(split_lines(&content))?.join("\n"),
+            FileEdit::StrReplace(edit) => edit.edit(&content)?.0,
+            FileEdit::CreateFile(_) | FileEdit::RemoveFile(_) => unreachable!(),
+        };
+        Ok(Some(format.encode(&format.normalize(&edited))))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ApplyEditsRequest {
+    // Applied in order, later edits to the same file see the result of earlier ones. Each
+    // expected_hash is checked against the file as it was before the batch.
+    pub edits: Vec<FileEdit>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct FileEditResult {
+    pub path: String,
+    // "created", "modified", "removed" or "unchanged"
+    pub status: String,
+    pub edits: usize,
+    // Hash of the new content, None if the file was removed
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ApplyEditsResponse {
+    // One entry per file, in the order each file first appears in the edits
+    pub results: Vec<FileEditResult>,
+}
+
+struct StagedFile {
+    path: PathBuf,
+    // Kept for the history, with the file's mode
+    before: Option<FileState>,
+    original: Option<Vec<u8>>,
+    staged: Option<Vec<u8>>,
+    edits: usize,
+}
+
+impl StagedFile {
+    fn status(&self) -> &'static str {
+        match (&self.original, &self.staged) {
+            (None, Some(_)) => "created",
+            (Some(_), None) => "removed",
+            (original, staged) if original == staged => "unchanged",
+            _ => "modified",
+        }
+    }
+}
+
+fn write_state(path: &Path, content: &Option<Vec<u8>>) -> std::io::Result<()> {
+    match content {
+        Some(content) => write_atomic(path, content),
+        None => match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+    }
+}
+
+// Put a file back the way it was before the batch, mode included
+fn restore(file: &StagedFile) -> std::io::Result<()> {
+    write_state(&file.path, &file.original)?;
+    if let Some(before) = &file.before {
+        fs::set_permissions(&file.path, fs::Permissions::from_mode(before.mode))?;
+    }
+    Ok(())
+}
+
+// Write every changed file, restoring the ones already written if any write fails
+fn commit(files: &[StagedFile]) -> Result<(), String> {
+    let changed: Vec<&StagedFile> = files.iter().filter(|f| f.original != f.staged).collect();
+    for (i, file) in changed.iter().enumerate() {
+        if let Err(e) = write_state(&file.path, &file.staged) {
+            let not_restored: Vec<String> = changed[..i]
+                .iter()
+                .rev()
+                .filter_map(|written| match restore(written) {
+                    Ok(()) => None,
+                    Err(e) => Some(format!("{:?} ({})", written.path, e)),
+                })
+                .collect();
+            if !not_restored.is_empty() {
+                return Err(format!(
+                    "Failed to write {:?}: {}. Restoring the files already written failed for {}, \
+                     those may still have their new content",
+                    file.path,
+                    e,
+                    not_restored.join(", ")
+                ));
+            }
+            return Err(format!(
+                "Failed to write {:?}: {}. No files were changed",
+                file.path, e
+            ));
+        }
+    }
+    Ok(())
+}
+
+impl ApplyEditsRequest {
+    pub async fn process(self) -> Result<ApplyEditsResponse, Box<dyn Error>> {
+        if self.edits.is_empty() {
+            return Err("No edits to apply".into());
+        }
+
+        let mut files: Vec<StagedFile> = Vec::new();
+        for (i, edit) in self.edits.iter().enumerate() {
+            let describe = |e: Box<dyn Error>| -> Box<dyn Error> {
+                format!(
+                    "Edit {} ({} {:?}) failed: {}. No files were changed",
+                    i + 1,
+                    edit.name(),
+                    edit.path(),
+                    e
+                )
+                .into()
+            };
+            let path = ensure_relative(PathBuf::from(edit.path()))
+                .await
+                .map_err(describe)?;
+            let index = match files.iter().position(|f| f.path == path) {
+                Some(index) => index,
+                None => {
+                    let before = history::snapshot(&path);
+                    let original = before.as_ref().map(|s| s.content.clone());
+                    files.push(StagedFile {
+                        path: path.clone(),
+                        before,
+                        staged: original.clone(),
+                        original,
+                        edits: 0,
+                    });
+                    files.len() - 1
+                }
+            };
+            let file = &mut files[index];
+            if let Some(expected) = edit.expected_hash() {
+                let current = file.original.as_deref().map(content_hash);
+                if current.as_deref() != Some(expected) {
+                    let conflict = HashConflict {
+                        path: path.clone(),
+                        expected: expected.to_string(),
+                        current,
+                    };
+                    return Err(describe(Box::new(conflict)));
+                }
+            }
+            file.staged = edit.apply(file.staged.take()).map_err(describe)?;
+            file.edits += 1;
+        }
+
+        let files = tokio::task::spawn_blocking(move || commit(&files).map(|_| files)).await??;
+        history::record(
+            "ApplyEdits",
+            files
+                .iter()
+                .map(|f| FileChange::new(&f.path, f.before.clone()))
+                .collect(),
+        );
+
+        Ok(ApplyEditsResponse {
+            results: files
+                .iter()
+                .map(|f| FileEditResult {
+                    path: f.path.to_string_lossy().to_string(),
+                    status: f.status().to_string(),
+                    edits: f.edits,
+                    hash: f.staged.as_deref().map(content_hash),
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        history::clear();
+        dir
+    }
+
+    fn parse(edits: serde_json::Value) -> ApplyEditsRequest {
+        serde_json::from_value(serde_json::json!({ "edits": edits })).unwrap()
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_apply_edits(_tmp_dir: TempDir) {
+        fs::write("a.py", "import os\n\ndef main():\n    pass\n").unwrap();
+        fs::write("b.py", "old\n").unwrap();
+        let request = parse(serde_json::json!([
+            {"type": "StrReplace", "path": "a.py", "old_str": "    pass", "new_str": "    run()"},
+            {"type": "InsertContent", "path": "a.py", "line": 1, "content": "import sys"},
+            {"type": "CreateFile", "path": "c.py", "content": "def run():\n    pass\n"},
+            {"type": "RemoveFile", "path": "b.py"},
+        ]));
+        let response = request.process().await.unwrap();
+
+        assert_eq!(
+            fs::read_to_string("a.py").unwrap(),
+            "import sys\nimport os\n\ndef main():\n    run()\n"
+        );
+        assert!(Path::new("c.py").exists());
+        assert!(!Path::new("b.py").exists());
+        let statuses: Vec<(&str, &str, usize)> = response
+            .results
+            .iter()
+            .map(|r| (r.path.as_str(), r.status.as_str(), r.edits))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("a.py", "modified", 2),
+                ("c.py", "created", 1),
+                ("b.py", "removed", 1)
+            ]
+        );
+        // The whole batch is a single undo step
+        assert_eq!(history::entries().len(), 1);
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_same_file_different_spelling(_tmp_dir: TempDir) {
+        fs::write("a.py", "x = 1\n").unwrap();
+        let request = parse(serde_json::json!([
+            {"type": "StrReplace", "path": "./a.py", "old_str": "x = 1", "new_str": "x = 2"},
+            {"type": "StrReplace", "path": "a.py", "old_str": "x = 2", "new_str": "x = 3"},
+        ]));
+        let response = request.process().await.unwrap();
+        assert_eq!(response.results.len(), 1);
+        assert_eq!(response.results[0].edits, 2);
+        assert_eq!(fs::read_to_string("a.py").unwrap(), "x = 3\n");
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_failed_write_restores(_tmp_dir: TempDir) {
+        fs::write("a.py", "x = 1\n").unwrap();
+        fs::set_permissions("a.py", fs::Permissions::from_mode(0o755)).unwrap();
+        // A file where the new file's directory would have to be
+        fs::write("pkg", "").unwrap();
+        let request = parse(serde_json::json!([
+            {"type": "RemoveFile", "path": "a.py"},
+            {"type": "CreateFile", "path": "pkg/b.py", "content": "y = 1\n"},
+        ]));
+        let err = request.process().await.unwrap_err().to_string();
+        assert!(err.ends_with("No files were changed"));
+        assert_eq!(fs::read_to_string("a.py").unwrap(), "x = 1\n");
+        let mode = fs::metadata("a.py").unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+        assert!(history::entries().is_empty());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_failed_edit_changes_nothing(_tmp_dir: TempDir) {
+        fs::write("a.py", "x = 1\n").unwrap();
+        fs::write("b.py", "y = 2\n").unwrap();
+        let request = parse(serde_json::json!([
+            {"type": "ReplaceContent", "path": "a.py", "start_line": 1, "content": "x = 10"},
+            {"type": "CreateFile", "path": "c.py", "content": "z = 3\n"},
+            {"type": "StrReplace", "path": "b.py", "old_str": "y = 3", "new_str": "y = 4"},
+        ]));
+        let err = request.process().await.unwrap_err().to_string();
+
+        assert!(err.starts_with("Edit 3 (StrReplace \"b.py\") failed: old_str was not found"));
+        assert!(err.ends_with("No files were changed"));
+        assert_eq!(fs::read_to_string("a.py").unwrap(), "x = 1\n");
+        assert!(!Path::new("c.py").exists());
+        assert!(history::entries().is_empty());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_expected_hash_checked_before_batch(_tmp_dir: TempDir) {
+        fs::write("a.py", "x = 1\n").unwrap();
+        let hash = content_hash(b"x = 1\n");
+        let request = parse(serde_json::json!([
+            {"type": "ReplaceContent", "path": "a.py", "start_line": 1, "content": "x = 2", "expected_hash": hash},
+            {"type": "InsertContent", "path": "a.py", "line": 2, "content": "y = 1", "expected_hash": hash},
+            {"type": "DeleteContent", "path": "a.py", "start_line": 1, "expected_hash": "stale"},
+        ]));
+        let err = request.process().await.unwrap_err().to_string();
+        assert!(err.starts_with("Edit 3 (DeleteContent \"a.py\") failed: Conflict"));
+        assert_eq!(fs::read_to_string("a.py").unwrap(), "x = 1\n");
+    }
+}
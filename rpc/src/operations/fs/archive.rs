@@ -0,0 +1,535 @@
+//! Move a workspace subtree between the server and the Agent as a base64 encoded tar.gz.
+//!
+//! PackArchive honors .gitignore (so build output isn't shipped around) and never includes
+//! .git. UnpackArchive checks every entry before writing anything: only plain files and
+//! directories are allowed (pax and GNU headers describing them are skipped), paths have to stay
+//! inside the destination, and the total unpacked size is capped, so a hostile or broken archive
+//! can't escape the workspace or fill the disk.
+use std::{
+    error::Error,
+    fs,
+    io::{Cursor, Read},
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use ignore::WalkBuilder;
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+use tar::{Archive, Builder, EntryType};
+
+use crate::operations::fs::{
+    copy_path::OverwritePolicy,
+    history::{self, FileChange},
+    utils::{ensure_not_protected, ensure_not_symlinked, ensure_relative, write_atomic, AGENT_DIR},
+};
+
+pub fn default_max_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+pub fn default_max_entries() -> usize {
+    10_000
+}
+
+fn default_dest_path() -> String {
+    ".".to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct PackArchiveRequest {
+    pub path: String,
+    // Limit on the total size of the files going into the archive, before compression
+    #[oai(default = "default_max_bytes")]
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct PackArchiveResponse {
+    // Base64 encoded tar.gz, entry paths relative to the packed directory
+    pub archive: String,
+    pub files: usize,
+    // Symlinks aren't packed, since UnpackArchive would refuse them anyway
+    pub skipped: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct UnpackArchiveRequest {
+    // Base64 encoded tar.gz
+    pub archive: String,
+    #[oai(default = "default_dest_path")]
+    #[serde(default = "default_dest_path")]
+    pub dest_path: String,
+    #[oai(default)]
+    #[serde(default)]
+    pub overwrite: OverwritePolicy,
+    // Limit on the total unpacked size of the files in the archive
+    #[oai(default = "default_max_bytes")]
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    #[oai(default = "default_max_entries")]
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct UnpackArchiveResponse {
+    pub files: Vec<String>,
+    // Files that already existed and were left alone because of the `skip` overwrite policy
+    pub skipped: Vec<String>,
+}
+
+pub fn pack(root: &Path, max_bytes: u64) -> Result<PackArchiveResponse, Box<dyn Error>> {
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let mut builder = Builder::new(encoder);
+    builder.follow_symlinks(false);
+    let mut total: u64 = 0;
+    let mut files = 0;
+    let mut skipped = Vec::new();
+
+    let walker = WalkBuilder::new(root)
+        .hidden(false)
+        .require_git(false)
+        .filter_entry(|entry| entry.file_name() != ".git" && entry.file_name() != AGENT_DIR)
+        .build();
+    for entry in walker {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(root)?.to_path_buf();
+        if relative.as_os_str().is_empty() {
+            continue;
+        }
+        let file_type = entry.file_type().ok_or("Can't archive stdin")?;
+        if file_type.is_dir() {
+            builder.append_dir(&relative, entry.path())?;
+        } else if file_type.is_file() {
+            total += entry.metadata()?.len();
+            if total > max_bytes {
+                return Err(format!(
+                    "Directory holds more than {} bytes of files, raise max_bytes to pack it",
+                    max_bytes
+                )
+                .into());
+            }
+            builder.append_path_with_name(entry.path(), &relative)?;
+            files += 1;
+        } else {
+            skipped.push(relative.to_string_lossy().to_string());
+        }
+    }
+    let bytes = builder.into_inner()?.finish()?;
+    Ok(PackArchiveResponse {
+        archive: STANDARD.encode(bytes),
+        files,
+        skipped,
+    })
+}
+
+// Entry path relative to the destination, rejecting anything that could point outside of it
+fn entry_path(path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir => {}
+            _ => return Err(format!("Archive entry {:?} escapes the destination", path).into()),
+        }
+    }
+    ensure_not_protected(&relative)?;
+    Ok(relative)
+}
+
+// Refuse to write through a symlink that's already in the workspace, it could point anywhere
+fn ensure_no_symlinks(dest: &Path, relative: &Path) -> Result<(), Box<dyn Error>> {
+    let mut path = dest.to_path_buf();
+    for component in relative.components() {
+        path.push(component);
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                return Err(format!("Archive entry would write through symlink {:?}", path).into())
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum EntryKind {
+    File,
+    Directory,
+    // Pax / GNU headers that only describe other entries (e.g. the commit id git archive adds),
+    // nothing to unpack
+    Metadata,
+}
+
+struct PlannedEntry {
+    relative: PathBuf,
+    kind: EntryKind,
+}
+
+// First pass over the archive, checking every entry without writing anything
+fn plan(
+    archive: &[u8],
+    dest: &Path,
+    max_bytes: u64,
+    max_entries: usize,
+) -> Result<Vec<PlannedEntry>, Box<dyn Error>> {
+    let mut archive = Archive::new(GzDecoder::new(Cursor::new(archive)));
+    let mut planned = Vec::new();
+    let mut total: u64 = 0;
+    for entry in archive.entries()? {
+        let entry = entry?;
+        // Metadata entries count too, so they can't pad an archive out endlessly
+        if planned.len() >= max_entries {
+            return Err(format!(
+                "Archive has more than {} entries, raise max_entries to unpack it",
+                max_entries
+            )
+            .into());
+        }
+        let kind = match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => EntryKind::File,
+            EntryType::Directory => EntryKind::Directory,
+            EntryType::XGlobalHeader
+            | EntryType::XHeader
+            | EntryType::GNULongName
+            | EntryType::GNULongLink => EntryKind::Metadata,
+            other => {
+                let path = entry.path()?;
+                return Err(format!(
+                    "Archive entry {:?} is a {:?}, only files and directories are allowed",
+                    path, other
+                )
+                .into());
+            }
+        };
+        if kind == EntryKind::Metadata {
+            planned.push(PlannedEntry {
+                relative: PathBuf::new(),
+                kind,
+            });
+            continue;
+        }
+        let relative = entry_path(&entry.path()?)?;
+        ensure_no_symlinks(dest, &relative)?;
+        total += entry.header().size()?;
+        if total > max_bytes {
+            return Err(format!(
+                "Archive unpacks to more than {} bytes, raise max_bytes to unpack it",
+                max_bytes
+            )
+            .into());
+        }
+        planned.push(PlannedEntry { relative, kind });
+    }
+    Ok(planned)
+}
+
+pub fn unpack(
+    archive: &[u8],
+    dest: &Path,
+    overwrite: OverwritePolicy,
+    max_bytes: u64,
+    max_entries: usize,
+) -> Result<UnpackArchiveResponse, Box<dyn Error>> {
+    let planned = plan(archive, dest, max_bytes, max_entries)?;
+    if overwrite == OverwritePolicy::Error {
+        if let Some(existing) = planned
+            .iter()
+            .find(|e| e.kind == EntryKind::File && dest.join(&e.relative).exists())
+        {
+            return Err(format!(
+                "Destination {:?} already exists",
+                dest.join(&existing.relative)
+            )
+            .into());
+        }
+    }
+
+    let mut response = UnpackArchiveResponse {
+        files: Vec::new(),
+        skipped: Vec::new(),
+    };
+    let mut changes = Vec::new();
+    let unpacked = unpack_entries(
+        archive,
+        dest,
+        planned,
+        overwrite,
+        &mut response,
+        &mut changes,
+    );
+    // Files written before an error are recorded too, so they can still be undone
+    let written = changes.len();
+    history::record("UnpackArchive", changes);
+    if let Err(e) = unpacked {
+        return Err(format!(
+            "{} ({} files were unpacked before that, Undo reverts them)",
+            e, written
+        )
+        .into());
+    }
+    Ok(response)
+}
+
+fn unpack_entries(
+    archive: &[u8],
+    dest: &Path,
+    planned: Vec<PlannedEntry>,
+    overwrite: OverwritePolicy,
+    response: &mut UnpackArchiveResponse,
+    changes: &mut Vec<FileChange>,
+) -> Result<(), Box<dyn Error>> {
+    let mut archive = Archive::new(GzDecoder::new(Cursor::new(archive)));
+    for (entry, planned) in archive.entries()?.zip(planned) {
+        let mut entry = entry?;
+        let path = dest.join(&planned.relative);
+        match planned.kind {
+            EntryKind::Metadata => continue,
+            EntryKind::Directory => {
+                fs::create_dir_all(&path)?;
+                continue;
+            }
+            EntryKind::File => {}
+        }
+        if path.exists() && overwrite == OverwritePolicy::Skip {
+            response.skipped.push(path.to_string_lossy().to_string());
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        let before = history::snapshot(&path);
+        // Keep executable bits from the archive, nothing more exotic
+        let mode = entry.header().mode()? & 0o777;
+        let written = write_atomic(&path, &content)
+            .and_then(|_| fs::set_permissions(&path, fs::Permissions::from_mode(mode | 0o600)));
+        changes.push(FileChange::new(&path, before));
+        written?;
+        response.files.push(path.to_string_lossy().to_string());
+    }
+    Ok(())
+}
+
+impl PackArchiveRequest {
+    pub async fn process(self) -> Result<PackArchiveResponse, Box<dyn Error>> {
+        let path = ensure_relative(PathBuf::from(self.path)).await?;
+        ensure_not_symlinked(&path)?;
+        if !path.is_dir() {
+            return Err(format!("{:?} is not a directory", path).into());
+        }
+        let max_bytes = self.max_bytes;
+        tokio::task::spawn_blocking(move || pack(&path, max_bytes).map_err(|e| e.to_string()))
+            .await?
+            .map_err(|e| e.into())
+    }
+}
+
+impl UnpackArchiveRequest {
+    pub async fn process(self) -> Result<UnpackArchiveResponse, Box<dyn Error>> {
+        let dest = ensure_relative(PathBuf::from(self.dest_path)).await?;
+        // Entries are checked for symlinks below the destination, this covers the rest
+        ensure_not_symlinked(&dest)?;
+        let archive = STANDARD.decode(self.archive.trim())?;
+        if archive.len() as u64 > self.max_bytes {
+            return Err(format!(
+                "Archive is larger than {} bytes, raise max_bytes to unpack it",
+                self.max_bytes
+            )
+            .into());
+        }
+        let (overwrite, max_bytes, max_entries) =
+            (self.overwrite, self.max_bytes, self.max_entries);
+        tokio::task::spawn_blocking(move || {
+            fs::create_dir_all(&dest).map_err(|e| e.to_string())?;
+            unpack(&archive, &dest, overwrite, max_bytes, max_entries).map_err(|e| e.to_string())
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        history::clear();
+        dir
+    }
+
+    // Build a tar.gz by hand, for entries PackArchive would never produce
+    fn raw_archive(name: &str, entry_type: EntryType, content: &[u8]) -> String {
+        raw_archive_entries(&[(name, entry_type, content)])
+    }
+
+    fn raw_archive_entries(entries: &[(&str, EntryType, &[u8])]) -> String {
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (name, entry_type, content) in entries {
+            let mut header = tar::Header::new_ustar();
+            header.set_entry_type(*entry_type);
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            // set_path refuses `..`, so write the name bytes directly
+            header.as_ustar_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_cksum();
+            builder.append(&header, *content).unwrap();
+        }
+        let mut encoder = builder.into_inner().unwrap();
+        encoder.flush().unwrap();
+        STANDARD.encode(encoder.finish().unwrap())
+    }
+
+    fn unpack_request(archive: String, dest_path: &str) -> UnpackArchiveRequest {
+        UnpackArchiveRequest {
+            archive,
+            dest_path: dest_path.to_string(),
+            overwrite: OverwritePolicy::Error,
+            max_bytes: default_max_bytes(),
+            max_entries: default_max_entries(),
+        }
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_pack_unpack_round_trip(_tmp_dir: TempDir) {
+        fs::create_dir_all("project/src").unwrap();
+        fs::create_dir_all("project/.git").unwrap();
+        fs::create_dir_all("project/target").unwrap();
+        fs::write("project/.gitignore", "target/\n").unwrap();
+        fs::write("project/src/main.rs", "fn main() {}\n").unwrap();
+        fs::write("project/.git/HEAD", "ref: refs/heads/main\n").unwrap();
+        fs::write("project/target/out", "build output").unwrap();
+        let request = PackArchiveRequest {
+            path: "project".to_string(),
+            max_bytes: default_max_bytes(),
+        };
+        let packed = request.process().await.unwrap();
+        assert_eq!(packed.files, 2);
+
+        let response = unpack_request(packed.archive, "copy")
+            .process()
+            .await
+            .unwrap();
+        assert_eq!(response.files.len(), 2);
+        assert_eq!(
+            fs::read_to_string("copy/src/main.rs").unwrap(),
+            "fn main() {}\n"
+        );
+        assert!(Path::new("copy/.gitignore").exists());
+        assert!(!Path::new("copy/.git").exists());
+        assert!(!Path::new("copy/target").exists());
+    }
+
+    #[rstest::rstest]
+    #[case("../escape.txt", EntryType::Regular, "escapes the destination")]
+    #[case("/etc/escape.txt", EntryType::Regular, "escapes the destination")]
+    #[case(".git/config", EntryType::Regular, "protected path")]
+    #[case("link", EntryType::Symlink, "only files and directories are allowed")]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_unpack_rejects_entries(
+        _tmp_dir: TempDir,
+        #[case] name: &str,
+        #[case] entry_type: EntryType,
+        #[case] error: &str,
+    ) {
+        let archive = raw_archive(name, entry_type, b"pwned");
+        let response = unpack_request(archive, "dest").process().await;
+        assert!(response.unwrap_err().to_string().contains(error));
+        assert!(!Path::new("escape.txt").exists());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_unpack_skips_pax_headers(_tmp_dir: TempDir) {
+        // What git archive starts with
+        let comment = b"52 comment=0123456789abcdef0123456789abcdef01234567\n";
+        let archive = raw_archive_entries(&[
+            ("pax_global_header", EntryType::XGlobalHeader, comment),
+            ("src/", EntryType::Directory, b""),
+            ("src/main.rs", EntryType::Regular, b"fn main() {}\n"),
+        ]);
+        let response = unpack_request(archive, ".").process().await.unwrap();
+        assert_eq!(response.files, vec!["./src/main.rs"]);
+        assert!(!Path::new("pax_global_header").exists());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_unpack_partial_failure_is_recorded(_tmp_dir: TempDir) {
+        // `sub` is a file, so sub/b.txt can't be written once a.txt already has been
+        fs::write("sub", "not a directory").unwrap();
+        let archive = raw_archive_entries(&[
+            ("a.txt", EntryType::Regular, b"a"),
+            ("sub/b.txt", EntryType::Regular, b"b"),
+        ]);
+        let error = unpack_request(archive, ".").process().await.unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("1 files were unpacked before that"));
+        assert_eq!(history::entries().len(), 1);
+        history::undo(1).unwrap();
+        assert!(!Path::new("a.txt").exists());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_unpack_limits(_tmp_dir: TempDir) {
+        let archive = raw_archive("big.txt", EntryType::Regular, &[b'a'; 1000]);
+        let mut request = unpack_request(archive, ".");
+        request.max_bytes = 500;
+        let response = request.process().await;
+        assert!(response
+            .unwrap_err()
+            .to_string()
+            .contains("raise max_bytes"));
+        assert!(!Path::new("big.txt").exists());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_unpack_through_symlink(_tmp_dir: TempDir) {
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), "link").unwrap();
+        let archive = raw_archive("link/file.txt", EntryType::Regular, b"pwned");
+        let response = unpack_request(archive, ".").process().await;
+        assert!(response.unwrap_err().to_string().contains("symlink"));
+        assert!(!outside.path().join("file.txt").exists());
+
+        // The destination itself, or a parent of it, being the symlink
+        for dest in ["link", "link/sub"] {
+            let archive = raw_archive("file.txt", EntryType::Regular, b"pwned");
+            let response = unpack_request(archive, dest).process().await;
+            assert!(response.unwrap_err().to_string().contains("symlink"));
+        }
+        assert!(!outside.path().join("file.txt").exists());
+        assert!(!outside.path().join("sub").exists());
+
+        let request = PackArchiveRequest {
+            path: "link".to_string(),
+            max_bytes: default_max_bytes(),
+        };
+        assert!(request.process().await.is_err());
+        let archive = raw_archive("file.txt", EntryType::Regular, b"pwned");
+        assert!(unpack_request(archive, "..").process().await.is_err());
+    }
+}
@@ -0,0 +1,447 @@
+//! Snapshots of the workspace that don't depend on git, for workspaces that aren't repositories.
+//!
+//! File contents go into a content-addressed object store under the Agent directory, so files
+//! that didn't change between checkpoints are only stored once. Each checkpoint is a manifest
+//! mapping paths to object hashes and modes. Hidden files are included, but anything
+//! .gitignore'd (build output, virtualenvs) is left out, as are .git and the Agent directory.
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use ignore::WalkBuilder;
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+use crate::operations::fs::{
+    history::{self, FileChange},
+    utils::{content_hash, create_agent_dir, ensure_not_symlinked, write_atomic, AGENT_DIR},
+};
+
+fn objects_dir() -> PathBuf {
+    Path::new(AGENT_DIR).join("objects")
+}
+
+fn checkpoints_dir() -> PathBuf {
+    Path::new(AGENT_DIR).join("checkpoints")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestFile {
+    hash: String,
+    mode: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    id: String,
+    label: Option<String>,
+    timestamp: String,
+    files: BTreeMap<PathBuf, ManifestFile>,
+}
+
+impl Manifest {
+    fn load(id: &str) -> Result<Self, Box<dyn Error>> {
+        // Ids end up in a path, so only accept what checkpoint() generates
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(format!("Invalid checkpoint id {:?}", id).into());
+        }
+        let path = checkpoints_dir().join(format!("{}.json", id));
+        let content = fs::read(&path).map_err(|_| format!("No checkpoint with id {:?}", id))?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    fn info(&self) -> CheckpointInfo {
+        CheckpointInfo {
+            id: self.id.clone(),
+            label: self.label.clone(),
+            timestamp: self.timestamp.clone(),
+            files: self.files.len(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct CheckpointRequest {
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct CheckpointInfo {
+    pub id: String,
+    pub label: Option<String>,
+    // RFC 3339
+    pub timestamp: String,
+    pub files: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct CheckpointResponse {
+    pub checkpoint: CheckpointInfo,
+    // Files whose content wasn't already in the store from an earlier checkpoint
+    pub new_objects: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ListCheckpointsRequest {}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ListCheckpointsResponse {
+    // Oldest first
+    pub checkpoints: Vec<CheckpointInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RestoreRequest {
+    pub id: String,
+    // Only report what would change, without touching any files
+    #[oai(default)]
+    #[serde(default)]
+    pub preview: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Object)]
+pub struct RestoreResponse {
+    pub created: Vec<String>,
+    pub modified: Vec<String>,
+    pub deleted: Vec<String>,
+    pub applied: bool,
+}
+
+// Files in the workspace a checkpoint covers, relative to the workspace root
+fn workspace_files() -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let walker = WalkBuilder::new(".")
+        .hidden(false)
+        .require_git(false)
+        .filter_entry(|entry| entry.file_name() != ".git" && entry.file_name() != AGENT_DIR)
+        .build();
+    let mut files = Vec::new();
+    for entry in walker {
+        let entry = entry?;
+        if entry.file_type().is_some_and(|t| t.is_file()) {
+            files.push(entry.path().strip_prefix(".")?.to_path_buf());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn checkpoint(label: Option<String>) -> Result<CheckpointResponse, Box<dyn Error>> {
+    create_agent_dir("objects")?;
+    create_agent_dir("checkpoints")?;
+    let mut files = BTreeMap::new();
+    let mut new_objects = 0;
+    for path in workspace_files()? {
+        let content = fs::read(&path)?;
+        let hash = content_hash(&content);
+        let object = objects_dir().join(&hash);
+        if !object.exists() {
+            write_atomic(&object, &content)?;
+            new_objects += 1;
+        }
+        let mode = fs::metadata(&path)?.permissions().mode() & 0o777;
+        files.insert(path, ManifestFile { hash, mode });
+    }
+
+    let now = Utc::now();
+    let id = format!(
+        "{}-{}",
+        now.format("%Y%m%dT%H%M%S"),
+        &uuid::Uuid::new_v4().simple().to_string()[..8]
+    );
+    let manifest = Manifest {
+        id: id.clone(),
+        label,
+        timestamp: now.to_rfc3339(),
+        files,
+    };
+    let path = checkpoints_dir().join(format!("{}.json", id));
+    write_atomic(&path, &serde_json::to_vec_pretty(&manifest)?)?;
+    Ok(CheckpointResponse {
+        checkpoint: manifest.info(),
+        new_objects,
+    })
+}
+
+fn list_checkpoints() -> Result<Vec<CheckpointInfo>, Box<dyn Error>> {
+    let entries = match fs::read_dir(checkpoints_dir()) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(vec![]),
+    };
+    let mut checkpoints = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "json") {
+            let manifest: Manifest = serde_json::from_slice(&fs::read(&path)?)?;
+            checkpoints.push(manifest.info());
+        }
+    }
+    checkpoints.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then(a.id.cmp(&b.id)));
+    Ok(checkpoints)
+}
+
+fn restore(id: &str, preview: bool) -> Result<RestoreResponse, Box<dyn Error>> {
+    let manifest = Manifest::load(id)?;
+    let current = workspace_files()?;
+    let mut response = RestoreResponse::default();
+    let mut to_write = Vec::new();
+    for (path, file) in &manifest.files {
+        let object = objects_dir().join(&file.hash);
+        if !object.exists() {
+            return Err(format!("Checkpoint store is missing the content of {:?}", path).into());
+        }
+        match fs::read(path) {
+            Ok(content) if content_hash(&content) == file.hash => continue,
+            Ok(_) => response.modified.push(path.to_string_lossy().to_string()),
+            Err(_) => response.created.push(path.to_string_lossy().to_string()),
+        }
+        to_write.push((path, file, object));
+    }
+    let to_delete: Vec<&PathBuf> = current
+        .iter()
+        .filter(|path| !manifest.files.contains_key(*path))
+        .collect();
+    response.deleted = to_delete
+        .iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+    if preview {
+        return Ok(response);
+    }
+
+    // A symlinked directory anywhere on the way would put the write outside the workspace
+    for path in to_write
+        .iter()
+        .map(|(path, _, _)| *path)
+        .chain(to_delete.iter().copied())
+    {
+        ensure_not_symlinked(path).map_err(|e| format!("Refusing to restore: {}", e))?;
+    }
+    let mut changes = Vec::new();
+    let applied = apply_restore(&to_write, &to_delete, &mut changes);
+    // Whatever was done before an error is recorded, so it can still be undone
+    let restored = changes.len();
+    history::record("Restore", changes);
+    if let Err(e) = applied {
+        return Err(format!(
+            "{} ({} files were restored before that, Undo reverts them)",
+            e, restored
+        )
+        .into());
+    }
+    response.applied = true;
+    Ok(response)
+}
+
+fn apply_restore(
+    to_write: &[(&PathBuf, &ManifestFile, PathBuf)],
+    to_delete: &[&PathBuf],
+    changes: &mut Vec<FileChange>,
+) -> Result<(), Box<dyn Error>> {
+    for (path, file, object) in to_write {
+        let before = history::snapshot(path);
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let written = write_atomic(path, &fs::read(object)?)
+            .and_then(|_| fs::set_permissions(path, fs::Permissions::from_mode(file.mode)));
+        changes.push(FileChange::new(path, before));
+        written.map_err(|e| format!("Couldn't write {:?}: {}", path, e))?;
+    }
+    for path in to_delete {
+        let before = history::snapshot(path);
+        let removed = fs::remove_file(path);
+        changes.push(FileChange::new(path, before));
+        removed.map_err(|e| format!("Couldn't remove {:?}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+impl CheckpointRequest {
+    pub async fn process(self) -> Result<CheckpointResponse, Box<dyn Error>> {
+        tokio::task::spawn_blocking(move || checkpoint(self.label).map_err(|e| e.to_string()))
+            .await?
+            .map_err(|e| e.into())
+    }
+}
+
+impl ListCheckpointsRequest {
+    pub async fn process(self) -> Result<ListCheckpointsResponse, Box<dyn Error>> {
+        let checkpoints =
+            tokio::task::spawn_blocking(|| list_checkpoints().map_err(|e| e.to_string())).await??;
+        Ok(ListCheckpointsResponse { checkpoints })
+    }
+}
+
+impl RestoreRequest {
+    pub async fn process(self) -> Result<RestoreResponse, Box<dyn Error>> {
+        tokio::task::spawn_blocking(move || {
+            restore(&self.id, self.preview).map_err(|e| e.to_string())
+        })
+        .await?
+        .map_err(|e| e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        notifications,
+        operations::git::{
+            status::GitStatusRequest,
+            utils::{init_repo, run_git},
+        },
+    };
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        history::clear();
+        dir
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_checkpoint_dedup_and_list(_tmp_dir: TempDir) {
+        fs::create_dir("src").unwrap();
+        fs::write("src/main.rs", "fn main() {}\n").unwrap();
+        fs::write("README.md", "# Demo\n").unwrap();
+        fs::write(".gitignore", "target/\n").unwrap();
+        fs::create_dir("target").unwrap();
+        fs::write("target/out", "build output").unwrap();
+
+        let request = CheckpointRequest {
+            label: Some("before".to_string()),
+        };
+        let first = request.process().await.unwrap();
+        assert_eq!(first.checkpoint.files, 3);
+        assert_eq!(first.new_objects, 3);
+
+        fs::write("README.md", "# Demo, edited\n").unwrap();
+        let second = CheckpointRequest { label: None }.process().await.unwrap();
+        assert_eq!(second.new_objects, 1);
+
+        let list = ListCheckpointsRequest {}.process().await.unwrap();
+        let ids: Vec<&str> = list.checkpoints.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, vec![first.checkpoint.id, second.checkpoint.id]);
+        assert_eq!(list.checkpoints[0].label.as_deref(), Some("before"));
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_checkpoint_keeps_git_status_clean(_tmp_dir: TempDir) {
+        init_repo().await;
+        fs::write("a.txt", "a").unwrap();
+        run_git(&["add", "a.txt"]).await.unwrap();
+        run_git(&["commit", "-q", "-m", "init"]).await.unwrap();
+
+        CheckpointRequest { label: None }.process().await.unwrap();
+        let request = GitStatusRequest {
+            paths: vec![],
+            include_ignored: false,
+        };
+        assert!(request.process().await.unwrap().entries.is_empty());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_restore(_tmp_dir: TempDir) {
+        fs::write("keep.txt", "keep").unwrap();
+        fs::write("edit.txt", "original").unwrap();
+        fs::write("remove.txt", "remove").unwrap();
+        let id = CheckpointRequest { label: None }
+            .process()
+            .await
+            .unwrap()
+            .checkpoint
+            .id;
+
+        fs::write("edit.txt", "changed").unwrap();
+        fs::remove_file("remove.txt").unwrap();
+        fs::write("new.txt", "new").unwrap();
+
+        let request = RestoreRequest {
+            id: id.clone(),
+            preview: true,
+        };
+        let preview = request.process().await.unwrap();
+        assert_eq!(preview.created, vec!["remove.txt"]);
+        assert_eq!(preview.modified, vec!["edit.txt"]);
+        assert_eq!(preview.deleted, vec!["new.txt"]);
+        assert!(!preview.applied);
+        assert!(Path::new("new.txt").exists());
+
+        let request = RestoreRequest { id, preview: false };
+        assert!(request.process().await.unwrap().applied);
+        assert_eq!(fs::read_to_string("edit.txt").unwrap(), "original");
+        assert_eq!(fs::read_to_string("remove.txt").unwrap(), "remove");
+        assert!(!Path::new("new.txt").exists());
+        assert_eq!(history::entries().len(), 1);
+        // Restored files aren't reported back to the LLM as someone else's changes
+        assert!(notifications::is_known(
+            Path::new("remove.txt"),
+            Some(b"remove")
+        ));
+        assert!(notifications::is_known(Path::new("new.txt"), None));
+
+        let request = RestoreRequest {
+            id: "../../etc/passwd".to_string(),
+            preview: true,
+        };
+        assert!(request.process().await.is_err());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_restore_errors(_tmp_dir: TempDir) {
+        fs::write("a.txt", "a").unwrap();
+        fs::create_dir("sub").unwrap();
+        fs::write("sub/b.txt", "b").unwrap();
+        let id = CheckpointRequest { label: None }
+            .process()
+            .await
+            .unwrap()
+            .checkpoint
+            .id;
+        fs::write("a.txt", "changed").unwrap();
+
+        // The directory now points outside the workspace
+        let outside = tempfile::tempdir().unwrap();
+        fs::remove_dir_all("sub").unwrap();
+        std::os::unix::fs::symlink(outside.path(), "sub").unwrap();
+        let request = RestoreRequest {
+            id: id.clone(),
+            preview: false,
+        };
+        let error = request.process().await.unwrap_err();
+        assert!(error.to_string().starts_with("Refusing to restore"));
+        assert!(!outside.path().join("b.txt").exists());
+        assert_eq!(fs::read_to_string("a.txt").unwrap(), "changed");
+
+        // sub/b.txt can't be written, a.txt was already restored by then
+        fs::remove_file("sub").unwrap();
+        fs::write("sub", "not a directory").unwrap();
+        let request = RestoreRequest { id, preview: false };
+        let error = request.process().await.unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("1 files were restored before that"));
+        assert_eq!(fs::read_to_string("a.txt").unwrap(), "a");
+        assert_eq!(history::entries().len(), 1);
+        assert_eq!(history::entries()[0].paths(), vec!["a.txt".to_string()]);
+    }
+}
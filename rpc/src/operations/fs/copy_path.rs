@@ -0,0 +1,233 @@
+//! Copy a file, or a directory recursively, to a new location inside the workspace.
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+use crate::operations::fs::{
+    history::{self, FileChange},
+    utils::{ensure_not_protected, ensure_relative},
+};
+
+// What to do when a file being copied already exists at the destination
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum OverwritePolicy {
+    #[default]
+    Error,
+    Skip,
+    Overwrite,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct CopyPathRequest {
+    pub src_path: String,
+    pub dest_path: String,
+    #[oai(default)]
+    #[serde(default)]
+    pub overwrite: OverwritePolicy,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct CopyPathResponse {
+    pub copied: usize,
+    // Destination files left alone because of the `skip` overwrite policy
+    pub skipped: Vec<String>,
+}
+
+fn copy_recursive(
+    src: &Path,
+    dest: &Path,
+    overwrite: OverwritePolicy,
+    response: &mut CopyPathResponse,
+    changes: &mut Vec<FileChange>,
+) -> Result<(), Box<dyn Error>> {
+    let file_type = fs::symlink_metadata(src)?.file_type();
+    if file_type.is_dir() {
+        fs::create_dir_all(dest)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_recursive(
+                &entry.path(),
+                &dest.join(entry.file_name()),
+                overwrite,
+                response,
+                changes,
+            )?;
+        }
+        return Ok(());
+    }
+
+    let before = history::snapshot(dest);
+    if fs::symlink_metadata(dest).is_ok() {
+        match overwrite {
+            OverwritePolicy::Error => {
+                return Err(format!("Destination {:?} already exists", dest).into());
+            }
+            OverwritePolicy::Skip => {
+                response.skipped.push(dest.to_string_lossy().to_string());
+                return Ok(());
+            }
+            OverwritePolicy::Overwrite => fs::remove_file(dest)?,
+        }
+    }
+    if file_type.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(src)?, dest)?;
+    } else {
+        fs::copy(src, dest)?;
+        changes.push(FileChange::new(dest, before));
+    }
+    response.copied += 1;
+    Ok(())
+}
+
+impl CopyPathRequest {
+    pub async fn process(self) -> Result<CopyPathResponse, Box<dyn Error>> {
+        let src_path = ensure_relative(PathBuf::from(self.src_path)).await?;
+        let dest_path = ensure_relative(PathBuf::from(self.dest_path)).await?;
+        ensure_not_protected(&src_path)?;
+        ensure_not_protected(&dest_path)?;
+        if dest_path.starts_with(&src_path) {
+            return Err("Can't copy a directory into itself".into());
+        }
+
+        let overwrite = self.overwrite;
+        let (result, changes) = tokio::task::spawn_blocking(move || {
+            let mut response = CopyPathResponse {
+                copied: 0,
+                skipped: Vec::new(),
+            };
+            let mut changes = Vec::new();
+            let result = copy_recursive(
+                &src_path,
+                &dest_path,
+                overwrite,
+                &mut response,
+                &mut changes,
+            )
+            .map(|_| response)
+            .map_err(|e| e.to_string());
+            (result, changes)
+        })
+        .await?;
+        // Files copied before a failure are recorded too, so they can still be undone
+        history::record("CopyPath", changes);
+        result.map_err(|e| e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        dir
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_copy_file(_tmp_dir: TempDir) {
+        fs::write("src.txt", "Hello, world!").unwrap();
+        let request = CopyPathRequest {
+            src_path: "src.txt".to_string(),
+            dest_path: "dest.txt".to_string(),
+            overwrite: OverwritePolicy::Error,
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.copied, 1);
+        assert_eq!(fs::read_to_string("dest.txt").unwrap(), "Hello, world!");
+        assert!(Path::new("src.txt").exists());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_copy_directory_recursive(_tmp_dir: TempDir) {
+        fs::create_dir_all("src/nested").unwrap();
+        fs::write("src/a.txt", "a").unwrap();
+        fs::write("src/nested/b.txt", "b").unwrap();
+        let request = CopyPathRequest {
+            src_path: "src".to_string(),
+            dest_path: "dest".to_string(),
+            overwrite: OverwritePolicy::Error,
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.copied, 2);
+        assert_eq!(fs::read_to_string("dest/nested/b.txt").unwrap(), "b");
+    }
+
+    #[rstest::rstest]
+    #[case(OverwritePolicy::Error, "old", 0)]
+    #[case(OverwritePolicy::Skip, "old", 0)]
+    #[case(OverwritePolicy::Overwrite, "new", 1)]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_overwrite_policy(
+        _tmp_dir: TempDir,
+        #[case] overwrite: OverwritePolicy,
+        #[case] expected: &str,
+        #[case] copied: usize,
+    ) {
+        fs::write("src.txt", "new").unwrap();
+        fs::write("dest.txt", "old").unwrap();
+        let request = CopyPathRequest {
+            src_path: "src.txt".to_string(),
+            dest_path: "dest.txt".to_string(),
+            overwrite,
+        };
+        let response = request.process().await;
+        match overwrite {
+            OverwritePolicy::Error => assert!(response.is_err()),
+            _ => assert_eq!(response.unwrap().copied, copied),
+        }
+        assert_eq!(fs::read_to_string("dest.txt").unwrap(), expected);
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_copy_protected_path(_tmp_dir: TempDir) {
+        fs::create_dir(".git").unwrap();
+        let request = CopyPathRequest {
+            src_path: ".git".to_string(),
+            dest_path: "git_backup".to_string(),
+            overwrite: OverwritePolicy::Error,
+        };
+        let response = request.process().await;
+        assert!(response.is_err());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_undo_copy(_tmp_dir: TempDir) {
+        history::clear();
+        fs::create_dir("src").unwrap();
+        fs::write("src/a.txt", "new").unwrap();
+        fs::write("src/b.txt", "b").unwrap();
+        fs::create_dir("dest").unwrap();
+        fs::write("dest/a.txt", "old").unwrap();
+        let request = CopyPathRequest {
+            src_path: "src".to_string(),
+            dest_path: "dest".to_string(),
+            overwrite: OverwritePolicy::Overwrite,
+        };
+        request.process().await.unwrap();
+        assert_eq!(fs::read_to_string("dest/a.txt").unwrap(), "new");
+
+        history::undo(1).unwrap();
+        assert_eq!(fs::read_to_string("dest/a.txt").unwrap(), "old");
+        assert!(!Path::new("dest/b.txt").exists());
+    }
+}
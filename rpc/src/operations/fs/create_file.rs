@@ -1,26 +1,41 @@
-use std::{error::Error, fs::File, io::Write, path::PathBuf};
+use std::{error::Error, path::PathBuf};
 
-use crate::operations::fs::utils::ensure_relative;
 use poem_openapi::Object;
 use serde::{Deserialize, Serialize};
 
+use crate::operations::fs::{
+    history::{self, FileChange},
+    utils::{check_hash, content_hash, ensure_relative, write_atomic},
+};
+
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct CreateFileRequest {
     pub path: String,
     pub content: String,
+    // When overwriting an existing file, the hash from the last read / edit of it
+    pub expected_hash: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct CreateFileResponse {
     pub success: bool,
+    pub hash: String,
 }
 
 impl CreateFileRequest {
     pub async fn process(self) -> Result<CreateFileResponse, Box<dyn Error>> {
         let path = ensure_relative(PathBuf::from(self.path)).await?;
-        let mut file = File::create(path)?;
-        file.write_all(self.content.as_bytes())?;
-        Ok(CreateFileResponse { success: true })
+        check_hash(&path, self.expected_hash.as_deref()).await?;
+        let before = history::snapshot(&path);
+        let content = self.content.into_bytes();
+        let hash = content_hash(&content);
+        let tmp_path = path.clone();
+        tokio::task::spawn_blocking(move || write_atomic(&tmp_path, &content)).await??;
+        history::record("CreateFile", vec![FileChange::new(&path, before)]);
+        Ok(CreateFileResponse {
+            success: true,
+            hash,
+        })
     }
 }
 
@@ -47,6 +62,7 @@ mod tests {
         let request = CreateFileRequest {
             path: file_path.to_str().unwrap().to_string(),
             content: "Hello, world!".to_string(),
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
         assert!(response.success);
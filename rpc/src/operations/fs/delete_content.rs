@@ -6,25 +6,41 @@ use std::{error::Error, path::PathBuf};
 use poem_openapi::Object;
 use serde::{Deserialize, Serialize};
 
-use crate::operations::fs::utils::{ensure_relative, read_lines};
+use crate::operations::fs::{
+    history::{self, FileChange},
+    utils::{check_hash, ensure_relative, read_lines, write_text, WrittenText},
+};
 
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct DeleteContentRequest {
     pub path: String,
     pub start_line: usize,
     pub end_line: Option<usize>,
+    // Hash from the last read / edit, the edit is rejected if the file has changed since
+    pub expected_hash: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct DeleteContentResponse {
     pub content: String,
+    pub hash: String,
 }
 
 impl DeleteContentRequest {
     pub async fn process(self) -> Result<DeleteContentResponse, Box<dyn Error>> {
-        let path = ensure_relative(PathBuf::from(self.path)).await?;
-        let mut lines = read_lines(&path).await?;
+        let path = ensure_relative(PathBuf::from(&self.path)).await?;
+        check_hash(&path, self.expected_hash.as_deref()).await?;
+        let before = history::snapshot(&path);
+        let (lines, format) = read_lines(&path).await?;
+        let lines = self.edit(lines)?;
+        let WrittenText { content, hash } = write_text(&path, &lines.join("\n"), &format).await?;
+        history::record("DeleteContent", vec![FileChange::new(&path, before)]);
+
+        Ok(DeleteContentResponse { content, hash })
+    }
 
+    // Apply the delete to a file's lines without touching disk
+    pub fn edit(&self, mut lines: Vec<String>) -> Result<Vec<String>, Box<dyn Error>> {
         // First sanity check the start line
         let start_line = match self.start_line {
             0 => 0,
@@ -43,10 +59,7 @@ impl DeleteContentRequest {
         };
 
         lines.drain(start_line..=end_line);
-        let content = lines.join("\n");
-        tokio::fs::write(path, &content).await?;
-
-        Ok(DeleteContentResponse { content })
+        Ok(lines)
     }
 }
 
@@ -75,6 +88,7 @@ mod tests {
             path: "test.txt".to_string(),
             start_line: 2,
             end_line: None,
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
         assert_eq!(response.content, "line1\nline3\n");
@@ -93,6 +107,7 @@ mod tests {
             path: "test.txt".to_string(),
             start_line: 2,
             end_line: Some(3),
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
         assert_eq!(response.content, "line1\n");
@@ -108,6 +123,7 @@ mod tests {
             path: "test.txt".to_string(),
             start_line: 5,
             end_line: None,
+            expected_hash: None,
         };
         let response = request.process().await;
         assert!(response.is_err());
@@ -127,6 +143,7 @@ mod tests {
             path: "test.txt".to_string(),
             start_line: 0,
             end_line: None,
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
         assert_eq!(response.content, "line2\nline3\n");
@@ -142,6 +159,7 @@ mod tests {
             path: "test.txt".to_string(),
             start_line: 3,
             end_line: None,
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
         assert_eq!(response.content, "line1\nline2");
@@ -157,8 +175,29 @@ mod tests {
             path: "test.txt".to_string(),
             start_line: 2,
             end_line: Some(5),
+            expected_hash: None,
+        };
+        let response = request.process().await.unwrap();
+        // The original file's final newline is kept
+        assert_eq!(response.content, "line1\n");
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_delete_preserves_crlf_and_bom(_tmp_dir: TempDir) {
+        std::fs::write("test.txt", b"\xEF\xBB\xBFline1\r\nline2\r\nline3").unwrap();
+        let request = DeleteContentRequest {
+            path: "test.txt".to_string(),
+            start_line: 2,
+            end_line: None,
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
-        assert_eq!(response.content, "line1");
+        assert_eq!(response.content, "line1\nline3");
+        assert_eq!(
+            std::fs::read("test.txt").unwrap(),
+            b"\xEF\xBB\xBFline1\r\nline3"
+        );
     }
 }
@@ -1,44 +1,53 @@
-use crate::operations::fs::utils::ensure_relative;
+use std::{error::Error, path::PathBuf};
+
 use llm_diff::FileDiff;
 use poem_openapi::Object;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::BufRead;
-use std::io::Write;
-use std::{error::Error, io::BufReader, path::PathBuf};
+
+use crate::operations::fs::{
+    history::{self, FileChange},
+    utils::{check_hash, ensure_relative, read_text, write_text, WrittenText},
+};
 
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct DiffRequest {
     pub commit_msg: String,
     pub path: String,
     pub diff_str: String,
+    // Hash from the last read / edit, the edit is rejected if the file has changed since
+    pub expected_hash: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct DiffResponse {
     pub new_content: String,
+    pub hash: String,
 }
 
 impl DiffRequest {
     pub async fn process(self) -> Result<DiffResponse, Box<dyn Error>> {
         println!("Processing diff request: {:?}", self);
-        let path = ensure_relative(PathBuf::from(self.path)).await?;
-        let reader = BufReader::new(File::open(&path)?);
-        let lines: Vec<String> = reader
-            .lines()
-            .map(|l| l.expect("could not parse line"))
-            .collect();
-
-        let diff = FileDiff::parse(&self.diff_str)?;
+        let path = ensure_relative(PathBuf::from(&self.path)).await?;
+        check_hash(&path, self.expected_hash.as_deref()).await?;
+        let before = history::snapshot(&path);
+        let (content, format) = read_text(&path).await?;
+        let lines: Vec<String> = content.lines().map(|l| l.to_string()).collect();
 
-        let applied = diff.apply(&lines)?;
+        let applied = self.edit(&lines)?;
         // Write the result to disk
-        let mut writer = File::create(&path)?;
-        write!(writer, "{}", applied.join("\n"))?;
+        let WrittenText {
+            content: new_content,
+            hash,
+        } = write_text(&path, &applied.join("\n"), &format).await?;
+        history::record("Diff", vec![FileChange::new(&path, before)]);
 
-        let new_content = std::fs::read_to_string(&path)?;
+        Ok(DiffResponse { new_content, hash })
+    }
 
-        Ok(DiffResponse { new_content })
+    // Apply the diff to a file's lines without touching disk
+    pub fn edit(&self, lines: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+        let diff = FileDiff::parse(&self.diff_str)?;
+        Ok(diff.apply(lines)?)
     }
 }
 
@@ -67,6 +76,7 @@ mod tests {
             path: "test.txt".to_string(),
             diff_str: "@@ -1,1 +1,5 @@\n foo\n-bar\n+qux\n baz".to_string(),
             commit_msg: "test".to_string(),
+            expected_hash: None,
         };
 
         let expected = "foo\nqux\nbaz";
@@ -77,4 +87,20 @@ mod tests {
         let content_on_disk = std::fs::read_to_string("test.txt").unwrap();
         assert_eq!(content_on_disk, expected);
     }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_patch_preserves_crlf(_tmp_dir: TempDir) {
+        std::fs::write("test.txt", b"foo\r\nbar\r\nbaz\r\n").unwrap();
+        let request = DiffRequest {
+            path: "test.txt".to_string(),
+            diff_str: "@@ -1,3 +1,3 @@\n foo\n-bar\n+qux\n baz".to_string(),
+            commit_msg: "test".to_string(),
+            expected_hash: None,
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.new_content, "foo\nqux\nbaz\n");
+        assert_eq!(std::fs::read("test.txt").unwrap(), b"foo\r\nqux\r\nbaz\r\n");
+    }
 }
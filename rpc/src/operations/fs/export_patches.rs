@@ -0,0 +1,470 @@
+//! Everything the Agent changed this session as a patch series, one patch per operation, for
+//! attaching to code review after an LLM session.
+//!
+//! Built from the history journal, so it covers the operations Undo / Redo know about and only
+//! the changes that are currently applied. Anything the journal missed (evicted entries, files
+//! changed by commands) is reported in `incomplete`. With git available it's a
+//! `git format-patch` style mbox that `git am` can apply (binary files included, as
+//! `GIT binary patch` literals), otherwise a plain multi-file unified diff.
+use std::{error::Error, io::Write};
+
+use flate2::{write::ZlibEncoder, Compression};
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use similar::TextDiff;
+
+use crate::operations::{
+    fs::history::{self, FileChange, FileState, Gaps, HistoryEntry},
+    git::utils::run_git,
+};
+
+// The characters git's base85 uses, in order
+const BASE85: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz!#$%&()*+-;<=>?@^_`{|}~";
+
+// Id of a missing blob in an index line
+const NULL_ID: &str = "0000000000000000000000000000000000000000";
+
+const DEFAULT_AUTHOR: &str = "Carabiner Agent <agent@carabiner.local>";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum PatchFormat {
+    // mbox if git is installed, unified otherwise
+    #[default]
+    Auto,
+    Mbox,
+    Unified,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ExportPatchesRequest {
+    #[oai(default)]
+    #[serde(default)]
+    pub format: PatchFormat,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct ExportPatchesResponse {
+    // The format actually used, never auto
+    pub format: PatchFormat,
+    pub patch: String,
+    pub operations: usize,
+    pub files: usize,
+    // Set when the patch doesn't cover everything that changed this session, saying what's missing
+    pub incomplete: Option<String>,
+}
+
+fn names(change: &FileChange) -> (String, String) {
+    let path = change.path.to_string_lossy();
+    match (&change.before, &change.after) {
+        (None, _) => ("/dev/null".to_string(), format!("b/{}", path)),
+        (_, None) => (format!("a/{}", path), "/dev/null".to_string()),
+        _ => (format!("a/{}", path), format!("b/{}", path)),
+    }
+}
+
+fn content(state: &Option<FileState>) -> &[u8] {
+    state
+        .as_ref()
+        .map(|s| s.content.as_slice())
+        .unwrap_or_default()
+}
+
+// Unified diff of the content, None if either side isn't text
+fn text_diff(change: &FileChange) -> Option<String> {
+    let (old_name, new_name) = names(change);
+    let before = std::str::from_utf8(content(&change.before)).ok()?;
+    let after = std::str::from_utf8(content(&change.after)).ok()?;
+    Some(
+        TextDiff::from_lines(before, after)
+            .unified_diff()
+            .header(&old_name, &new_name)
+            .to_string(),
+    )
+}
+
+fn file_diff(change: &FileChange) -> String {
+    text_diff(change).unwrap_or_else(|| {
+        let (old_name, new_name) = names(change);
+        format!("Binary files {} and {} differ\n", old_name, new_name)
+    })
+}
+
+// Mode as git writes it, which only tracks the executable bit
+fn git_mode(state: &FileState) -> &'static str {
+    match state.mode & 0o111 {
+        0 => "100644",
+        _ => "100755",
+    }
+}
+
+// Git's blob id, `git apply` needs the full ids to apply a binary patch
+fn blob_id(state: &Option<FileState>) -> String {
+    match state {
+        Some(state) => {
+            let mut hasher = Sha1::new();
+            hasher.update(format!("blob {}\0", state.content.len()));
+            hasher.update(&state.content);
+            format!("{:x}", hasher.finalize())
+        }
+        None => NULL_ID.to_string(),
+    }
+}
+
+// A `literal` hunk of a git binary patch: the zlib deflated content in base85, 52 bytes per line
+// with the line's length as its first character
+fn binary_literal(content: &[u8]) -> String {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    // Writing to a Vec can't fail
+    encoder.write_all(content).unwrap();
+    let deflated = encoder.finish().unwrap();
+    let mut literal = format!("literal {}\n", content.len());
+    for line in deflated.chunks(52) {
+        literal.push(match line.len() {
+            len @ 1..=26 => (b'A' + len as u8 - 1) as char,
+            len => (b'a' + len as u8 - 27) as char,
+        });
+        for group in line.chunks(4) {
+            let mut bytes = [0u8; 4];
+            bytes[..group.len()].copy_from_slice(group);
+            let mut value = u32::from_be_bytes(bytes);
+            let mut encoded = [0u8; 5];
+            for c in encoded.iter_mut().rev() {
+                *c = BASE85[(value % 85) as usize];
+                value /= 85;
+            }
+            literal.push_str(std::str::from_utf8(&encoded).unwrap());
+        }
+        literal.push('\n');
+    }
+    literal
+}
+
+fn git_diff(change: &FileChange) -> String {
+    let path = change.path.to_string_lossy();
+    let mut diff = format!("diff --git a/{} b/{}\n", path, path);
+    let index = format!(
+        "index {}..{}",
+        blob_id(&change.before),
+        blob_id(&change.after)
+    );
+    match (&change.before, &change.after) {
+        (None, Some(after)) => {
+            diff.push_str(&format!("new file mode {}\n{}\n", git_mode(after), index))
+        }
+        (Some(before), None) => diff.push_str(&format!(
+            "deleted file mode {}\n{}\n",
+            git_mode(before),
+            index
+        )),
+        (Some(before), Some(after)) if git_mode(before) != git_mode(after) => {
+            diff.push_str(&format!(
+                "old mode {}\nnew mode {}\n{}\n",
+                git_mode(before),
+                git_mode(after),
+                index
+            ))
+        }
+        (Some(before), Some(_)) => diff.push_str(&format!("{} {}\n", index, git_mode(before))),
+        (None, None) => {}
+    }
+    match text_diff(change) {
+        Some(text) => diff.push_str(&text),
+        None => {
+            // Forward then reverse, like git
+            diff.push_str(&format!(
+                "GIT binary patch\n{}\n{}\n",
+                binary_literal(content(&change.after)),
+                binary_literal(content(&change.before))
+            ));
+        }
+    }
+    diff
+}
+
+// First line of the description, or the operation if there's none
+fn title(entry: &HistoryEntry) -> &str {
+    let description = entry.description.as_deref().unwrap_or_default().trim();
+    match description.lines().next() {
+        Some(line) => line.trim(),
+        None => &entry.operation,
+    }
+}
+
+// The rest of the description after the title, if any
+fn body(entry: &HistoryEntry) -> Option<&str> {
+    let description = entry.description.as_deref()?.trim();
+    let (_, body) = description.split_once('\n')?;
+    Some(body.trim()).filter(|body| !body.is_empty())
+}
+
+// What the journal is missing, None if it has everything
+fn incomplete(gaps: &Gaps) -> Option<String> {
+    let mut missing = Vec::new();
+    if gaps.evicted > 0 {
+        missing.push(format!(
+            "{} older operations were evicted from the journal",
+            gaps.evicted
+        ));
+    }
+    if !gaps.unjournaled.is_empty() {
+        let runs: Vec<String> = gaps
+            .unjournaled
+            .iter()
+            .map(|(operation, count)| format!("{} {}", count, operation))
+            .collect();
+        missing.push(format!(
+            "files changed by {} aren't included",
+            runs.join(", ")
+        ));
+    }
+    match missing.is_empty() {
+        true => None,
+        false => Some(format!("Incomplete: {}", missing.join("; "))),
+    }
+}
+
+pub fn unified(entries: &[HistoryEntry], incomplete: Option<&str>) -> String {
+    let mut patch = String::new();
+    if let Some(incomplete) = incomplete {
+        patch.push_str(&format!("# {}\n\n", incomplete));
+    }
+    for (i, entry) in entries.iter().enumerate() {
+        patch.push_str(&format!(
+            "# Operation {}/{}: {}\n# Description: {}\n",
+            i + 1,
+            entries.len(),
+            entry.operation,
+            title(entry),
+        ));
+        for line in body(entry).into_iter().flat_map(|body| body.lines()) {
+            patch.push_str(&format!("#   {}\n", line));
+        }
+        patch.push_str(&format!("# Timestamp: {}\n", entry.timestamp.to_rfc3339()));
+        for change in &entry.changes {
+            patch.push_str(&file_diff(change));
+        }
+        patch.push('\n');
+    }
+    patch
+}
+
+pub fn mbox(entries: &[HistoryEntry], author: &str, incomplete: Option<&str>) -> String {
+    let mut patch = String::new();
+    for (i, entry) in entries.iter().enumerate() {
+        // Fixed hash and date in the From line, the same as git format-patch
+        patch.push_str("From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001\n");
+        patch.push_str(&format!("From: {}\n", author));
+        patch.push_str(&format!("Date: {}\n", entry.timestamp.to_rfc2822()));
+        patch.push_str(&format!(
+            "Subject: [PATCH {}/{}] {}\n\n",
+            i + 1,
+            entries.len(),
+            title(entry)
+        ));
+        if let Some(body) = body(entry) {
+            patch.push_str(&format!("{}\n\n", body));
+        }
+        patch.push_str(&format!(
+            "Operation: {}\nTimestamp: {}\n---\n",
+            entry.operation,
+            entry.timestamp.to_rfc3339()
+        ));
+        // Between the `---` and the diff, where git am ignores it
+        if let Some(incomplete) = incomplete.filter(|_| i == 0) {
+            patch.push_str(&format!("{}\n\n", incomplete));
+        }
+        for change in &entry.changes {
+            patch.push_str(&git_diff(change));
+        }
+        patch.push_str("-- \ncarabiner\n\n");
+    }
+    patch
+}
+
+// Author for the mbox headers, the repository's configured identity if there is one
+async fn git_author() -> Option<String> {
+    let name = run_git(&["config", "user.name"]).await.ok()?;
+    let email = run_git(&["config", "user.email"]).await.ok()?;
+    Some(format!("{} <{}>", name.trim(), email.trim()))
+}
+
+impl ExportPatchesRequest {
+    pub async fn process(self) -> Result<ExportPatchesResponse, Box<dyn Error>> {
+        let entries = history::entries();
+        let incomplete = incomplete(&history::gaps());
+        let format = match self.format {
+            PatchFormat::Auto => match run_git(&["--version"]).await {
+                Ok(_) => PatchFormat::Mbox,
+                Err(_) => PatchFormat::Unified,
+            },
+            format => format,
+        };
+        let patch = match format {
+            PatchFormat::Mbox => {
+                let author = git_author().await;
+                let author = author.as_deref().unwrap_or(DEFAULT_AUTHOR);
+                mbox(&entries, author, incomplete.as_deref())
+            }
+            _ => unified(&entries, incomplete.as_deref()),
+        };
+        let mut files: Vec<&std::path::PathBuf> = entries
+            .iter()
+            .flat_map(|e| e.changes.iter().map(|c| &c.path))
+            .collect();
+        files.sort();
+        files.dedup();
+        Ok(ExportPatchesResponse {
+            format,
+            patch,
+            operations: entries.len(),
+            files: files.len(),
+            incomplete,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::operations::git::utils::init_repo;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        history::clear();
+        dir
+    }
+
+    // Write a file the way a mutating operation would, recording it in the journal
+    fn write(path: &str, content: Option<&str>, description: &str) {
+        write_with(path, content.map(|c| c.as_bytes()), 0o644, description);
+    }
+
+    fn write_with(path: &str, content: Option<&[u8]>, mode: u32, description: &str) {
+        let path = Path::new(path);
+        let before = history::snapshot(path);
+        match content {
+            Some(content) => {
+                fs::write(path, content).unwrap();
+                fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+            }
+            None => fs::remove_file(path).unwrap(),
+        }
+        history::set_description(Some(description.to_string()));
+        history::record("Test", vec![FileChange::new(path, before)]);
+        history::set_description(None);
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_unified(_tmp_dir: TempDir) {
+        fs::write("a.txt", "one\ntwo\n").unwrap();
+        write("a.txt", Some("one\n2\n"), "Fix a.txt");
+        write("b.txt", Some("new\n"), "Create b.txt");
+
+        let request = ExportPatchesRequest {
+            format: PatchFormat::Unified,
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.operations, 2);
+        assert_eq!(response.files, 2);
+        assert_eq!(response.incomplete, None);
+        assert!(response
+            .patch
+            .starts_with("# Operation 1/2: Test\n# Description: Fix a.txt\n"));
+        assert!(response
+            .patch
+            .contains("--- a/a.txt\n+++ b/a.txt\n@@ -1,2 +1,2 @@\n one\n-two\n+2\n"));
+        assert!(response
+            .patch
+            .contains("--- /dev/null\n+++ b/b.txt\n@@ -0,0 +1 @@\n+new\n"));
+
+        history::record_unjournaled("RunCommand");
+        history::record_unjournaled("RunCommand");
+        history::record_unjournaled("ShellExec");
+        let request = ExportPatchesRequest {
+            format: PatchFormat::Unified,
+        };
+        let response = request.process().await.unwrap();
+        let incomplete = "Incomplete: files changed by 2 RunCommand, 1 ShellExec aren't included";
+        assert_eq!(response.incomplete.as_deref(), Some(incomplete));
+        assert!(response.patch.starts_with(&format!("# {}\n", incomplete)));
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_mbox_applies_with_git_am(_tmp_dir: TempDir) {
+        init_repo().await;
+        fs::write("a.txt", "one\ntwo\n").unwrap();
+        fs::write("old.txt", "old\n").unwrap();
+        run_git(&["add", "."]).await.unwrap();
+        run_git(&["commit", "-q", "-m", "init"]).await.unwrap();
+
+        write(
+            "a.txt",
+            Some("one\n2\n"),
+            "Fix a.txt\n\nThe second line was spelled out.",
+        );
+        write("b.txt", Some("new\n"), "Create b.txt");
+        write("old.txt", None, "Remove old.txt");
+        write("a.txt", Some("one\n2\nthree\n"), "Extend a.txt");
+        write_with(
+            "image.bin",
+            Some(&[0, 159, 146, 150, 255]),
+            0o644,
+            "Add image.bin",
+        );
+        write_with("image.bin", Some(&[0, 1, 2]), 0o644, "Edit image.bin");
+        write_with("run.sh", Some(b"echo hi\n"), 0o755, "Add run.sh");
+
+        let request = ExportPatchesRequest {
+            format: PatchFormat::Auto,
+        };
+        history::record_unjournaled("RunCommand");
+        let response = request.process().await.unwrap();
+        assert_eq!(response.format, PatchFormat::Mbox);
+        assert!(response
+            .patch
+            .contains("---\nIncomplete: files changed by 1 RunCommand"));
+        assert!(response.patch.contains(
+            "Subject: [PATCH 1/7] Fix a.txt\n\nThe second line was spelled out.\n\nOperation: Test\n"
+        ));
+        assert!(response.patch.contains("new file mode 100755\n"));
+        assert!(response.patch.contains("GIT binary patch\nliteral 5\n"));
+        assert!(response.patch.contains("From: Test <test@example.com>\n"));
+
+        run_git(&["stash", "--include-untracked", "--quiet"])
+            .await
+            .unwrap();
+        fs::write(".git/series.mbox", &response.patch).unwrap();
+        run_git(&["am", "--quiet", ".git/series.mbox"])
+            .await
+            .unwrap();
+        assert_eq!(fs::read_to_string("a.txt").unwrap(), "one\n2\nthree\n");
+        assert_eq!(fs::read_to_string("b.txt").unwrap(), "new\n");
+        assert!(!Path::new("old.txt").exists());
+        assert_eq!(fs::read("image.bin").unwrap(), vec![0, 1, 2]);
+        let mode = fs::metadata("run.sh").unwrap().permissions().mode();
+        assert_eq!(mode & 0o111, 0o111);
+        let log = run_git(&["log", "--format=%s"]).await.unwrap();
+        assert_eq!(
+            log,
+            "Add run.sh\nEdit image.bin\nAdd image.bin\nExtend a.txt\nRemove old.txt\nCreate b.txt\nFix a.txt\ninit\n"
+        );
+        let message = run_git(&["log", "-1", "--format=%b", "HEAD~6"])
+            .await
+            .unwrap();
+        assert!(message.starts_with("The second line was spelled out.\n"));
+    }
+}
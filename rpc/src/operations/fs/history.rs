@@ -0,0 +1,402 @@
+//! Journal of file mutations made by the Agent during this session, backing Undo / Redo.
+//!
+//! Mutating operations take a snapshot of each file before they touch it, then record the
+//! before / after content once they're done. Undo restores the before content, Redo the after
+//! content. Both refuse to run if a file was changed by something else in the meantime, so a
+//! human's edits are never silently overwritten.
+//!
+//! Each conversation has its own journal, so conversations sharing an Agent can't undo each
+//! other's edits. What a journal is missing, entries dropped to stay within the limits and
+//! commands that may have changed files behind its back, is counted so it can be reported.
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+
+use crate::{
+    notifications,
+    operations::fs::utils::{content_hash, write_atomic},
+};
+
+// Oldest entries are dropped after this, so a long session can't grow memory forever
+const MAX_ENTRIES: usize = 200;
+
+// File contents kept across all journals. Past this the oldest entries of the least recently
+// used conversations are dropped first.
+const MAX_BYTES: usize = 64 * 1024 * 1024;
+
+// Journals of the least recently used conversations are dropped after this
+const MAX_SESSIONS: usize = 16;
+
+lazy_static! {
+    // Most recently used last, keyed by conversation id
+    static ref HISTORIES: Mutex<Vec<(Option<String>, History)>> = Mutex::new(Vec::new());
+    // Conversation of the request being processed
+    static ref SESSION: Mutex<Option<String>> = Mutex::new(None);
+    // Description of the request being processed, attached to the entry it records
+    static ref DESCRIPTION: Mutex<Option<String>> = Mutex::new(None);
+    // Held while an Undo / Redo writes files, so two of them can't interleave. HISTORIES itself
+    // is never held during IO.
+    static ref STEPPING: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileState {
+    pub content: Vec<u8>,
+    // Permission bits, e.g. 0o755
+    pub mode: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileChange {
+    pub path: PathBuf,
+    // None means the file didn't exist
+    pub before: Option<FileState>,
+    pub after: Option<FileState>,
+}
+
+impl FileChange {
+    // Pair a snapshot taken before the mutation with the file's current state
+    pub fn new(path: &Path, before: Option<FileState>) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            before,
+            after: snapshot(path),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub operation: String,
+    // What the request said it was doing, e.g. a Diff's commit message
+    pub description: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    pub changes: Vec<FileChange>,
+}
+
+impl HistoryEntry {
+    // Bytes of file content the entry keeps in memory
+    fn size(&self) -> usize {
+        self.changes
+            .iter()
+            .map(|c| {
+                content(&c.before).map_or(0, <[u8]>::len) + content(&c.after).map_or(0, <[u8]>::len)
+            })
+            .sum()
+    }
+
+    pub fn paths(&self) -> Vec<String> {
+        self.changes
+            .iter()
+            .map(|c| c.path.to_string_lossy().to_string())
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct History {
+    undo: Vec<HistoryEntry>,
+    redo: Vec<HistoryEntry>,
+    // Entries dropped from the front of `undo` to stay within the limits
+    evicted: usize,
+    // Runs of operations that may have changed files without journaling them, by operation
+    unjournaled: BTreeMap<String, usize>,
+}
+
+// What the current conversation's journal doesn't cover
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Gaps {
+    pub evicted: usize,
+    pub unjournaled: BTreeMap<String, usize>,
+}
+
+// Current content and mode of a file, or None if it doesn't exist (or isn't a regular file)
+pub fn snapshot(path: &Path) -> Option<FileState> {
+    let content = fs::read(path).ok()?;
+    let mode = fs::metadata(path).ok()?.permissions().mode() & 0o7777;
+    Some(FileState { content, mode })
+}
+
+fn content(state: &Option<FileState>) -> Option<&[u8]> {
+    state.as_ref().map(|s| s.content.as_slice())
+}
+
+// Run `f` on the current conversation's journal, creating it if needed
+fn with_history<T>(f: impl FnOnce(&mut History) -> T) -> T {
+    let session = SESSION.lock().unwrap().clone();
+    with_session_history(session, f)
+}
+
+fn with_session_history<T>(session: Option<String>, f: impl FnOnce(&mut History) -> T) -> T {
+    let mut histories = HISTORIES.lock().unwrap();
+    let history = match histories.iter().position(|(id, _)| *id == session) {
+        Some(index) => histories.remove(index).1,
+        None => History::default(),
+    };
+    histories.push((session, history));
+    if histories.len() > MAX_SESSIONS {
+        histories.remove(0);
+    }
+    f(&mut histories.last_mut().unwrap().1)
+}
+
+// Set before processing each request, to the conversation it's part of
+pub fn set_session(conversation_id: Option<&str>) {
+    *SESSION.lock().unwrap() = conversation_id.map(|id| id.to_string());
+}
+
+pub fn record(operation: &str, changes: Vec<FileChange>) {
+    // The LLM made these changes, so they shouldn't come back to it as file change notifications
+    for change in &changes {
+        notifications::remember(&change.path, content(&change.after));
+    }
+    let changes: Vec<FileChange> = changes
+        .into_iter()
+        .filter(|c| c.before != c.after)
+        .collect();
+    if changes.is_empty() {
+        return;
+    }
+    let entry = HistoryEntry {
+        operation: operation.to_string(),
+        description: DESCRIPTION.lock().unwrap().clone(),
+        timestamp: Utc::now(),
+        changes,
+    };
+    with_history(|history| {
+        history.undo.push(entry);
+        if history.undo.len() > MAX_ENTRIES {
+            history.undo.remove(0);
+            history.evicted += 1;
+        }
+        // A new edit invalidates anything that was undone before it
+        history.redo.clear();
+    });
+    evict_to_max_bytes();
+}
+
+// Drop entries until all journals fit in MAX_BYTES, starting with the oldest entries of the
+// least recently used conversation. Redo entries go before undo entries of the same
+// conversation, since they're the least likely to be used.
+fn evict_to_max_bytes() {
+    let mut histories = HISTORIES.lock().unwrap();
+    let mut total: usize = histories
+        .iter()
+        .flat_map(|(_, h)| h.undo.iter().chain(h.redo.iter()))
+        .map(HistoryEntry::size)
+        .sum();
+    for (_, history) in histories.iter_mut() {
+        while total > MAX_BYTES && !history.redo.is_empty() {
+            total -= history.redo.remove(0).size();
+        }
+        while total > MAX_BYTES && !history.undo.is_empty() {
+            total -= history.undo.remove(0).size();
+            history.evicted += 1;
+        }
+    }
+}
+
+// Set before processing each request, since the operations themselves don't know how the
+// request describes them
+pub fn set_description(description: Option<String>) {
+    *DESCRIPTION.lock().unwrap() = description;
+}
+
+// Note an operation that can change files the journal doesn't see, e.g. a command the LLM ran
+pub fn record_unjournaled(operation: &str) {
+    with_history(|history| {
+        *history
+            .unjournaled
+            .entry(operation.to_string())
+            .or_default() += 1
+    });
+}
+
+// Entries that are currently applied, oldest first
+pub fn entries() -> Vec<HistoryEntry> {
+    with_history(|history| history.undo.clone())
+}
+
+pub fn gaps() -> Gaps {
+    with_history(|history| Gaps {
+        evicted: history.evicted,
+        unjournaled: history.unjournaled.clone(),
+    })
+}
+
+// Forget every conversation's journal
+pub fn clear() {
+    HISTORIES.lock().unwrap().clear();
+}
+
+fn describe(state: &Option<FileState>) -> String {
+    match content(state) {
+        Some(content) => content_hash(content),
+        None => "missing".to_string(),
+    }
+}
+
+fn write_state(path: &Path, state: &Option<FileState>) -> Result<(), Box<dyn Error>> {
+    match state {
+        Some(state) => {
+            if let Some(parent) = path.parent() {
+                if !parent.as_os_str().is_empty() {
+                    fs::create_dir_all(parent)?;
+                }
+            }
+            write_atomic(path, &state.content)?;
+            fs::set_permissions(path, fs::Permissions::from_mode(state.mode))?;
+        }
+        None => fs::remove_file(path)?,
+    }
+    Ok(())
+}
+
+// Move every file in the entry from one state to the other. All files are checked before any
+// are written, so a conflict leaves the workspace untouched, and the files already written are
+// put back if a write fails.
+fn apply(entry: &HistoryEntry, reverse: bool) -> Result<(), Box<dyn Error>> {
+    let pick = |change: &FileChange| match reverse {
+        true => (change.after.clone(), change.before.clone()),
+        false => (change.before.clone(), change.after.clone()),
+    };
+    for change in &entry.changes {
+        let (expected, _) = pick(change);
+        let current = snapshot(&change.path);
+        // Only the content counts, a changed mode is restored along with it
+        if content(&current) != content(&expected) {
+            return Err(format!(
+                "Conflict: {:?} was modified outside of {} (expected {}, found {})",
+                change.path,
+                entry.operation,
+                describe(&expected),
+                describe(&current)
+            )
+            .into());
+        }
+    }
+    // Restore in reverse order when undoing, e.g. so a move's destination is removed before
+    // its source is recreated
+    let changes: Vec<&FileChange> = match reverse {
+        true => entry.changes.iter().rev().collect(),
+        false => entry.changes.iter().collect(),
+    };
+    for (i, change) in changes.iter().enumerate() {
+        let (_, target) = pick(change);
+        if let Err(e) = write_state(&change.path, &target) {
+            let mut error = format!("Couldn't write {:?}: {}", change.path, e);
+            for written in changes[..i].iter().rev() {
+                let (original, _) = pick(written);
+                match write_state(&written.path, &original) {
+                    Ok(()) => notifications::remember(&written.path, content(&original)),
+                    Err(e) => error.push_str(&format!(
+                        ", and couldn't put back {:?}: {}",
+                        written.path, e
+                    )),
+                }
+            }
+            return Err(error.into());
+        }
+        notifications::remember(&change.path, content(&target));
+    }
+    Ok(())
+}
+
+fn stacks(
+    history: &mut History,
+    reverse: bool,
+) -> (&mut Vec<HistoryEntry>, &mut Vec<HistoryEntry>) {
+    match reverse {
+        true => (&mut history.undo, &mut history.redo),
+        false => (&mut history.redo, &mut history.undo),
+    }
+}
+
+// Apply up to `steps` entries from one stack, moving each to the other. Stops at the first
+// entry that can't be applied, returning the ones that were along with the error. Each entry
+// is taken off its stack while its files are written, so the journals stay usable meanwhile.
+fn step(
+    steps: usize,
+    reverse: bool,
+) -> Result<(Vec<HistoryEntry>, Option<String>), Box<dyn Error>> {
+    let _stepping = STEPPING.lock().unwrap();
+    let session = SESSION.lock().unwrap().clone();
+    let mut applied = Vec::new();
+    for _ in 0..steps {
+        let entry =
+            with_session_history(session.clone(), |history| stacks(history, reverse).0.pop());
+        let entry = match entry {
+            Some(entry) => entry,
+            None => break,
+        };
+        let result = apply(&entry, reverse);
+        let failed = result.is_err();
+        if !failed {
+            applied.push(entry.clone());
+        }
+        with_session_history(session.clone(), |history| {
+            let (from, to) = stacks(history, reverse);
+            match failed {
+                true => from.push(entry),
+                false => to.push(entry),
+            }
+        });
+        if let Err(e) = result {
+            // Nothing was applied, so there's no partial result to return
+            if applied.is_empty() {
+                return Err(e);
+            }
+            return Ok((applied, Some(e.to_string())));
+        }
+    }
+    Ok((applied, None))
+}
+
+pub fn undo(steps: usize) -> Result<(Vec<HistoryEntry>, Option<String>), Box<dyn Error>> {
+    step(steps, true)
+}
+
+pub fn redo(steps: usize) -> Result<(Vec<HistoryEntry>, Option<String>), Box<dyn Error>> {
+    step(steps, false)
+}
+
+// (undo, redo) stack sizes
+pub fn available() -> (usize, usize) {
+    with_history(|history| (history.undo.len(), history.redo.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[serial_test::serial]
+    fn test_evicts_past_max_bytes() {
+        clear();
+        set_session(None);
+        let change = |i: u8| FileChange {
+            path: PathBuf::from(format!("{}.bin", i)),
+            before: None,
+            after: Some(FileState {
+                content: vec![i; MAX_BYTES / 4],
+                mode: 0o644,
+            }),
+        };
+        for i in 0..6 {
+            record("Test", vec![change(i)]);
+        }
+        assert_eq!(available(), (4, 0));
+        assert_eq!(gaps().evicted, 2);
+        let oldest = with_history(|history| history.undo[0].changes[0].path.clone());
+        assert_eq!(oldest, PathBuf::from("2.bin"));
+        clear();
+    }
+}
@@ -4,24 +4,40 @@ use std::{error::Error, path::PathBuf};
 use poem_openapi::Object;
 use serde::{Deserialize, Serialize};
 
-use crate::operations::fs::utils::{ensure_relative, read_lines};
+use crate::operations::fs::{
+    history::{self, FileChange},
+    utils::{check_hash, ensure_relative, read_lines, write_text, WrittenText},
+};
 
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct InsertContentRequest {
     pub path: String,
     pub content: String,
     pub line: usize,
+    // Hash from the last read / edit, the edit is rejected if the file has changed since
+    pub expected_hash: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct InsertContentResponse {
     pub content: String,
+    pub hash: String,
 }
 
 impl InsertContentRequest {
     pub async fn process(self) -> Result<InsertContentResponse, Box<dyn Error>> {
-        let path = ensure_relative(PathBuf::from(self.path)).await?;
-        let mut lines = read_lines(&path).await?;
+        let path = ensure_relative(PathBuf::from(&self.path)).await?;
+        check_hash(&path, self.expected_hash.as_deref()).await?;
+        let before = history::snapshot(&path);
+        let (lines, format) = read_lines(&path).await?;
+        let lines = self.edit(lines)?;
+        let WrittenText { content, hash } = write_text(&path, &lines.join("\n"), &format).await?;
+        history::record("InsertContent", vec![FileChange::new(&path, before)]);
+        Ok(InsertContentResponse { content, hash })
+    }
+
+    // Apply the insert to a file's lines without touching disk
+    pub fn edit(&self, mut lines: Vec<String>) -> Result<Vec<String>, Box<dyn Error>> {
         // Figure out where to insert the new content now
         // If line is 0, the LLM incorrectly sent a 0-indexed line number but we should just handle
         // it, the LLM wants to prepend content to top of file
@@ -32,10 +48,8 @@ impl InsertContentRequest {
             line => line - 1,
         };
 
-        lines.insert(line, self.content);
-        let content = lines.join("\n");
-        tokio::fs::write(path, &content).await?;
-        Ok(InsertContentResponse { content })
+        lines.insert(line, self.content.clone());
+        Ok(lines)
     }
 }
 
@@ -64,6 +78,7 @@ mod tests {
             path: "test.txt".to_string(),
             content: "new line".to_string(),
             line: 2,
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
         assert_eq!(response.content, "line1\nnew line\nline2\nline3");
@@ -82,6 +97,7 @@ mod tests {
             path: "test.txt".to_string(),
             content: "new line\nanother new line".to_string(),
             line: 2,
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
         assert_eq!(
@@ -100,6 +116,7 @@ mod tests {
             path: "test.txt".to_string(),
             content: "new line".to_string(),
             line: 0,
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
         assert_eq!(response.content, "new line\nline1\nline2\nline3");
@@ -115,8 +132,34 @@ mod tests {
             path: "test.txt".to_string(),
             content: "new line".to_string(),
             line: 5,
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
         assert_eq!(response.content, "line1\nline2\nline3\nnew line");
     }
+
+    #[rstest::rstest]
+    #[case(b"line1\r\nline2\r\n".to_vec(), b"new\r\nline1\r\nline2\r\n".to_vec())]
+    #[case(b"\xEF\xBB\xBFline1\nline2".to_vec(), b"\xEF\xBB\xBFnew\nline1\nline2".to_vec())]
+    #[case(
+        b"\xFF\xFEa\x00\r\x00\n\x00b\x00".to_vec(),
+        b"\xFF\xFEn\x00e\x00w\x00\r\x00\n\x00a\x00\r\x00\n\x00b\x00".to_vec()
+    )]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_insert_preserves_format(
+        _tmp_dir: TempDir,
+        #[case] original: Vec<u8>,
+        #[case] expected: Vec<u8>,
+    ) {
+        std::fs::write("test.txt", original).unwrap();
+        let request = InsertContentRequest {
+            path: "test.txt".to_string(),
+            content: "new".to_string(),
+            line: 1,
+            expected_hash: None,
+        };
+        request.process().await.unwrap();
+        assert_eq!(std::fs::read("test.txt").unwrap(), expected);
+    }
 }
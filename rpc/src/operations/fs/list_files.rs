@@ -1,7 +1,19 @@
 //! List the files and subdirectories at a given path.
 //! Only allows relative paths from CWD where Agent started.
-use std::{collections::VecDeque, error::Error, fs, path::PathBuf};
-
+//!
+//! The walk honors .gitignore / .ignore files (even outside of a git repo), lists hidden files
+//! but not the .git directory, and runs on a blocking thread pool so large directory trees
+//! don't stall the async runtime.
+use std::{
+    error::Error,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use ignore::{WalkBuilder, WalkState};
 use poem_openapi::Object;
 use serde::{Deserialize, Serialize};
 
@@ -10,9 +22,11 @@ use serde::{Deserialize, Serialize};
 pub struct ListFilesRequest {
     pub path: String,
     pub max_depth: i32,
+    // Stop walking once this many files / untraversed directories have been collected
+    pub max_entries: usize,
     // In preliminary testing, the LLM frequently leaves this out which causes a poem-openapi
     // validation error. If I learn how to use defaults in poem-openapi like FastAPI / pydantic
-    // does, we can add this back in. For now it just hard-defaults to True in the impl
+    // does, we can add this back in. For now hidden files are always listed
     // pub ignore_hidden: bool,
 }
 
@@ -21,22 +35,18 @@ impl Default for ListFilesRequest {
         Self {
             path: ".".to_string(),
             max_depth: 3,
+            max_entries: 1000,
             // ignore_hidden: true,
         }
     }
 }
 
-#[derive(Debug)]
-struct Directory {
-    path: PathBuf,
-    files: Vec<PathBuf>,
-    depth: i32,
-}
-
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct ListFilesResponse {
     pub files: Vec<String>,
     pub untraversed: Vec<String>,
+    // True if the walk stopped early because max_entries was reached
+    pub truncated: bool,
 }
 
 impl ListFilesResponse {
@@ -45,13 +55,58 @@ impl ListFilesResponse {
     }
 }
 
-// See Request struct comment
-// fn is_hidden(path: &PathBuf) -> bool {
-//     path.file_name()
-//         .and_then(|name| name.to_str())
-//         .map(|s| s.starts_with("."))
-//         .unwrap_or(false)
-// }
+#[derive(Debug, Default)]
+struct WalkResults {
+    files: Vec<PathBuf>,
+    untraversed: Vec<PathBuf>,
+}
+
+// Walk the directory tree in parallel. Depth is counted the same way as the original BFS:
+// files directly in `path` are depth 0, and directories deeper than max_depth are reported
+// as untraversed rather than descended into.
+fn walk(path: PathBuf, max_depth: usize, max_entries: usize) -> (WalkResults, bool) {
+    let results = Arc::new(Mutex::new(WalkResults::default()));
+    let count = Arc::new(AtomicUsize::new(0));
+    let truncated = Arc::new(AtomicBool::new(false));
+
+    let walker = WalkBuilder::new(&path)
+        .max_depth(Some(max_depth + 1))
+        .require_git(false)
+        .hidden(false)
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .build_parallel();
+
+    walker.run(|| {
+        let results = Arc::clone(&results);
+        let count = Arc::clone(&count);
+        let truncated = Arc::clone(&truncated);
+        Box::new(move |entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => return WalkState::Continue,
+            };
+            let is_file = entry.file_type().map(|t| t.is_file()).unwrap_or(false);
+            let is_untraversed = entry.depth() == max_depth + 1
+                && entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+            if !is_file && !is_untraversed {
+                return WalkState::Continue;
+            }
+            if count.fetch_add(1, Ordering::SeqCst) >= max_entries {
+                truncated.store(true, Ordering::SeqCst);
+                return WalkState::Quit;
+            }
+            let mut results = results.lock().unwrap();
+            match is_file {
+                true => results.files.push(entry.into_path()),
+                false => results.untraversed.push(entry.into_path()),
+            }
+            WalkState::Continue
+        })
+    });
+
+    let results = std::mem::take(&mut *results.lock().unwrap());
+    (results, truncated.load(Ordering::SeqCst))
+}
 
 impl ListFilesRequest {
     pub async fn process(self) -> Result<ListFilesResponse, Box<dyn Error>> {
@@ -66,58 +121,29 @@ impl ListFilesRequest {
                 }
             }
         }
-        let mut directories: Vec<Directory> = Vec::new();
-        let mut untraversed_dirs: Vec<PathBuf> = Vec::new();
-        let mut queue: VecDeque<Directory> = VecDeque::new();
-        queue.push_back(Directory {
-            path: path,
-            files: Vec::new(),
-            depth: 0,
-        });
-        while let Some(mut dir) = queue.pop_front() {
-            let entries = match fs::read_dir(&dir.path) {
-                Ok(entries) => entries,
-                Err(_) => continue,
-            };
-            for entry in entries.filter_map(Result::ok) {
-                let path = entry.path();
-
-                // See the request struct comment
-                // if self.ignore_hidden && is_hidden(&path) {
-                //     continue;
-                // }
-                if path.is_file() {
-                    dir.files.push(path);
-                } else if path.is_dir() {
-                    if dir.depth < self.max_depth {
-                        let new_dir = Directory {
-                            path,
-                            files: Vec::new(),
-                            depth: dir.depth + 1,
-                        };
-                        queue.push_back(new_dir);
-                    } else {
-                        untraversed_dirs.push(path);
-                    }
-                }
-            }
-            directories.push(dir);
-        }
+        let max_depth = self.max_depth.max(0) as usize;
+        let max_entries = self.max_entries;
+        let (results, truncated) =
+            tokio::task::spawn_blocking(move || walk(path, max_depth, max_entries)).await?;
+
         // collect all files with their full relative path
-        let mut files: Vec<String> = directories
+        let mut files: Vec<String> = results
+            .files
             .iter()
-            .flat_map(|dir| {
-                dir.files
-                    .iter()
-                    .map(|path| path.to_string_lossy().to_string())
-            })
+            .map(|path| path.to_string_lossy().to_string())
             .collect();
         files.sort();
-        let untraversed: Vec<String> = untraversed_dirs
+        let mut untraversed: Vec<String> = results
+            .untraversed
             .iter()
             .map(|path| path.to_string_lossy().to_string())
             .collect();
-        Ok(ListFilesResponse { files, untraversed })
+        untraversed.sort();
+        Ok(ListFilesResponse {
+            files,
+            untraversed,
+            truncated,
+        })
     }
 }
 
@@ -152,8 +178,7 @@ mod tests {
         let req = ListFilesRequest {
             path: ".".to_string(),
             max_depth: 1,
-            // See Request struct comment
-            // ignore_hidden: true,
+            ..Default::default()
         };
 
         // Process the request
@@ -164,6 +189,7 @@ mod tests {
         assert_eq!(resp.untraversed.len(), 1); // level2 should be untraversed
         assert_eq!(resp.files[0], "./level1/file1.txt");
         assert_eq!(resp.untraversed[0], "./level1/level2");
+        assert!(!resp.truncated);
     }
 
     #[rstest::rstest]
@@ -173,8 +199,7 @@ mod tests {
         let req = ListFilesRequest {
             path: "/".to_string(),
             max_depth: 1,
-            // See Request struct comment
-            // ignore_hidden: true,
+            ..Default::default()
         };
         let resp = req.process().await;
         assert!(resp.is_err());
@@ -183,4 +208,42 @@ mod tests {
             "Path must be a sub-directory of the current working directory"
         );
     }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_respects_gitignore(_tmp_dir: TempDir) {
+        fs::create_dir_all("target/debug").unwrap();
+        fs::create_dir("src").unwrap();
+        File::create("target/debug/build.o").unwrap();
+        File::create("src/main.rs").unwrap();
+        File::create(".hidden").unwrap();
+        fs::write(".gitignore", "target/\n").unwrap();
+
+        fs::create_dir(".git").unwrap();
+        File::create(".git/HEAD").unwrap();
+
+        let resp = ListFilesRequest::default().process().await.unwrap();
+        assert_eq!(
+            resp.files,
+            vec!["./.gitignore", "./.hidden", "./src/main.rs"]
+        );
+        assert!(resp.untraversed.is_empty());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_truncated_at_max_entries(_tmp_dir: TempDir) {
+        for i in 0..10 {
+            File::create(format!("file{}.txt", i)).unwrap();
+        }
+        let req = ListFilesRequest {
+            max_entries: 4,
+            ..Default::default()
+        };
+        let resp = req.process().await.unwrap();
+        assert_eq!(resp.files.len(), 4);
+        assert!(resp.truncated);
+    }
 }
@@ -1,11 +1,22 @@
+pub mod apply_edits;
+pub mod archive;
+pub mod checkpoint;
+pub mod copy_path;
 pub mod create_directory;
 pub mod create_file;
 pub mod delete_content;
 pub mod diff;
+pub mod export_patches;
+pub mod history;
 pub mod insert_content;
 pub mod list_files;
 pub mod move_file;
+pub mod outline;
 pub mod read_file;
+pub mod remove_directory;
 pub mod remove_file;
 pub mod replace_content;
+pub mod stat;
+pub mod str_replace;
+pub mod undo;
 pub mod utils;
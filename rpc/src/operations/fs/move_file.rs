@@ -3,6 +3,8 @@ use std::{fs, io};
 use poem_openapi::Object;
 use serde::{Deserialize, Serialize};
 
+use crate::operations::fs::history::{self, FileChange};
+
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct MoveFileRequest {
     pub src_path: String,
@@ -29,7 +31,16 @@ impl MoveFileRequest {
             ));
         }
 
+        let src_before = history::snapshot(src_path);
+        let dest_before = history::snapshot(dest_path);
         fs::rename(&self.src_path, &self.dest_path)?;
+        history::record(
+            "MoveFile",
+            vec![
+                FileChange::new(src_path, src_before),
+                FileChange::new(dest_path, dest_before),
+            ],
+        );
         Ok(MoveFileResponse { success: true })
     }
 }
@@ -0,0 +1,405 @@
+//! Outline the symbols (functions, classes, structs, impls, methods...) defined in source files,
+//! with their line ranges and signatures. Lets the LLM find the lines it wants to edit without
+//! reading whole files.
+//!
+//! Files are parsed with tree-sitter grammars compiled into the Agent, currently Rust and
+//! Python. Pointing it at a directory outlines every supported file in it, honoring
+//! .gitignore the same way ListFiles does.
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use ignore::WalkBuilder;
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+use tree_sitter::{Language, Node, Parser};
+
+use crate::operations::fs::utils::ensure_relative;
+
+// Files bigger than this are skipped, they're almost certainly generated
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+fn default_max_files() -> usize {
+    200
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct OutlineRequest {
+    // A source file, or a directory to outline every supported file in
+    pub path: String,
+    #[oai(default = "default_max_files")]
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Object)]
+pub struct Symbol {
+    pub name: String,
+    // function, method, class, struct, enum, trait, impl, module, type or macro
+    pub kind: String,
+    // One-based and inclusive, ready to use with ReplaceContent / DeleteContent
+    pub start_line: usize,
+    pub end_line: usize,
+    // Declaration up to the start of the body, whitespace collapsed
+    pub signature: String,
+    // Name of the enclosing class / impl / trait / module, if any
+    pub parent: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct FileOutline {
+    pub path: String,
+    pub language: String,
+    pub symbols: Vec<Symbol>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct OutlineResponse {
+    pub files: Vec<FileOutline>,
+    // True if the directory had more than max_files supported files
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SourceLanguage {
+    Rust,
+    Python,
+}
+
+impl SourceLanguage {
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "rs" => Some(Self::Rust),
+            "py" | "pyi" => Some(Self::Python),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Rust => "rust",
+            Self::Python => "python",
+        }
+    }
+
+    fn grammar(&self) -> Language {
+        match self {
+            Self::Rust => tree_sitter_rust::language(),
+            Self::Python => tree_sitter_python::language(),
+        }
+    }
+}
+
+struct Outliner<'a> {
+    source: &'a str,
+    language: SourceLanguage,
+    symbols: Vec<Symbol>,
+}
+
+impl<'a> Outliner<'a> {
+    fn text(&self, node: Node) -> &'a str {
+        &self.source[node.byte_range()]
+    }
+
+    fn field_text(&self, node: Node, field: &str) -> Option<String> {
+        node.child_by_field_name(field)
+            .map(|n| self.text(n).to_string())
+    }
+
+    // Everything before the body, e.g. `pub fn foo(a: i32) -> i32` or `def foo(a):`
+    fn signature(&self, node: Node) -> String {
+        let end = match node.child_by_field_name("body") {
+            Some(body) => body.start_byte(),
+            None => node.end_byte(),
+        };
+        let text = &self.source[node.start_byte()..end];
+        text.split_whitespace().collect::<Vec<&str>>().join(" ")
+    }
+
+    fn push(&mut self, node: Node, span: Node, name: String, kind: &str, parent: Option<&str>) {
+        self.symbols.push(Symbol {
+            name,
+            kind: kind.to_string(),
+            start_line: span.start_position().row + 1,
+            end_line: node.end_position().row + 1,
+            signature: self.signature(node),
+            parent: parent.map(|p| p.to_string()),
+        });
+    }
+
+    fn visit_children(&mut self, node: Node, parent: Option<&str>, parent_kind: Option<&str>) {
+        let mut cursor = node.walk();
+        let children: Vec<Node> = node.named_children(&mut cursor).collect();
+        for child in children {
+            match self.language {
+                SourceLanguage::Rust => self.visit_rust(child, parent, parent_kind),
+                SourceLanguage::Python => self.visit_python(child, child, parent, parent_kind),
+            }
+        }
+    }
+
+    fn visit_rust(&mut self, node: Node, parent: Option<&str>, parent_kind: Option<&str>) {
+        let kind = match node.kind() {
+            "function_item" | "function_signature_item" => match parent_kind {
+                Some("impl") | Some("trait") => "method",
+                _ => "function",
+            },
+            "struct_item" | "union_item" => "struct",
+            "enum_item" => "enum",
+            "trait_item" => "trait",
+            "impl_item" => "impl",
+            "mod_item" => "module",
+            "type_item" => "type",
+            "macro_definition" => "macro",
+            _ => return,
+        };
+        let name = match kind {
+            "impl" => {
+                let type_name = self.field_text(node, "type").unwrap_or_default();
+                match self.field_text(node, "trait") {
+                    Some(trait_name) => format!("{} for {}", trait_name, type_name),
+                    None => type_name,
+                }
+            }
+            _ => self.field_text(node, "name").unwrap_or_default(),
+        };
+        self.push(node, node, name.clone(), kind, parent);
+        // Methods live in impl / trait bodies, and tests in nested modules
+        if matches!(kind, "impl" | "trait" | "module") {
+            if let Some(body) = node.child_by_field_name("body") {
+                self.visit_children(body, Some(&name), Some(kind));
+            }
+        }
+    }
+
+    // `span` is the node the symbol's lines start from, the decorated_definition for decorated
+    // functions / classes so the decorators are included
+    fn visit_python(
+        &mut self,
+        node: Node,
+        span: Node,
+        parent: Option<&str>,
+        parent_kind: Option<&str>,
+    ) {
+        let kind = match node.kind() {
+            "decorated_definition" => {
+                if let Some(definition) = node.child_by_field_name("definition") {
+                    self.visit_python(definition, node, parent, parent_kind);
+                }
+                return;
+            }
+            "function_definition" => match parent_kind {
+                Some("class") => "method",
+                _ => "function",
+            },
+            "class_definition" => "class",
+            _ => return,
+        };
+        let name = self.field_text(node, "name").unwrap_or_default();
+        self.push(node, span, name.clone(), kind, parent);
+        if kind == "class" {
+            if let Some(body) = node.child_by_field_name("body") {
+                self.visit_children(body, Some(&name), Some(kind));
+            }
+        }
+    }
+}
+
+fn outline_source(source: &str, language: SourceLanguage) -> Result<Vec<Symbol>, Box<dyn Error>> {
+    let mut parser = Parser::new();
+    parser.set_language(language.grammar())?;
+    let tree = parser.parse(source, None).ok_or("Failed to parse file")?;
+    let mut outliner = Outliner {
+        source,
+        language,
+        symbols: Vec::new(),
+    };
+    outliner.visit_children(tree.root_node(), None, None);
+    Ok(outliner.symbols)
+}
+
+fn outline_file(path: &Path, language: SourceLanguage) -> Result<FileOutline, Box<dyn Error>> {
+    let source = fs::read_to_string(path)?;
+    Ok(FileOutline {
+        path: path.to_string_lossy().to_string(),
+        language: language.name().to_string(),
+        symbols: outline_source(&source, language)?,
+    })
+}
+
+fn outline_directory(path: &Path, max_files: usize) -> OutlineResponse {
+    let mut paths: Vec<PathBuf> = WalkBuilder::new(path)
+        .require_git(false)
+        .build()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .filter(|entry| {
+            let size = entry.metadata().map(|m| m.len()).unwrap_or(u64::MAX);
+            size <= MAX_FILE_SIZE && SourceLanguage::from_path(entry.path()).is_some()
+        })
+        .map(|entry| entry.into_path())
+        .collect();
+    paths.sort();
+    let truncated = paths.len() > max_files;
+    paths.truncate(max_files);
+    // Files that fail to read (e.g. not UTF-8) are left out rather than failing the whole walk
+    let files = paths
+        .iter()
+        .filter_map(|p| outline_file(p, SourceLanguage::from_path(p)?).ok())
+        .collect();
+    OutlineResponse { files, truncated }
+}
+
+impl OutlineRequest {
+    pub async fn process(self) -> Result<OutlineResponse, Box<dyn Error>> {
+        let path = ensure_relative(PathBuf::from(self.path)).await?;
+        let max_files = self.max_files;
+        tokio::task::spawn_blocking(move || {
+            if path.is_dir() {
+                return Ok(outline_directory(&path, max_files));
+            }
+            let language = SourceLanguage::from_path(&path).ok_or_else(|| {
+                format!("Unsupported file type for {:?}, expected .rs or .py", path)
+            })?;
+            let file = outline_file(&path, language).map_err(|e| e.to_string())?;
+            Ok(OutlineResponse {
+                files: vec![file],
+                truncated: false,
+            })
+        })
+        .await?
+        .map_err(|e: String| e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        dir
+    }
+
+    fn summary(symbols: &[Symbol]) -> Vec<(&str, &str, usize, usize, Option<&str>)> {
+        symbols
+            .iter()
+            .map(|s| {
+                (
+                    s.name.as_str(),
+                    s.kind.as_str(),
+                    s.start_line,
+                    s.end_line,
+                    s.parent.as_deref(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_outline_rust() {
+        let source = r#"use std::fmt;
+
+pub struct Point {
+    x: i32,
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.x)
+    }
+}
+
+pub fn origin() -> Point {
+    Point { x: 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_origin() {}
+}
+"#;
+        let symbols = outline_source(source, SourceLanguage::Rust).unwrap();
+        assert_eq!(
+            summary(&symbols),
+            vec![
+                ("Point", "struct", 3, 5, None),
+                ("fmt::Display for Point", "impl", 7, 11, None),
+                ("fmt", "method", 8, 10, Some("fmt::Display for Point")),
+                ("origin", "function", 13, 15, None),
+                ("tests", "module", 18, 21, None),
+                ("test_origin", "function", 20, 20, Some("tests")),
+            ]
+        );
+        assert_eq!(symbols[3].signature, "pub fn origin() -> Point");
+    }
+
+    #[test]
+    fn test_outline_python() {
+        let source = r#"import os
+
+
+class Greeter(Base):
+    def __init__(self, name):
+        self.name = name
+
+    @property
+    def greeting(self) -> str:
+        return f"Hello {self.name}"
+
+
+def main():
+    print(Greeter("world").greeting)
+"#;
+        let symbols = outline_source(source, SourceLanguage::Python).unwrap();
+        assert_eq!(
+            summary(&symbols),
+            vec![
+                ("Greeter", "class", 4, 10, None),
+                ("__init__", "method", 5, 6, Some("Greeter")),
+                ("greeting", "method", 8, 10, Some("Greeter")),
+                ("main", "function", 13, 14, None),
+            ]
+        );
+        assert_eq!(symbols[0].signature, "class Greeter(Base):");
+        assert_eq!(symbols[2].signature, "def greeting(self) -> str:");
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_outline_directory(_tmp_dir: TempDir) {
+        fs::create_dir_all("src").unwrap();
+        fs::write("src/lib.rs", "fn a() {}\n").unwrap();
+        fs::write("src/main.py", "def b():\n    pass\n").unwrap();
+        fs::write("src/notes.txt", "not code").unwrap();
+        let request = OutlineRequest {
+            path: ".".to_string(),
+            max_files: default_max_files(),
+        };
+        let response = request.process().await.unwrap();
+        let files: Vec<(&str, &str)> = response
+            .files
+            .iter()
+            .map(|f| (f.path.as_str(), f.language.as_str()))
+            .collect();
+        assert_eq!(
+            files,
+            vec![("./src/lib.rs", "rust"), ("./src/main.py", "python")]
+        );
+        assert!(!response.truncated);
+
+        let request = OutlineRequest {
+            path: "src/notes.txt".to_string(),
+            max_files: default_max_files(),
+        };
+        assert!(request.process().await.is_err());
+    }
+}
@@ -1,9 +1,15 @@
-//! Return file contents as a string
+//! Return file contents as a string, decoded the same way the edit operations read files (so
+//! UTF-16 works and line endings are LF) while the hash is of the bytes on disk
 use std::error::Error;
 
 use poem_openapi::Object;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    notifications,
+    operations::fs::utils::{content_hash, decode_text},
+};
+
 #[derive(Debug, Default, Serialize, Deserialize, Object)]
 #[oai(default)]
 pub struct ReadFileRequest {
@@ -13,6 +19,8 @@ pub struct ReadFileRequest {
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct ReadFileResponse {
     pub content: String,
+    // Pass this back as `expected_hash` when editing the file
+    pub hash: String,
 }
 
 impl ReadFileRequest {
@@ -29,7 +37,41 @@ impl ReadFileRequest {
                 }
             }
         }
-        let content = tokio::fs::read_to_string(path).await?;
-        Ok(ReadFileResponse { content })
+        let bytes = tokio::fs::read(path).await?;
+        let hash = content_hash(&bytes);
+        notifications::remember(path, Some(&bytes));
+        let (content, _) = decode_text(&bytes)?;
+        Ok(ReadFileResponse { content, hash })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        dir
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_read_utf16(_tmp_dir: TempDir) {
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend("caf\u{e9}\r\n".encode_utf16().flat_map(|u| u.to_le_bytes()));
+        fs::write("utf16.txt", &bytes).unwrap();
+        let request = ReadFileRequest {
+            path: "utf16.txt".to_string(),
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.content, "caf\u{e9}\n");
+        assert_eq!(response.hash, content_hash(&bytes));
     }
 }
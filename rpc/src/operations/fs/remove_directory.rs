@@ -0,0 +1,245 @@
+//! Remove a directory. Non-empty directories are only removed when `recursive` is set, and
+//! only if they hold no more than `max_entries` files and subdirectories, so an LLM can't
+//! wipe out a large tree with one call.
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    notifications,
+    operations::fs::{
+        history::{self, FileChange},
+        utils::{ensure_not_protected, ensure_relative},
+    },
+};
+
+fn default_max_entries() -> usize {
+    1000
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RemoveDirectoryRequest {
+    pub path: String,
+    #[oai(default)]
+    #[serde(default)]
+    pub recursive: bool,
+    #[oai(default = "default_max_entries")]
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RemoveDirectoryResponse {
+    // Number of files and subdirectories removed, not counting the directory itself
+    pub removed_entries: usize,
+}
+
+// Collect everything under a directory without following symlinks, bailing out once the
+// count passes the limit
+fn collect_entries(
+    path: &Path,
+    limit: usize,
+    entries: &mut Vec<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        entries.push(entry.path());
+        if entries.len() > limit {
+            break;
+        }
+        if entry.file_type()?.is_dir() {
+            collect_entries(&entry.path(), limit, entries)?;
+        }
+    }
+    Ok(())
+}
+
+impl RemoveDirectoryRequest {
+    pub async fn process(self) -> Result<RemoveDirectoryResponse, Box<dyn Error>> {
+        let path = ensure_relative(PathBuf::from(self.path)).await?;
+        ensure_not_protected(&path)?;
+        if !fs::symlink_metadata(&path)?.is_dir() {
+            return Err(format!("{:?} is not a directory", path).into());
+        }
+
+        if !self.recursive {
+            fs::remove_dir(&path)?;
+            return Ok(RemoveDirectoryResponse { removed_entries: 0 });
+        }
+
+        let max_entries = self.max_entries;
+        let (result, changes) = tokio::task::spawn_blocking(move || {
+            let mut entries = Vec::new();
+            if let Err(e) = collect_entries(&path, max_entries, &mut entries) {
+                return (Err(e.to_string()), Vec::new());
+            }
+            if entries.len() > max_entries {
+                let error = format!(
+                    "Directory has more than {} entries, raise max_entries to remove it",
+                    max_entries
+                );
+                return (Err(error), Vec::new());
+            }
+            // Snapshot the regular files so Undo can bring them back. Directories and symlinks
+            // aren't journaled, parent directories are recreated when a file is restored.
+            let snapshots: Vec<(PathBuf, Option<history::FileState>)> = entries
+                .iter()
+                .map(|entry| match fs::symlink_metadata(entry) {
+                    Ok(metadata) if metadata.is_file() => (entry.clone(), history::snapshot(entry)),
+                    _ => (entry.clone(), None),
+                })
+                .collect();
+            let result = fs::remove_dir_all(&path).map_err(|e| e.to_string());
+            let mut changes = Vec::new();
+            for (entry, before) in snapshots {
+                match before {
+                    Some(before) => changes.push(FileChange::new(&entry, Some(before))),
+                    None => notifications::remember(&entry, None),
+                }
+            }
+            let response = RemoveDirectoryResponse {
+                removed_entries: entries.len(),
+            };
+            (result.map(|_| response), changes)
+        })
+        .await?;
+        // Also recorded when removal failed partway, for the files that are already gone
+        history::record("RemoveDirectory", changes);
+        result.map_err(|e| e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        dir
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_remove_empty_directory(_tmp_dir: TempDir) {
+        fs::create_dir("empty").unwrap();
+        let request = RemoveDirectoryRequest {
+            path: "empty".to_string(),
+            recursive: false,
+            max_entries: default_max_entries(),
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.removed_entries, 0);
+        assert!(!Path::new("empty").exists());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_non_recursive_fails_on_non_empty(_tmp_dir: TempDir) {
+        fs::create_dir("full").unwrap();
+        fs::write("full/file.txt", "content").unwrap();
+        let request = RemoveDirectoryRequest {
+            path: "full".to_string(),
+            recursive: false,
+            max_entries: default_max_entries(),
+        };
+        assert!(request.process().await.is_err());
+        assert!(Path::new("full/file.txt").exists());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_remove_recursive(_tmp_dir: TempDir) {
+        fs::create_dir_all("full/nested").unwrap();
+        fs::write("full/a.txt", "a").unwrap();
+        fs::write("full/nested/b.txt", "b").unwrap();
+        let request = RemoveDirectoryRequest {
+            path: "full".to_string(),
+            recursive: true,
+            max_entries: default_max_entries(),
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.removed_entries, 3);
+        assert!(!Path::new("full").exists());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_undo_remove_recursive(_tmp_dir: TempDir) {
+        history::clear();
+        fs::create_dir_all("full/nested").unwrap();
+        fs::write("full/a.txt", "a").unwrap();
+        fs::write("full/nested/b.txt", "b").unwrap();
+        let request = RemoveDirectoryRequest {
+            path: "full".to_string(),
+            recursive: true,
+            max_entries: default_max_entries(),
+        };
+        request.process().await.unwrap();
+        assert!(!Path::new("full").exists());
+
+        history::undo(1).unwrap();
+        assert_eq!(fs::read_to_string("full/a.txt").unwrap(), "a");
+        assert_eq!(fs::read_to_string("full/nested/b.txt").unwrap(), "b");
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_max_entries_limit(_tmp_dir: TempDir) {
+        fs::create_dir("full").unwrap();
+        for i in 0..5 {
+            fs::write(format!("full/{}.txt", i), "content").unwrap();
+        }
+        let request = RemoveDirectoryRequest {
+            path: "full".to_string(),
+            recursive: true,
+            max_entries: 3,
+        };
+        let response = request.process().await;
+        assert_eq!(
+            response.unwrap_err().to_string(),
+            "Directory has more than 3 entries, raise max_entries to remove it"
+        );
+        assert!(Path::new("full/0.txt").exists());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_remove_workspace_root(_tmp_dir: TempDir) {
+        let request = RemoveDirectoryRequest {
+            path: ".".to_string(),
+            recursive: true,
+            max_entries: default_max_entries(),
+        };
+        let response = request.process().await;
+        assert_eq!(
+            response.unwrap_err().to_string(),
+            "Operation not allowed on the workspace root"
+        );
+
+        fs::create_dir("src").unwrap();
+        for path in ["..", "src/.."] {
+            let request = RemoveDirectoryRequest {
+                path: path.to_string(),
+                recursive: true,
+                max_entries: default_max_entries(),
+            };
+            assert!(request.process().await.is_err());
+        }
+        assert!(Path::new("src").exists());
+    }
+}
@@ -3,6 +3,8 @@ use std::{fs, io};
 use poem_openapi::Object;
 use serde::{Deserialize, Serialize};
 
+use crate::operations::fs::history::{self, FileChange};
+
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct RemoveFileRequest {
     pub path: String,
@@ -25,7 +27,9 @@ impl RemoveFileRequest {
             ));
         }
 
+        let before = history::snapshot(path);
         fs::remove_file(&self.path)?;
+        history::record("RemoveFile", vec![FileChange::new(path, before)]);
         Ok(RemoveFileResponse { success: true })
     }
 }
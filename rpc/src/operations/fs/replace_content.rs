@@ -4,7 +4,10 @@ use std::{error::Error, path::PathBuf};
 use poem_openapi::Object;
 use serde::{Deserialize, Serialize};
 
-use crate::operations::fs::utils::{ensure_relative, read_lines};
+use crate::operations::fs::{
+    history::{self, FileChange},
+    utils::{check_hash, ensure_relative, read_lines, write_text, WrittenText},
+};
 
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct ReplaceContentRequest {
@@ -12,18 +15,31 @@ pub struct ReplaceContentRequest {
     pub content: String,
     pub start_line: usize,
     pub end_line: Option<usize>, // Empty to replace just a single line
+    // Hash from the last read / edit, the edit is rejected if the file has changed since
+    pub expected_hash: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Object)]
 pub struct ReplaceContentResponse {
     pub content: String,
+    pub hash: String,
 }
 
 impl ReplaceContentRequest {
     pub async fn process(self) -> Result<ReplaceContentResponse, Box<dyn Error>> {
-        let path = ensure_relative(PathBuf::from(self.path)).await?;
-        let mut lines = read_lines(&path).await?;
+        let path = ensure_relative(PathBuf::from(&self.path)).await?;
+        check_hash(&path, self.expected_hash.as_deref()).await?;
+        let before = history::snapshot(&path);
+        let (lines, format) = read_lines(&path).await?;
+        let lines = self.edit(lines)?;
+        let WrittenText { content, hash } = write_text(&path, &lines.join("\n"), &format).await?;
+        history::record("ReplaceContent", vec![FileChange::new(&path, before)]);
 
+        Ok(ReplaceContentResponse { content, hash })
+    }
+
+    // Apply the replacement to a file's lines without touching disk
+    pub fn edit(&self, mut lines: Vec<String>) -> Result<Vec<String>, Box<dyn Error>> {
         // First sanity check the start line
         // - help the LLM if it sent 0, clearly trying to change the top line in the file
         // - raise error if start line is out of index after downcasting to 0-based index
@@ -50,10 +66,7 @@ impl ReplaceContentRequest {
 
         // splice in new lines, use inclusive range (=end_line)
         lines.splice(start_line..=end_line, new_lines);
-        let content = lines.join("\n");
-        tokio::fs::write(path, &content).await?;
-
-        Ok(ReplaceContentResponse { content })
+        Ok(lines)
     }
 }
 
@@ -64,6 +77,7 @@ mod tests {
     use tempfile::TempDir;
 
     use super::*;
+    use crate::operations::fs::utils::content_hash;
 
     #[rstest::fixture]
     fn tmp_dir() -> TempDir {
@@ -83,6 +97,7 @@ mod tests {
             content: "new line".to_string(),
             start_line: 2,
             end_line: None,
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
         assert_eq!(response.content, "line1\nnew line\nline3\n");
@@ -102,6 +117,7 @@ mod tests {
             content: "new line\nnew line2".to_string(),
             start_line: 2,
             end_line: None,
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
         assert_eq!(response.content, "line1\nnew line\nnew line2\nline3\n");
@@ -118,6 +134,7 @@ mod tests {
             content: "new line".to_string(),
             start_line: 2,
             end_line: Some(3),
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
         assert_eq!(response.content, "line1\nnew line\n");
@@ -134,6 +151,7 @@ mod tests {
             content: "new line".to_string(),
             start_line: 0,
             end_line: None,
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
         assert_eq!(response.content, "new line\nline2\nline3\n");
@@ -150,6 +168,7 @@ mod tests {
             content: "new line".to_string(),
             start_line: 3,
             end_line: None,
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
         assert_eq!(response.content, "line1\nline2\nnew line");
@@ -166,6 +185,7 @@ mod tests {
             content: "new line".to_string(),
             start_line: 4,
             end_line: None,
+            expected_hash: None,
         };
         let response = request.process().await;
         assert!(response.is_err());
@@ -186,8 +206,64 @@ mod tests {
             content: "new line".to_string(),
             start_line: 3,
             end_line: Some(10),
+            expected_hash: None,
         };
         let response = request.process().await.unwrap();
-        assert_eq!(response.content, "line1\nline2\nnew line");
+        // The original file's final newline is kept
+        assert_eq!(response.content, "line1\nline2\nnew line\n");
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_expected_hash(_tmp_dir: TempDir) {
+        let mut f = File::create("test.txt").unwrap();
+        f.write_all(b"line1\nline2\nline3").unwrap();
+        let hash = content_hash(b"line1\nline2\nline3");
+        let request = ReplaceContentRequest {
+            path: "test.txt".to_string(),
+            content: "new line".to_string(),
+            start_line: 1,
+            end_line: None,
+            expected_hash: Some(hash),
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.hash, content_hash(b"new line\nline2\nline3"));
+
+        // Edit again with the now-stale hash
+        let request = ReplaceContentRequest {
+            path: "test.txt".to_string(),
+            content: "other line".to_string(),
+            start_line: 2,
+            end_line: None,
+            expected_hash: Some(content_hash(b"line1\nline2\nline3")),
+        };
+        let err = request.process().await.unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&format!("current hash {}", response.hash)));
+        let content = std::fs::read_to_string("test.txt").unwrap();
+        assert_eq!(content, "new line\nline2\nline3");
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_replace_preserves_crlf(_tmp_dir: TempDir) {
+        std::fs::write("test.txt", b"line1\r\nline2\r\nline3\r\n").unwrap();
+        let request = ReplaceContentRequest {
+            path: "test.txt".to_string(),
+            content: "new line\nnew line2".to_string(),
+            start_line: 2,
+            end_line: None,
+            expected_hash: None,
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.content, "line1\nnew line\nnew line2\nline3\n");
+        // Only the replaced line differs on disk, the rest keep their CRLF endings
+        assert_eq!(
+            std::fs::read("test.txt").unwrap(),
+            b"line1\r\nnew line\r\nnew line2\r\nline3\r\n"
+        );
     }
 }
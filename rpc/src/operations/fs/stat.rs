@@ -0,0 +1,214 @@
+//! Return metadata for one or more paths without reading their full content.
+//! Missing paths are not an error, they come back with `exists: false` so the LLM can check
+//! for a file before creating or editing it.
+use std::{
+    error::Error,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, time::Duration};
+
+use crate::operations::{
+    commands::utils::run_command_with_timeout,
+    fs::utils::{decode_text, detect_encoding, ensure_relative},
+};
+
+// Don't read huge files into memory just to count their lines
+const MAX_LINE_COUNT_BYTES: u64 = 10 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct StatRequest {
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct StatResponse {
+    pub entries: Vec<PathStat>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Object)]
+pub struct PathStat {
+    pub path: String,
+    pub exists: bool,
+    // "file", "dir", or "symlink"
+    pub file_type: Option<String>,
+    pub symlink_target: Option<String>,
+    pub size: Option<u64>,
+    // Octal permission bits, e.g. "644"
+    pub permissions: Option<String>,
+    // RFC 3339 modified time
+    pub modified: Option<String>,
+    pub line_count: Option<usize>,
+    pub encoding: Option<String>,
+    // None if the path isn't inside a git repository (or git isn't installed)
+    pub git_tracked: Option<bool>,
+}
+
+fn count_lines(content: &[u8]) -> usize {
+    let newlines = content.iter().filter(|&&b| b == b'\n').count();
+    match content.last() {
+        Some(b'\n') | None => newlines,
+        Some(_) => newlines + 1,
+    }
+}
+
+async fn git_tracked(path: &Path) -> Option<bool> {
+    let path = path.to_str()?;
+    let args = vec!["ls-files", "--error-unmatch", "--", path];
+    let result = run_command_with_timeout("git", &args, Duration::from_secs(5))
+        .await
+        .ok()?;
+    match result.exit_status {
+        Some(0) => Some(true),
+        Some(1) => Some(false),
+        _ => None,
+    }
+}
+
+async fn stat_path(path: PathBuf) -> Result<PathStat, Box<dyn Error>> {
+    let mut stat = PathStat {
+        path: path.to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let link_meta = match fs::symlink_metadata(&path).await {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(stat),
+        Err(e) => return Err(e.into()),
+    };
+    stat.exists = true;
+
+    if link_meta.file_type().is_symlink() {
+        stat.file_type = Some("symlink".to_string());
+        stat.symlink_target = Some(fs::read_link(&path).await?.to_string_lossy().to_string());
+    } else if link_meta.is_dir() {
+        stat.file_type = Some("dir".to_string());
+    } else {
+        stat.file_type = Some("file".to_string());
+    }
+
+    // Size, permissions etc follow symlinks. A dangling symlink still "exists" but has no
+    // target metadata to report
+    if let Ok(meta) = fs::metadata(&path).await {
+        stat.size = Some(meta.len());
+        stat.permissions = Some(format!("{:o}", meta.permissions().mode() & 0o7777));
+        if let Ok(modified) = meta.modified() {
+            stat.modified = Some(chrono::DateTime::<chrono::Utc>::from(modified).to_rfc3339());
+        }
+        if meta.is_file() && meta.len() <= MAX_LINE_COUNT_BYTES {
+            let content = fs::read(&path).await?;
+            let encoding = detect_encoding(&content);
+            // Counted on the decoded text, so UTF-16 newlines are found. Undecodable content
+            // falls back to counting newline bytes.
+            if encoding != "binary" {
+                stat.line_count = Some(match decode_text(&content) {
+                    Ok((text, _)) => count_lines(text.as_bytes()),
+                    Err(_) => count_lines(&content),
+                });
+            }
+            stat.encoding = Some(encoding.to_string());
+        }
+    }
+    stat.git_tracked = git_tracked(&path).await;
+    Ok(stat)
+}
+
+impl StatRequest {
+    pub async fn process(self) -> Result<StatResponse, Box<dyn Error>> {
+        let mut entries = Vec::new();
+        for path in self.paths {
+            let path = ensure_relative(PathBuf::from(path)).await?;
+            entries.push(stat_path(path).await?);
+        }
+        Ok(StatResponse { entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, fs::File, io::Write};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        dir
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_stat_file_and_missing(_tmp_dir: TempDir) {
+        let mut f = File::create("test.txt").unwrap();
+        f.write_all(b"line1\nline2\nline3").unwrap();
+        fs::set_permissions("test.txt", fs::Permissions::from_mode(0o640)).unwrap();
+        let request = StatRequest {
+            paths: vec!["test.txt".to_string(), "missing.txt".to_string()],
+        };
+        let response = request.process().await.unwrap();
+
+        let file = &response.entries[0];
+        assert!(file.exists);
+        assert_eq!(file.file_type.as_deref(), Some("file"));
+        assert_eq!(file.size, Some(17));
+        assert_eq!(file.permissions.as_deref(), Some("640"));
+        assert_eq!(file.line_count, Some(3));
+        assert_eq!(file.encoding.as_deref(), Some("utf-8"));
+        assert!(file.modified.is_some());
+
+        let missing = &response.entries[1];
+        assert!(!missing.exists);
+        assert!(missing.file_type.is_none());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_stat_utf16_lines(_tmp_dir: TempDir) {
+        let mut content = vec![0xFF, 0xFE];
+        content.extend("a\nb\n".encode_utf16().flat_map(|u| u.to_le_bytes()));
+        fs::write("utf16.txt", content).unwrap();
+        let request = StatRequest {
+            paths: vec!["utf16.txt".to_string()],
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.entries[0].encoding.as_deref(), Some("utf-16le"));
+        assert_eq!(response.entries[0].line_count, Some(2));
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_stat_dir_and_symlink(_tmp_dir: TempDir) {
+        fs::create_dir("subdir").unwrap();
+        std::os::unix::fs::symlink("subdir", "link").unwrap();
+        let request = StatRequest {
+            paths: vec!["subdir".to_string(), "link".to_string()],
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.entries[0].file_type.as_deref(), Some("dir"));
+        assert_eq!(response.entries[1].file_type.as_deref(), Some("symlink"));
+        assert_eq!(
+            response.entries[1].symlink_target.as_deref(),
+            Some("subdir")
+        );
+        assert!(response.entries[1].line_count.is_none());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_stat_outside_cwd(_tmp_dir: TempDir) {
+        let request = StatRequest {
+            paths: vec!["/etc/passwd".to_string()],
+        };
+        let response = request.process().await;
+        assert!(response.is_err());
+    }
+}
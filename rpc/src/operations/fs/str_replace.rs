@@ -0,0 +1,259 @@
+//! Replace an exact string in a file. Less error prone for LLMs than line numbers or unified
+//! diffs, since the old text has to match what's actually in the file.
+//!
+//! By default `old_str` must match exactly once. If it matches more than once, the LLM can add
+//! surrounding context, pick a match with the one-based `occurrence`, or set `replace_all`.
+use std::{error::Error, path::PathBuf};
+
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+use crate::operations::fs::{
+    history::{self, FileChange},
+    utils::{check_hash, ensure_relative, read_text, write_text, WrittenText},
+};
+
+// Lines of context shown around each edited region in the response snippet
+const SNIPPET_CONTEXT: usize = 3;
+
+// One-based, inclusive start / end lines
+type LineRange = (usize, usize);
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct StrReplaceRequest {
+    pub path: String,
+    pub old_str: String,
+    pub new_str: String,
+    pub occurrence: Option<usize>,
+    #[oai(default)]
+    #[serde(default)]
+    pub replace_all: bool,
+    // Hash from the last read / edit, the edit is rejected if the file has changed since
+    pub expected_hash: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct StrReplaceResponse {
+    pub replacements: usize,
+    // Edited region(s) of the new content with one-based line numbers
+    pub snippet: String,
+    pub hash: String,
+}
+
+fn line_number(content: &str, offset: usize) -> usize {
+    content[..offset].matches('\n').count() + 1
+}
+
+// Render the line ranges with some context, merging ranges that overlap
+fn render_snippet(content: &str, ranges: &[LineRange]) -> String {
+    let lines: Vec<&str> = content.split('\n').collect();
+    let mut windows: Vec<LineRange> = Vec::new();
+    for &(start, end) in ranges {
+        let start = start.saturating_sub(SNIPPET_CONTEXT).max(1);
+        let end = (end + SNIPPET_CONTEXT).min(lines.len());
+        match windows.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+            _ => windows.push((start, end)),
+        }
+    }
+    let width = windows.last().map(|w| w.1.to_string().len()).unwrap_or(1);
+    windows
+        .iter()
+        .map(|&(start, end)| {
+            (start..=end)
+                .map(|n| format!("{:>width$} | {}", n, lines[n - 1], width = width))
+                .collect::<Vec<String>>()
+                .join("\n")
+        })
+        .collect::<Vec<String>>()
+        .join("\n...\n")
+}
+
+impl StrReplaceRequest {
+    pub async fn process(self) -> Result<StrReplaceResponse, Box<dyn Error>> {
+        let path = ensure_relative(PathBuf::from(&self.path)).await?;
+        check_hash(&path, self.expected_hash.as_deref()).await?;
+        let before = history::snapshot(&path);
+        // Match against LF content, the original line endings are restored on write
+        let (content, format) = read_text(&path).await?;
+        let (new_content, ranges) = self.edit(&content)?;
+
+        let WrittenText { content, hash } = write_text(&path, &new_content, &format).await?;
+        history::record("StrReplace", vec![FileChange::new(&path, before)]);
+        Ok(StrReplaceResponse {
+            replacements: ranges.len(),
+            snippet: render_snippet(&content, &ranges),
+            hash,
+        })
+    }
+
+    // Apply the replacement to LF normalized content without touching disk. Also returns the
+    // line range of each replacement in the new content.
+    pub fn edit(&self, content: &str) -> Result<(String, Vec<LineRange>), Box<dyn Error>> {
+        if self.old_str.is_empty() {
+            return Err("old_str must not be empty".into());
+        }
+        let old_str = self.old_str.replace("\r\n", "\n");
+        let new_str = self.new_str.replace("\r\n", "\n");
+
+        let matches: Vec<usize> = content.match_indices(&old_str).map(|(i, _)| i).collect();
+        if matches.is_empty() {
+            let hint = match content.contains(old_str.trim()) && !old_str.trim().is_empty() {
+                true => " It does match when ignoring leading / trailing whitespace, check indentation and newlines.",
+                false => " Read the file again to get its current content.",
+            };
+            return Err(format!("old_str was not found in {:?}.{}", self.path, hint).into());
+        }
+
+        let selected: Vec<usize> = match (self.replace_all, self.occurrence) {
+            (true, _) => matches.clone(),
+            (false, Some(n)) if n >= 1 && n <= matches.len() => vec![matches[n - 1]],
+            (false, Some(n)) => {
+                return Err(format!(
+                    "occurrence {} requested but old_str matches {} time(s)",
+                    n,
+                    matches.len()
+                )
+                .into())
+            }
+            (false, None) if matches.len() == 1 => matches.clone(),
+            (false, None) => {
+                let lines: Vec<String> = matches
+                    .iter()
+                    .map(|&i| line_number(content, i).to_string())
+                    .collect();
+                return Err(format!(
+                    "old_str matches {} times (at lines {}). Include more surrounding context to make it unique, or pass occurrence or replace_all",
+                    matches.len(),
+                    lines.join(", ")
+                )
+                .into());
+            }
+        };
+
+        // Build the new content and remember where each replacement landed in it
+        let mut new_content = String::with_capacity(content.len());
+        let mut ranges = Vec::new();
+        let mut last = 0;
+        for &start in &selected {
+            new_content.push_str(&content[last..start]);
+            let new_start = new_content.len();
+            new_content.push_str(&new_str);
+            let start_line = line_number(&new_content, new_start);
+            let end_line = start_line + new_str.matches('\n').count();
+            ranges.push((start_line, end_line));
+            last = start + old_str.len();
+        }
+        new_content.push_str(&content[last..]);
+        Ok((new_content, ranges))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        dir
+    }
+
+    fn request(old_str: &str, new_str: &str) -> StrReplaceRequest {
+        StrReplaceRequest {
+            path: "test.py".to_string(),
+            old_str: old_str.to_string(),
+            new_str: new_str.to_string(),
+            occurrence: None,
+            replace_all: false,
+            expected_hash: None,
+        }
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_replace_unique(_tmp_dir: TempDir) {
+        fs::write(
+            "test.py",
+            "def foo():\n    return 1\n\ndef bar():\n    return 2\n",
+        )
+        .unwrap();
+        let response = request("    return 1", "    x = 1\n    return x")
+            .process()
+            .await
+            .unwrap();
+        assert_eq!(response.replacements, 1);
+        assert_eq!(
+            fs::read_to_string("test.py").unwrap(),
+            "def foo():\n    x = 1\n    return x\n\ndef bar():\n    return 2\n"
+        );
+        assert_eq!(
+            response.snippet,
+            "1 | def foo():\n2 |     x = 1\n3 |     return x\n4 | \n5 | def bar():\n6 |     return 2"
+        );
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_not_found(_tmp_dir: TempDir) {
+        fs::write("test.py", "def foo():\n    return 1\n").unwrap();
+        let err = request("return 2", "return 3").process().await.unwrap_err();
+        assert!(err.to_string().starts_with("old_str was not found"));
+
+        let err = request("  return 1  ", "return 3")
+            .process()
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("ignoring leading / trailing whitespace"));
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_multiple_matches(_tmp_dir: TempDir) {
+        fs::write("test.py", "x = 1\ny = 2\nx = 1\n").unwrap();
+        let err = request("x = 1", "x = 3").process().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "old_str matches 2 times (at lines 1, 3). Include more surrounding context to make it unique, or pass occurrence or replace_all"
+        );
+
+        let mut req = request("x = 1", "x = 3");
+        req.occurrence = Some(2);
+        req.process().await.unwrap();
+        assert_eq!(
+            fs::read_to_string("test.py").unwrap(),
+            "x = 1\ny = 2\nx = 3\n"
+        );
+
+        let mut req = request("x = ", "z = ");
+        req.replace_all = true;
+        let response = req.process().await.unwrap();
+        assert_eq!(response.replacements, 2);
+        assert_eq!(
+            fs::read_to_string("test.py").unwrap(),
+            "z = 1\ny = 2\nz = 3\n"
+        );
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_preserves_crlf(_tmp_dir: TempDir) {
+        fs::write("test.py", "a = 1\r\nb = 2\r\n").unwrap();
+        request("a = 1\nb = 2", "a = 1\nb = 3")
+            .process()
+            .await
+            .unwrap();
+        assert_eq!(fs::read("test.py").unwrap(), b"a = 1\r\nb = 3\r\n");
+    }
+}
@@ -0,0 +1,259 @@
+//! Step backwards / forwards through the file mutations made by this Agent session.
+//! Undo / Redo act on whole operations, e.g. undoing a MoveFile restores the source file and
+//! removes the destination.
+use std::error::Error;
+
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+use crate::operations::fs::history::{self, HistoryEntry};
+
+fn default_steps() -> usize {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct HistoryItem {
+    pub operation: String,
+    pub description: Option<String>,
+    pub timestamp: String,
+    pub paths: Vec<String>,
+}
+
+impl From<HistoryEntry> for HistoryItem {
+    fn from(entry: HistoryEntry) -> Self {
+        Self {
+            paths: entry.paths(),
+            operation: entry.operation,
+            description: entry.description,
+            timestamp: entry.timestamp.to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct UndoRequest {
+    #[oai(default = "default_steps")]
+    #[serde(default = "default_steps")]
+    pub steps: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct UndoResponse {
+    // Most recent first
+    pub undone: Vec<HistoryItem>,
+    // Why fewer than `steps` were undone, when an entry after the first couldn't be
+    pub error: Option<String>,
+    pub undo_available: usize,
+    pub redo_available: usize,
+    // Older operations dropped from the journal to bound its size, they can't be undone
+    pub evicted: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RedoRequest {
+    #[oai(default = "default_steps")]
+    #[serde(default = "default_steps")]
+    pub steps: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct RedoResponse {
+    pub redone: Vec<HistoryItem>,
+    // Why fewer than `steps` were redone, when an entry after the first couldn't be
+    pub error: Option<String>,
+    pub undo_available: usize,
+    pub redo_available: usize,
+    pub evicted: usize,
+}
+
+impl UndoRequest {
+    pub async fn process(self) -> Result<UndoResponse, Box<dyn Error>> {
+        let (undone, error) = tokio::task::spawn_blocking(move || {
+            history::undo(self.steps).map_err(|e| e.to_string())
+        })
+        .await??;
+        let (undo_available, redo_available) = history::available();
+        Ok(UndoResponse {
+            undone: undone.into_iter().map(HistoryItem::from).collect(),
+            error,
+            undo_available,
+            redo_available,
+            evicted: history::gaps().evicted,
+        })
+    }
+}
+
+impl RedoRequest {
+    pub async fn process(self) -> Result<RedoResponse, Box<dyn Error>> {
+        let (redone, error) = tokio::task::spawn_blocking(move || {
+            history::redo(self.steps).map_err(|e| e.to_string())
+        })
+        .await??;
+        let (undo_available, redo_available) = history::available();
+        Ok(RedoResponse {
+            redone: redone.into_iter().map(HistoryItem::from).collect(),
+            error,
+            undo_available,
+            redo_available,
+            evicted: history::gaps().evicted,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        CreateFileRequest, InsertContentRequest, MoveFileRequest, RemoveFileRequest,
+        ReplaceContentRequest,
+    };
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        history::clear();
+        dir
+    }
+
+    async fn create(path: &str, content: &str) {
+        let request = CreateFileRequest {
+            path: path.to_string(),
+            content: content.to_string(),
+            expected_hash: None,
+        };
+        request.process().await.unwrap();
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_undo_redo_edits(_tmp_dir: TempDir) {
+        create("test.txt", "line1\nline2").await;
+        let request = ReplaceContentRequest {
+            path: "test.txt".to_string(),
+            content: "new line".to_string(),
+            start_line: 1,
+            end_line: None,
+            expected_hash: None,
+        };
+        request.process().await.unwrap();
+        let request = InsertContentRequest {
+            path: "test.txt".to_string(),
+            content: "line3".to_string(),
+            line: 3,
+            expected_hash: None,
+        };
+        request.process().await.unwrap();
+        assert_eq!(
+            fs::read_to_string("test.txt").unwrap(),
+            "new line\nline2\nline3"
+        );
+
+        let response = UndoRequest { steps: 2 }.process().await.unwrap();
+        assert_eq!(response.undone.len(), 2);
+        assert_eq!(response.undone[0].operation, "InsertContent");
+        assert_eq!(response.undone[1].operation, "ReplaceContent");
+        assert_eq!((response.undo_available, response.redo_available), (1, 2));
+        assert_eq!(fs::read_to_string("test.txt").unwrap(), "line1\nline2");
+
+        let response = RedoRequest { steps: 1 }.process().await.unwrap();
+        assert_eq!(response.redone[0].operation, "ReplaceContent");
+        assert_eq!(fs::read_to_string("test.txt").unwrap(), "new line\nline2");
+
+        // Undoing the CreateFile removes the file again
+        UndoRequest { steps: 5 }.process().await.unwrap();
+        assert!(!std::path::Path::new("test.txt").exists());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_undo_move_and_remove(_tmp_dir: TempDir) {
+        create("a.txt", "a").await;
+        let request = MoveFileRequest {
+            src_path: "a.txt".to_string(),
+            dest_path: "b.txt".to_string(),
+        };
+        request.process().await.unwrap();
+        let request = RemoveFileRequest {
+            path: "b.txt".to_string(),
+        };
+        request.process().await.unwrap();
+
+        UndoRequest { steps: 1 }.process().await.unwrap();
+        assert_eq!(fs::read_to_string("b.txt").unwrap(), "a");
+        UndoRequest { steps: 1 }.process().await.unwrap();
+        assert_eq!(fs::read_to_string("a.txt").unwrap(), "a");
+        assert!(!std::path::Path::new("b.txt").exists());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_undo_conflict(_tmp_dir: TempDir) {
+        create("test.txt", "from the LLM").await;
+        // Someone else edits the file after the Agent did
+        fs::write("test.txt", "from a human").unwrap();
+
+        let response = UndoRequest { steps: 1 }.process().await;
+        assert!(response.unwrap_err().to_string().contains("Conflict"));
+        assert_eq!(fs::read_to_string("test.txt").unwrap(), "from a human");
+        assert_eq!(history::available(), (1, 0));
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_undo_partial(_tmp_dir: TempDir) {
+        create("a.txt", "a").await;
+        create("b.txt", "b").await;
+        fs::write("a.txt", "from a human").unwrap();
+
+        let response = UndoRequest { steps: 2 }.process().await.unwrap();
+        assert_eq!(response.undone.len(), 1);
+        assert!(response.error.unwrap().contains("Conflict"));
+        assert_eq!((response.undo_available, response.redo_available), (1, 1));
+        assert!(!std::path::Path::new("b.txt").exists());
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_undo_keeps_mode(_tmp_dir: TempDir) {
+        use std::os::unix::fs::PermissionsExt;
+
+        create("run.sh", "echo hi").await;
+        fs::set_permissions("run.sh", fs::Permissions::from_mode(0o755)).unwrap();
+        let request = RemoveFileRequest {
+            path: "run.sh".to_string(),
+        };
+        request.process().await.unwrap();
+
+        UndoRequest { steps: 1 }.process().await.unwrap();
+        let mode = fs::metadata("run.sh").unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_undo_per_session(_tmp_dir: TempDir) {
+        history::set_session(Some("first"));
+        create("a.txt", "a").await;
+        history::set_session(Some("second"));
+        assert_eq!(history::available(), (0, 0));
+        let response = UndoRequest { steps: 1 }.process().await.unwrap();
+        assert!(response.undone.is_empty());
+        assert!(std::path::Path::new("a.txt").exists());
+
+        history::set_session(Some("first"));
+        assert_eq!(history::available(), (1, 0));
+        history::set_session(None);
+    }
+}
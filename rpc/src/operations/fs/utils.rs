@@ -1,31 +1,404 @@
-use std::{error::Error, path::PathBuf};
+use std::{
+    error::Error,
+    fmt, fs,
+    io::{self, Write},
+    os::unix::{
+        fs::{MetadataExt, PermissionsExt},
+        io::AsRawFd,
+    },
+    path::{Component, Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
 
 pub async fn ensure_relative(path: PathBuf) -> Result<PathBuf, Box<dyn Error>> {
-    match path.is_relative() {
-        true => Ok(path),
+    let path = match path.is_relative() {
+        true => path,
         false => match path.strip_prefix(std::env::current_dir()?) {
-            Ok(relative_path) => Ok(relative_path.to_path_buf()),
-            Err(_) => Err("Path must be a sub-directory of the current working directory".into()),
+            Ok(relative_path) => relative_path.to_path_buf(),
+            Err(_) => {
+                return Err("Path must be a sub-directory of the current working directory".into())
+            }
         },
+    };
+    normalize(&path)
+}
+
+// Drop `.` components, so `./a.py` and `a.py` are the same path and the workspace root is
+// always `.`. `..` is rejected rather than resolved: it could climb out of the workspace, or
+// make a path like `src/..` name the workspace root without looking like it.
+fn normalize(path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                return Err(format!("Path {:?} must not contain `..`", path).into())
+            }
+            component => normalized.push(component),
+        }
+    }
+    match normalized.as_os_str().is_empty() {
+        true => Ok(PathBuf::from(".")),
+        false => Ok(normalized),
+    }
+}
+
+// Hidden directory in the workspace where the Agent keeps its own state, e.g. checkpoints
+pub const AGENT_DIR: &str = ".carabiner";
+
+// Create a directory for Agent state. The Agent directory ignores itself, so its state never
+// shows up in git status or gets committed along with the workspace.
+pub fn create_agent_dir(name: &str) -> io::Result<PathBuf> {
+    let path = Path::new(AGENT_DIR).join(name);
+    fs::create_dir_all(&path)?;
+    let gitignore = Path::new(AGENT_DIR).join(".gitignore");
+    if !gitignore.exists() {
+        fs::write(gitignore, "*\n")?;
+    }
+    Ok(path)
+}
+
+// Paths the LLM can't bulk copy over or delete, no matter what the request flags say
+const PROTECTED_NAMES: [&str; 2] = [".git", AGENT_DIR];
+
+pub fn ensure_not_protected(path: &Path) -> Result<(), Box<dyn Error>> {
+    if normalize(path)? == Path::new(".") {
+        return Err("Operation not allowed on the workspace root".into());
+    }
+    for component in path.components() {
+        if let Component::Normal(name) = component {
+            if PROTECTED_NAMES.iter().any(|p| name == *p) {
+                return Err(format!("Operation not allowed on protected path {:?}", path).into());
+            }
+        }
     }
+    Ok(())
 }
 
-pub async fn read_lines(path: &PathBuf) -> Result<Vec<String>, Box<dyn Error>> {
+// Error if the workspace relative path goes through a symlink, which could point anywhere.
+// Components that don't exist (yet) end the check.
+pub fn ensure_not_symlinked(path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut current = PathBuf::new();
+    for component in path.components() {
+        current.push(component);
+        match fs::symlink_metadata(&current) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                return Err(format!("{:?} goes through the symlink {:?}", path, current).into())
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    Ok(())
+}
+
+// Read a text file as LF normalized lines, along with the format it was stored in so edits
+// can be written back the same way
+pub async fn read_lines(path: &Path) -> Result<(Vec<String>, TextFormat), Box<dyn Error>> {
+    let (content, format) = read_text(path).await?;
+    Ok((split_lines(&content), format))
+}
+
+// Read a text file as LF normalized content, along with the format it was stored in
+pub async fn read_text(path: &Path) -> Result<(String, TextFormat), Box<dyn Error>> {
     // Bubble up exception if file isn't found
-    let content = tokio::fs::read_to_string(path).await?;
+    let bytes = tokio::fs::read(path).await?;
+    decode_text(&bytes)
+}
 
+// What read_text does with the bytes, for callers that need the raw bytes too
+pub fn decode_text(bytes: &[u8]) -> Result<(String, TextFormat), Box<dyn Error>> {
+    let format = TextFormat::detect(bytes);
+    let content = format.decode(bytes)?;
+    Ok((content, format))
+}
+
+pub fn split_lines(content: &str) -> Vec<String> {
     // Gotcha here: .lines() will strip trailing \n so foo\nbar\nbaz is the same as foo\nbar\nbaz\n
     let has_trailing_newline = content.ends_with('\n');
     let mut lines: Vec<String> = content.lines().map(|l| l.to_string()).collect();
     if has_trailing_newline {
         lines.push("".into());
     }
-    Ok(lines)
+    lines
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+// UTF-16 files are only recognized by their BOM, anything else is treated as UTF-8
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+
+// Formatting details of an existing text file that edits should carry over, so that editing
+// one line of a CRLF file doesn't rewrite every line of it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextFormat {
+    pub encoding: TextEncoding,
+    pub line_ending: LineEnding,
+    pub bom: bool,
+    pub final_newline: bool,
+}
+
+impl Default for TextFormat {
+    fn default() -> Self {
+        Self {
+            encoding: TextEncoding::Utf8,
+            line_ending: LineEnding::Lf,
+            bom: false,
+            final_newline: true,
+        }
+    }
+}
+
+fn decode_utf16(bytes: &[u8], from_bytes: fn([u8; 2]) -> u16) -> Result<String, Box<dyn Error>> {
+    if bytes.len() % 2 != 0 {
+        return Err("Invalid UTF-16 content, odd number of bytes".into());
+    }
+    let units = bytes.chunks(2).map(|c| from_bytes([c[0], c[1]]));
+    Ok(char::decode_utf16(units).collect::<Result<String, _>>()?)
+}
+
+impl TextFormat {
+    pub fn detect(content: &[u8]) -> Self {
+        let (encoding, bom) = if content.starts_with(&[0xFF, 0xFE]) {
+            (TextEncoding::Utf16Le, true)
+        } else if content.starts_with(&[0xFE, 0xFF]) {
+            (TextEncoding::Utf16Be, true)
+        } else {
+            (TextEncoding::Utf8, content.starts_with(&[0xEF, 0xBB, 0xBF]))
+        };
+        let text = match encoding {
+            TextEncoding::Utf8 => String::from_utf8_lossy(content),
+            _ => Self {
+                encoding,
+                ..Self::default()
+            }
+            .decode_raw(content)
+            .unwrap_or_default()
+            .into(),
+        };
+        let crlf = text.matches("\r\n").count();
+        let lf = text.matches('\n').count();
+        Self {
+            encoding,
+            // Mixed files go with whichever style is more common
+            line_ending: match crlf > lf - crlf {
+                true => LineEnding::CrLf,
+                false => LineEnding::Lf,
+            },
+            bom,
+            final_newline: text.is_empty() || text.ends_with('\n'),
+        }
+    }
+
+    fn decode_raw(&self, content: &[u8]) -> Result<String, Box<dyn Error>> {
+        match self.encoding {
+            TextEncoding::Utf8 => Ok(String::from_utf8(content.to_vec())?),
+            TextEncoding::Utf16Le => decode_utf16(content, u16::from_le_bytes),
+            TextEncoding::Utf16Be => decode_utf16(content, u16::from_be_bytes),
+        }
+    }
+
+    // Decode bytes read from disk into normalized content
+    pub fn decode(&self, content: &[u8]) -> Result<String, Box<dyn Error>> {
+        Ok(self.normalize(&self.decode_raw(content)?))
+    }
+
+    // Normalize edited content to LF / no BOM, with the final newline matching the original
+    pub fn normalize(&self, content: &str) -> String {
+        let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+        let mut content = content.replace("\r\n", "\n");
+        if self.final_newline && !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        } else if !self.final_newline && content.ends_with('\n') {
+            content.pop();
+        }
+        content
+    }
+
+    // Turn normalized content into the bytes to write to disk
+    pub fn encode(&self, content: &str) -> Vec<u8> {
+        let content = match self.line_ending {
+            LineEnding::Lf => content.to_string(),
+            LineEnding::CrLf => content.replace('\n', "\r\n"),
+        };
+        let mut bytes = Vec::with_capacity(content.len() + 3);
+        match self.encoding {
+            TextEncoding::Utf8 => {
+                if self.bom {
+                    bytes.extend_from_slice(&[0xEF, 0xBB, 0xBF]);
+                }
+                bytes.extend_from_slice(content.as_bytes());
+            }
+            TextEncoding::Utf16Le => {
+                bytes.extend_from_slice(&[0xFF, 0xFE]);
+                content
+                    .encode_utf16()
+                    .for_each(|u| bytes.extend_from_slice(&u.to_le_bytes()));
+            }
+            TextEncoding::Utf16Be => {
+                bytes.extend_from_slice(&[0xFE, 0xFF]);
+                content
+                    .encode_utf16()
+                    .for_each(|u| bytes.extend_from_slice(&u.to_be_bytes()));
+            }
+        }
+        bytes
+    }
+}
+
+// Write to a temp file in the same directory, fsync it, and rename it over the original so a
+// crash mid-write never leaves a truncated file behind. Existing files keep their mode and
+// ownership. If the path is a symlink, the file it points to is replaced instead of the link.
+pub fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    let path = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => fs::canonicalize(path)?,
+        _ => path.to_path_buf(),
+    };
+    let original = fs::metadata(&path).ok();
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path has no file name"))?;
+    let tmp_path = dir.join(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        uuid::Uuid::new_v4()
+    ));
+
+    let write_tmp = || -> io::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+        file.write_all(content)?;
+        if let Some(original) = &original {
+            // Changing owner needs privileges the Agent usually doesn't have, and the file
+            // is already owned by us in the common case, so this is best-effort
+            unsafe { libc::fchown(file.as_raw_fd(), original.uid(), original.gid()) };
+            // chown can clear setuid / setgid bits, so set the mode afterwards
+            file.set_permissions(fs::Permissions::from_mode(original.mode()))?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)
+    };
+    if let Err(e) = write_tmp() {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    // Make sure the rename itself is durable
+    fs::File::open(&dir)?.sync_all()
+}
+
+pub struct WrittenText {
+    // Normalized (LF, no BOM) content
+    pub content: String,
+    // Hash of the bytes that were actually written to disk
+    pub hash: String,
+}
+
+// Shared write path for the text edit operations. Edited content is written atomically, in the
+// encoding / line ending / BOM / final newline style the original was read with.
+pub async fn write_text(
+    path: &Path,
+    content: &str,
+    format: &TextFormat,
+) -> Result<WrittenText, Box<dyn Error>> {
+    let content = format.normalize(content);
+    let bytes = format.encode(&content);
+    let path = path.to_path_buf();
+    let hash = content_hash(&bytes);
+    tokio::task::spawn_blocking(move || write_atomic(&path, &bytes)).await??;
+    Ok(WrittenText { content, hash })
+}
+
+// Hex sha256 of a file's raw bytes. Returned by reads and edits so the LLM can pass it back
+// as `expected_hash` on its next edit.
+pub fn content_hash(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+#[derive(Debug)]
+pub struct HashConflict {
+    pub path: PathBuf,
+    pub expected: String,
+    // None if the file no longer exists
+    pub current: Option<String>,
+}
+
+impl fmt::Display for HashConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.current {
+            Some(current) => write!(
+                f,
+                "Conflict: {:?} has changed since it was read (expected hash {}, current hash {}). Read the file again before editing it",
+                self.path, self.expected, current
+            ),
+            None => write!(
+                f,
+                "Conflict: {:?} no longer exists (expected hash {})",
+                self.path, self.expected
+            ),
+        }
+    }
+}
+
+impl Error for HashConflict {}
+
+// Optimistic concurrency check for edits. If the LLM sent the hash it got from its last read,
+// refuse to edit a file that someone else has changed in the meantime.
+pub async fn check_hash(path: &Path, expected: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let expected = match expected {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+    let current = match tokio::fs::read(path).await {
+        Ok(content) => Some(content_hash(&content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    if current.as_deref() != Some(expected) {
+        return Err(Box::new(HashConflict {
+            path: path.to_path_buf(),
+            expected: expected.to_string(),
+            current,
+        }));
+    }
+    Ok(())
+}
+
+// Best-effort guess at a file's text encoding from its raw bytes
+pub fn detect_encoding(content: &[u8]) -> &'static str {
+    if content.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        "utf-8-bom"
+    } else if content.starts_with(&[0xFF, 0xFE]) {
+        "utf-16le"
+    } else if content.starts_with(&[0xFE, 0xFF]) {
+        "utf-16be"
+    } else if content.contains(&0) {
+        "binary"
+    } else if std::str::from_utf8(content).is_ok() {
+        "utf-8"
+    } else {
+        "unknown"
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{fs::File, io::Write};
+    use std::fs::File;
 
     use tempfile::TempDir;
 
@@ -51,6 +424,17 @@ mod tests {
             unhappy_path.unwrap_err().to_string(),
             "Path must be a sub-directory of the current working directory"
         );
+
+        let path = ensure_relative(PathBuf::from("./src/./main.rs"))
+            .await
+            .unwrap();
+        assert_eq!(path, PathBuf::from("src/main.rs"));
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(ensure_relative(cwd).await.unwrap(), PathBuf::from("."));
+        for path in ["..", "src/..", "src/../../etc/passwd"] {
+            assert!(ensure_relative(PathBuf::from(path)).await.is_err());
+        }
+        assert!(ensure_not_protected(Path::new("./")).is_err());
     }
 
     #[rstest::rstest]
@@ -60,7 +444,7 @@ mod tests {
         let mut f = File::create("test.txt").unwrap();
         f.write_all(b"line1\nline2\nline3").unwrap();
         let path = PathBuf::from("test.txt");
-        let lines = read_lines(&path).await.unwrap();
+        let (lines, _) = read_lines(&path).await.unwrap();
         assert_eq!(lines.len(), 3);
         assert_eq!(lines[0], "line1");
         assert_eq!(lines[2], "line3")
@@ -73,7 +457,7 @@ mod tests {
         let mut f = File::create("test.txt").unwrap();
         f.write_all(b"line1\nline2\nline3\n\n").unwrap();
         let path = PathBuf::from("test.txt");
-        let lines = read_lines(&path).await.unwrap();
+        let (lines, _) = read_lines(&path).await.unwrap();
         assert_eq!(lines.len(), 5);
         assert_eq!(lines[2], "line3");
         assert_eq!(lines[3], "");
@@ -87,9 +471,116 @@ mod tests {
         let mut f = File::create("test.txt").unwrap();
         f.write_all(b"  line1\nline2  \n  line3  ").unwrap();
         let path = PathBuf::from("test.txt");
-        let lines = read_lines(&path).await.unwrap();
+        let (lines, _) = read_lines(&path).await.unwrap();
         assert_eq!(lines[0], "  line1");
         assert_eq!(lines[1], "line2  ");
         assert_eq!(lines[2], "  line3  ");
     }
+
+    #[test]
+    fn test_ensure_not_protected() {
+        assert!(ensure_not_protected(Path::new("src/main.rs")).is_ok());
+        assert!(ensure_not_protected(Path::new(".github")).is_ok());
+        assert!(ensure_not_protected(Path::new("")).is_err());
+        assert!(ensure_not_protected(Path::new(".")).is_err());
+        assert!(ensure_not_protected(Path::new(".git")).is_err());
+        assert!(ensure_not_protected(Path::new("sub/.git/config")).is_err());
+    }
+
+    #[test]
+    fn test_text_format_detect() {
+        let format = TextFormat::detect(b"\xEF\xBB\xBFa\r\nb\r\nc");
+        assert_eq!(format.line_ending, LineEnding::CrLf);
+        assert!(format.bom);
+        assert!(!format.final_newline);
+
+        let format = TextFormat::detect(b"a\nb\r\nc\n");
+        assert_eq!(format.line_ending, LineEnding::Lf);
+        assert!(!format.bom);
+        assert!(format.final_newline);
+    }
+
+    #[rstest::rstest]
+    #[case(TextEncoding::Utf16Le, b"\xFF\xFEa\x00\r\x00\n\x00b\x00".to_vec())]
+    #[case(TextEncoding::Utf16Be, b"\xFE\xFF\x00a\x00\r\x00\n\x00b".to_vec())]
+    fn test_text_format_utf16(#[case] encoding: TextEncoding, #[case] bytes: Vec<u8>) {
+        let format = TextFormat::detect(&bytes);
+        assert_eq!(format.encoding, encoding);
+        assert_eq!(format.line_ending, LineEnding::CrLf);
+        assert!(!format.final_newline);
+        assert_eq!(format.decode(&bytes).unwrap(), "a\nb");
+        // Round trips byte for byte
+        assert_eq!(format.encode("a\nb"), bytes);
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_write_text_preserves_format(_tmp_dir: TempDir) {
+        fs::write("test.txt", b"\xEF\xBB\xBFline1\r\nline2\r\n").unwrap();
+        let (_, format) = read_text(Path::new("test.txt")).await.unwrap();
+        let written = write_text(Path::new("test.txt"), "\u{feff}line1\nnew line", &format)
+            .await
+            .unwrap();
+        assert_eq!(written.content, "line1\nnew line\n");
+        let on_disk = fs::read("test.txt").unwrap();
+        assert_eq!(on_disk, b"\xEF\xBB\xBFline1\r\nnew line\r\n");
+        assert_eq!(written.hash, content_hash(&on_disk));
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_write_atomic_preserves_mode(_tmp_dir: TempDir) {
+        fs::write("script.sh", "echo hi").unwrap();
+        fs::set_permissions("script.sh", fs::Permissions::from_mode(0o755)).unwrap();
+        write_atomic(Path::new("script.sh"), b"echo bye").unwrap();
+        let meta = fs::metadata("script.sh").unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o755);
+        assert_eq!(fs::read_to_string("script.sh").unwrap(), "echo bye");
+        // No temp files left behind
+        assert_eq!(fs::read_dir(".").unwrap().count(), 1);
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_write_atomic_through_symlink(_tmp_dir: TempDir) {
+        fs::write("target.txt", "old").unwrap();
+        std::os::unix::fs::symlink("target.txt", "link.txt").unwrap();
+        write_atomic(Path::new("link.txt"), b"new").unwrap();
+        assert!(fs::symlink_metadata("link.txt")
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string("target.txt").unwrap(), "new");
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_check_hash(_tmp_dir: TempDir) {
+        fs::write("test.txt", "content").unwrap();
+        let path = Path::new("test.txt");
+        let hash = content_hash(b"content");
+        assert!(check_hash(path, None).await.is_ok());
+        assert!(check_hash(path, Some(&hash)).await.is_ok());
+
+        let err = check_hash(path, Some("stale")).await.unwrap_err();
+        assert!(err.to_string().contains(&format!("current hash {}", hash)));
+
+        fs::remove_file(path).unwrap();
+        let err = check_hash(path, Some(&hash)).await.unwrap_err();
+        assert!(err.to_string().contains("no longer exists"));
+    }
+
+    #[test]
+    fn test_detect_encoding() {
+        assert_eq!(detect_encoding(b"plain ascii"), "utf-8");
+        assert_eq!(detect_encoding("caf\u{e9}".as_bytes()), "utf-8");
+        assert_eq!(detect_encoding(b"\xEF\xBB\xBFbom"), "utf-8-bom");
+        assert_eq!(detect_encoding(b"\xFF\xFEh\x00"), "utf-16le");
+        assert_eq!(detect_encoding(b"\x7FELF\x00\x01"), "binary");
+        assert_eq!(detect_encoding(b"caf\xE9"), "unknown");
+    }
 }
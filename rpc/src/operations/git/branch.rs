@@ -0,0 +1,125 @@
+use std::error::Error;
+
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+use crate::operations::git::utils::{current_branch, ensure_not_option, run_git};
+
+const BRANCH_FORMAT: &str = "--format=%(refname:short)%1f%(objectname)%1f%(upstream:short)";
+
+// Lists local branches, optionally creating or deleting one first
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct GitBranchRequest {
+    pub create: Option<String>,
+    // Where a created branch starts, HEAD if not set
+    pub start_point: Option<String>,
+    pub delete: Option<String>,
+    // Delete the branch even if it isn't merged
+    #[oai(default)]
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct GitBranchInfo {
+    pub name: String,
+    pub hash: String,
+    pub upstream: Option<String>,
+    pub current: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct GitBranchResponse {
+    pub current: Option<String>,
+    pub branches: Vec<GitBranchInfo>,
+}
+
+impl GitBranchRequest {
+    pub async fn process(self) -> Result<GitBranchResponse, Box<dyn Error>> {
+        if let Some(name) = &self.create {
+            ensure_not_option(name)?;
+            let mut args = vec!["branch", "--", name.as_str()];
+            if let Some(start_point) = &self.start_point {
+                ensure_not_option(start_point)?;
+                args.push(start_point);
+            }
+            run_git(&args).await?;
+        }
+        if let Some(name) = &self.delete {
+            ensure_not_option(name)?;
+            let flag = match self.force {
+                true => "-D",
+                false => "-d",
+            };
+            run_git(&["branch", flag, "--", name]).await?;
+        }
+
+        let current = current_branch().await;
+        let output = run_git(&["for-each-ref", BRANCH_FORMAT, "refs/heads"]).await?;
+        let branches = output
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split('\x1f');
+                let name = fields.next()?.to_string();
+                let hash = fields.next()?.to_string();
+                let upstream = fields.next().filter(|u| !u.is_empty()).map(String::from);
+                Some(GitBranchInfo {
+                    current: current.as_ref() == Some(&name),
+                    name,
+                    hash,
+                    upstream,
+                })
+            })
+            .collect();
+        Ok(GitBranchResponse { current, branches })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::operations::git::utils::init_repo;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        dir
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_branch(_tmp_dir: TempDir) {
+        init_repo().await;
+        fs::write("a.txt", "a").unwrap();
+        run_git(&["add", "a.txt"]).await.unwrap();
+        run_git(&["commit", "-q", "-m", "init"]).await.unwrap();
+
+        let request = GitBranchRequest {
+            create: Some("feature".to_string()),
+            start_point: None,
+            delete: None,
+            force: false,
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.current.as_deref(), Some("main"));
+        let names: Vec<&str> = response.branches.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, vec!["feature", "main"]);
+        assert!(response.branches[1].current);
+        assert_eq!(response.branches[0].hash, response.branches[1].hash);
+
+        let request = GitBranchRequest {
+            create: None,
+            start_point: None,
+            delete: Some("feature".to_string()),
+            force: false,
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.branches.len(), 1);
+    }
+}
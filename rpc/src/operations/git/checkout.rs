@@ -0,0 +1,94 @@
+use std::error::Error;
+
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+use crate::operations::git::utils::{current_branch, ensure_not_option, head_hash, run_git};
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct GitCheckoutRequest {
+    // Branch, tag or commit to switch to, or the name of the branch to create
+    pub target: String,
+    #[oai(default)]
+    #[serde(default)]
+    pub create: bool,
+    // Where a created branch starts, HEAD if not set
+    pub start_point: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct GitCheckoutResponse {
+    // None when the checkout left HEAD detached
+    pub branch: Option<String>,
+    pub head: String,
+}
+
+impl GitCheckoutRequest {
+    pub async fn process(self) -> Result<GitCheckoutResponse, Box<dyn Error>> {
+        ensure_not_option(&self.target)?;
+        let mut args = vec!["checkout", "--quiet"];
+        if self.create {
+            args.extend(["-b", self.target.as_str()]);
+            if let Some(start_point) = &self.start_point {
+                ensure_not_option(start_point)?;
+                args.push(start_point);
+            }
+        } else {
+            // Trailing -- so the target is never taken as a path to restore
+            args.extend([self.target.as_str(), "--"]);
+        }
+        run_git(&args).await?;
+        Ok(GitCheckoutResponse {
+            branch: current_branch().await,
+            head: head_hash().await?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::operations::git::utils::init_repo;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        dir
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_checkout(_tmp_dir: TempDir) {
+        init_repo().await;
+        fs::write("a.txt", "main").unwrap();
+        run_git(&["add", "a.txt"]).await.unwrap();
+        run_git(&["commit", "-q", "-m", "init"]).await.unwrap();
+        let first = head_hash().await.unwrap();
+
+        let request = GitCheckoutRequest {
+            target: "feature".to_string(),
+            create: true,
+            start_point: None,
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.branch.as_deref(), Some("feature"));
+        fs::write("a.txt", "feature").unwrap();
+        run_git(&["commit", "-q", "-am", "change"]).await.unwrap();
+
+        let request = GitCheckoutRequest {
+            target: first.clone(),
+            create: false,
+            start_point: None,
+        };
+        let response = request.process().await.unwrap();
+        assert_eq!(response.branch, None);
+        assert_eq!(response.head, first);
+        assert_eq!(fs::read_to_string("a.txt").unwrap(), "main");
+    }
+}
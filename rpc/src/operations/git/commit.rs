@@ -0,0 +1,132 @@
+use std::error::Error;
+
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+use crate::operations::git::utils::{current_branch, head_hash, pathspecs, run_git};
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct GitCommitRequest {
+    pub message: String,
+    // Stage these paths before committing
+    #[oai(default)]
+    #[serde(default)]
+    pub paths: Vec<String>,
+    // Stage every change in the working tree, including new and deleted files
+    #[oai(default)]
+    #[serde(default)]
+    pub all: bool,
+    // Identity to commit as, the repository's configured user if not set
+    pub author_name: Option<String>,
+    pub author_email: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct GitCommitResponse {
+    pub hash: String,
+    pub branch: Option<String>,
+    pub files: Vec<String>,
+}
+
+impl GitCommitRequest {
+    pub async fn process(self) -> Result<GitCommitResponse, Box<dyn Error>> {
+        if self.message.trim().is_empty() {
+            return Err("Commit message can't be empty".into());
+        }
+        if self.all {
+            run_git(&["add", "--all"]).await?;
+        }
+        let specs = pathspecs(&self.paths).await?;
+        if !specs.is_empty() {
+            let mut args = vec!["add", "--all", "--"];
+            args.extend(specs.iter().map(|s| s.as_str()));
+            run_git(&args).await?;
+        }
+
+        let mut identity = Vec::new();
+        if let Some(name) = &self.author_name {
+            identity.push(format!("user.name={}", name));
+        }
+        if let Some(email) = &self.author_email {
+            identity.push(format!("user.email={}", email));
+        }
+        let mut args = Vec::new();
+        for setting in &identity {
+            args.extend(["-c", setting.as_str()]);
+        }
+        args.extend(["commit", "--quiet", "-m", self.message.as_str()]);
+        run_git(&args).await?;
+
+        let hash = head_hash().await?;
+        let files = run_git(&[
+            "diff-tree",
+            "--no-commit-id",
+            "--name-only",
+            "-r",
+            "--root",
+            "-z",
+            "HEAD",
+        ])
+        .await?;
+        Ok(GitCommitResponse {
+            hash,
+            branch: current_branch().await,
+            files: files
+                .split('\0')
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_string())
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::operations::git::utils::init_repo;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        dir
+    }
+
+    fn commit_request(paths: Vec<String>, all: bool) -> GitCommitRequest {
+        GitCommitRequest {
+            message: "Add files".to_string(),
+            paths,
+            all,
+            author_name: Some("Bot".to_string()),
+            author_email: Some("bot@example.com".to_string()),
+        }
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_commit(_tmp_dir: TempDir) {
+        init_repo().await;
+        fs::write("a.txt", "a").unwrap();
+        fs::write("b.txt", "b").unwrap();
+
+        let response = commit_request(vec!["a.txt".to_string()], false)
+            .process()
+            .await
+            .unwrap();
+        assert_eq!(response.files, vec!["a.txt"]);
+        assert_eq!(response.branch.as_deref(), Some("main"));
+        let author = run_git(&["log", "-1", "--format=%an <%ae>"]).await.unwrap();
+        assert_eq!(author.trim(), "Bot <bot@example.com>");
+
+        let response = commit_request(vec![], true).process().await.unwrap();
+        assert_eq!(response.files, vec!["b.txt"]);
+
+        let error = commit_request(vec![], true).process().await.unwrap_err();
+        assert!(error.to_string().contains("nothing to commit"));
+    }
+}
@@ -0,0 +1,145 @@
+use std::error::Error;
+
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+use crate::operations::git::utils::{ensure_not_option, pathspecs, run_git};
+
+fn default_context_lines() -> usize {
+    3
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct GitDiffRequest {
+    #[oai(default)]
+    #[serde(default)]
+    pub paths: Vec<String>,
+    // Diff the index against HEAD instead of the working tree against the index
+    #[oai(default)]
+    #[serde(default)]
+    pub staged: bool,
+    // Compare against a revision ("HEAD~1") or between two ("main..feature") instead
+    pub revision: Option<String>,
+    #[oai(default = "default_context_lines")]
+    #[serde(default = "default_context_lines")]
+    pub context_lines: usize,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct GitDiffFile {
+    pub path: String,
+    // None for binary files
+    pub additions: Option<usize>,
+    pub deletions: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct GitDiffResponse {
+    pub diff: String,
+    pub files: Vec<GitDiffFile>,
+}
+
+// Parse `git diff --numstat -z`, renames show up as an empty path followed by old and new paths
+fn parse_numstat(output: &str) -> Vec<GitDiffFile> {
+    let mut files = Vec::new();
+    let mut records = output.split('\0');
+    while let Some(record) = records.next() {
+        let mut parts = record.splitn(3, '\t');
+        let (Some(additions), Some(deletions), Some(path)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        let path = match path {
+            "" => {
+                records.next();
+                records.next().unwrap_or_default().to_string()
+            }
+            path => path.to_string(),
+        };
+        files.push(GitDiffFile {
+            path,
+            additions: additions.parse().ok(),
+            deletions: deletions.parse().ok(),
+        });
+    }
+    files
+}
+
+impl GitDiffRequest {
+    pub async fn process(self) -> Result<GitDiffResponse, Box<dyn Error>> {
+        let specs = pathspecs(&self.paths).await?;
+        let context = format!("-U{}", self.context_lines);
+        let mut args = vec!["diff", "--no-color", "--no-ext-diff"];
+        if self.staged {
+            args.push("--cached");
+        }
+        if let Some(revision) = &self.revision {
+            ensure_not_option(revision)?;
+            args.push(revision);
+        }
+
+        let mut diff_args = args.clone();
+        diff_args.extend([context.as_str(), "--"]);
+        diff_args.extend(specs.iter().map(|s| s.as_str()));
+        let diff = run_git(&diff_args).await?;
+
+        args.extend(["--numstat", "-z", "--"]);
+        args.extend(specs.iter().map(|s| s.as_str()));
+        let files = parse_numstat(&run_git(&args).await?);
+        Ok(GitDiffResponse { diff, files })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::operations::git::utils::init_repo;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        dir
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_diff(_tmp_dir: TempDir) {
+        init_repo().await;
+        fs::write("a.txt", "one\ntwo\n").unwrap();
+        run_git(&["add", "a.txt"]).await.unwrap();
+        run_git(&["commit", "-q", "-m", "init"]).await.unwrap();
+        fs::write("a.txt", "one\n2\nthree\n").unwrap();
+
+        let request = GitDiffRequest {
+            paths: vec![],
+            staged: false,
+            revision: None,
+            context_lines: default_context_lines(),
+        };
+        let response = request.process().await.unwrap();
+        assert!(response.diff.contains("-two\n+2\n+three\n"));
+        assert_eq!(
+            response.files,
+            vec![GitDiffFile {
+                path: "a.txt".to_string(),
+                additions: Some(2),
+                deletions: Some(1),
+            }]
+        );
+
+        let request = GitDiffRequest {
+            paths: vec![],
+            staged: true,
+            revision: Some("--output=/tmp/pwned".to_string()),
+            context_lines: default_context_lines(),
+        };
+        assert!(request.process().await.is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use std::error::Error;
+
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+use crate::operations::git::utils::{ensure_not_option, pathspecs, run_git};
+
+fn default_max_count() -> usize {
+    20
+}
+
+// Fields are split by unit separators and commits by record separators, which can't show up in
+// a commit message by accident
+const LOG_FORMAT: &str = "--format=%H%x1f%an%x1f%ae%x1f%aI%x1f%s%x1f%b%x1e";
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct GitLogRequest {
+    // Only commits touching these paths
+    #[oai(default)]
+    #[serde(default)]
+    pub paths: Vec<String>,
+    // Branch, tag or range to list, HEAD if not set
+    pub revision: Option<String>,
+    #[oai(default = "default_max_count")]
+    #[serde(default = "default_max_count")]
+    pub max_count: usize,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct GitCommitInfo {
+    pub hash: String,
+    pub author_name: String,
+    pub author_email: String,
+    // RFC 3339
+    pub date: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct GitLogResponse {
+    pub commits: Vec<GitCommitInfo>,
+}
+
+fn parse_log(output: &str) -> Vec<GitCommitInfo> {
+    output
+        .split('\x1e')
+        .filter_map(|record| {
+            let fields: Vec<&str> = record.trim_start_matches('\n').split('\x1f').collect();
+            match fields[..] {
+                [hash, author_name, author_email, date, subject, body] => Some(GitCommitInfo {
+                    hash: hash.to_string(),
+                    author_name: author_name.to_string(),
+                    author_email: author_email.to_string(),
+                    date: date.to_string(),
+                    subject: subject.to_string(),
+                    body: body.trim_end().to_string(),
+                }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+impl GitLogRequest {
+    pub async fn process(self) -> Result<GitLogResponse, Box<dyn Error>> {
+        let specs = pathspecs(&self.paths).await?;
+        let max_count = format!("--max-count={}", self.max_count);
+        let mut args = vec!["log", LOG_FORMAT, max_count.as_str()];
+        if let Some(revision) = &self.revision {
+            ensure_not_option(revision)?;
+            args.push(revision);
+        }
+        args.push("--");
+        args.extend(specs.iter().map(|s| s.as_str()));
+        let output = run_git(&args).await?;
+        Ok(GitLogResponse {
+            commits: parse_log(&output),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::operations::git::utils::init_repo;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        dir
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_log(_tmp_dir: TempDir) {
+        init_repo().await;
+        for (i, message) in ["First", "Second\n\nWith a body\nover two lines"]
+            .iter()
+            .enumerate()
+        {
+            fs::write("a.txt", i.to_string()).unwrap();
+            run_git(&["add", "a.txt"]).await.unwrap();
+            run_git(&["commit", "-q", "-m", message]).await.unwrap();
+        }
+        let request = GitLogRequest {
+            paths: vec![],
+            revision: None,
+            max_count: default_max_count(),
+        };
+        let commits = request.process().await.unwrap().commits;
+        assert_eq!(commits.len(), 2);
+        assert_eq!(commits[0].subject, "Second");
+        assert_eq!(commits[0].body, "With a body\nover two lines");
+        assert_eq!(commits[0].author_email, "test@example.com");
+        assert_eq!(commits[1].subject, "First");
+        assert_eq!(commits[1].body, "");
+        assert_eq!(commits[1].hash.len(), 40);
+    }
+}
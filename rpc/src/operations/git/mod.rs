@@ -0,0 +1,7 @@
+pub mod branch;
+pub mod checkout;
+pub mod commit;
+pub mod diff;
+pub mod log;
+pub mod status;
+pub mod utils;
@@ -0,0 +1,229 @@
+use std::error::Error;
+
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+use crate::operations::git::utils::{pathspecs, run_git};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum GitFileStatus {
+    Unmodified,
+    Modified,
+    TypeChanged,
+    Added,
+    Deleted,
+    Renamed,
+    Copied,
+    Unmerged,
+    Untracked,
+    Ignored,
+}
+
+impl GitFileStatus {
+    fn from_code(code: char) -> Self {
+        match code {
+            'M' => GitFileStatus::Modified,
+            'T' => GitFileStatus::TypeChanged,
+            'A' => GitFileStatus::Added,
+            'D' => GitFileStatus::Deleted,
+            'R' => GitFileStatus::Renamed,
+            'C' => GitFileStatus::Copied,
+            'U' => GitFileStatus::Unmerged,
+            _ => GitFileStatus::Unmodified,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub struct GitStatusRequest {
+    // Limit the status to these paths, the whole repository if empty
+    #[oai(default)]
+    #[serde(default)]
+    pub paths: Vec<String>,
+    #[oai(default)]
+    #[serde(default)]
+    pub include_ignored: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Object)]
+pub struct GitStatusEntry {
+    pub path: String,
+    // Only set for renames and copies
+    pub orig_path: Option<String>,
+    // Change in the index compared to HEAD
+    pub staged: GitFileStatus,
+    // Change in the working tree compared to the index
+    pub unstaged: GitFileStatus,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Object)]
+pub struct GitStatusResponse {
+    // None when HEAD is detached
+    pub branch: Option<String>,
+    // None before the first commit
+    pub head: Option<String>,
+    pub upstream: Option<String>,
+    pub ahead: usize,
+    pub behind: usize,
+    pub entries: Vec<GitStatusEntry>,
+}
+
+// Parse `git status --porcelain=v2 --branch -z`
+fn parse_status(output: &str) -> GitStatusResponse {
+    let mut status = GitStatusResponse::default();
+    let mut records = output.split('\0').filter(|r| !r.is_empty());
+    while let Some(record) = records.next() {
+        if let Some(header) = record.strip_prefix("# ") {
+            let (key, value) = header.split_once(' ').unwrap_or((header, ""));
+            match key {
+                "branch.oid" if value != "(initial)" => status.head = Some(value.to_string()),
+                "branch.head" if value != "(detached)" => status.branch = Some(value.to_string()),
+                "branch.upstream" => status.upstream = Some(value.to_string()),
+                "branch.ab" => {
+                    for count in value.split(' ') {
+                        if let Some(ahead) = count.strip_prefix('+') {
+                            status.ahead = ahead.parse().unwrap_or(0);
+                        } else if let Some(behind) = count.strip_prefix('-') {
+                            status.behind = behind.parse().unwrap_or(0);
+                        }
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+        let (kind, rest) = record.split_at(1);
+        let rest = rest.trim_start();
+        let entry = match kind {
+            "?" | "!" => GitStatusEntry {
+                path: rest.to_string(),
+                orig_path: None,
+                staged: GitFileStatus::Unmodified,
+                unstaged: match kind {
+                    "?" => GitFileStatus::Untracked,
+                    _ => GitFileStatus::Ignored,
+                },
+            },
+            // "1 XY sub mH mI mW hH hI path", renames and copies have an extra score field and
+            // the original path as the next record
+            "1" | "2" | "u" => {
+                let fields = match kind {
+                    "1" => 8,
+                    "2" => 9,
+                    _ => 10,
+                };
+                let parts: Vec<&str> = rest.splitn(fields, ' ').collect();
+                let mut codes = parts[0].chars();
+                let (staged, unstaged) = match kind {
+                    "u" => (GitFileStatus::Unmerged, GitFileStatus::Unmerged),
+                    _ => (
+                        GitFileStatus::from_code(codes.next().unwrap_or('.')),
+                        GitFileStatus::from_code(codes.next().unwrap_or('.')),
+                    ),
+                };
+                let orig_path = match kind {
+                    "2" => records.next().map(|p| p.to_string()),
+                    _ => None,
+                };
+                GitStatusEntry {
+                    path: parts.last().unwrap_or(&"").to_string(),
+                    orig_path,
+                    staged,
+                    unstaged,
+                }
+            }
+            _ => continue,
+        };
+        status.entries.push(entry);
+    }
+    status
+}
+
+impl GitStatusRequest {
+    pub async fn process(self) -> Result<GitStatusResponse, Box<dyn Error>> {
+        let specs = pathspecs(&self.paths).await?;
+        let mut args = vec!["status", "--porcelain=v2", "--branch", "-z"];
+        if self.include_ignored {
+            args.push("--ignored");
+        }
+        args.push("--");
+        args.extend(specs.iter().map(|s| s.as_str()));
+        let output = run_git(&args).await?;
+        Ok(parse_status(&output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::operations::git::utils::init_repo;
+
+    #[rstest::fixture]
+    fn tmp_dir() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let output = "# branch.oid 1234\0# branch.head main\0# branch.upstream origin/main\0\
+                      # branch.ab +2 -1\0\
+                      1 .M N... 100644 100644 100644 aaa bbb src/main.rs\0\
+                      2 R. N... 100644 100644 100644 aaa bbb R100 new name.rs\0old.rs\0\
+                      ? notes.txt\0";
+        let status = parse_status(output);
+        assert_eq!(status.branch.as_deref(), Some("main"));
+        assert_eq!(status.upstream.as_deref(), Some("origin/main"));
+        assert_eq!((status.ahead, status.behind), (2, 1));
+        assert_eq!(
+            status.entries,
+            vec![
+                GitStatusEntry {
+                    path: "src/main.rs".to_string(),
+                    orig_path: None,
+                    staged: GitFileStatus::Unmodified,
+                    unstaged: GitFileStatus::Modified,
+                },
+                GitStatusEntry {
+                    path: "new name.rs".to_string(),
+                    orig_path: Some("old.rs".to_string()),
+                    staged: GitFileStatus::Renamed,
+                    unstaged: GitFileStatus::Unmodified,
+                },
+                GitStatusEntry {
+                    path: "notes.txt".to_string(),
+                    orig_path: None,
+                    staged: GitFileStatus::Unmodified,
+                    unstaged: GitFileStatus::Untracked,
+                },
+            ]
+        );
+    }
+
+    #[rstest::rstest]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_status(_tmp_dir: TempDir) {
+        init_repo().await;
+        fs::write("staged.txt", "staged").unwrap();
+        fs::write("untracked.txt", "untracked").unwrap();
+        run_git(&["add", "staged.txt"]).await.unwrap();
+        let request = GitStatusRequest {
+            paths: vec![],
+            include_ignored: false,
+        };
+        let status = request.process().await.unwrap();
+        assert_eq!(status.branch.as_deref(), Some("main"));
+        assert_eq!(status.head, None);
+        assert_eq!(status.entries.len(), 2);
+        assert_eq!(status.entries[0].staged, GitFileStatus::Added);
+        assert_eq!(status.entries[1].unstaged, GitFileStatus::Untracked);
+    }
+}
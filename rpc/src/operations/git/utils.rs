@@ -0,0 +1,106 @@
+use std::{error::Error, path::PathBuf};
+
+use tokio::time::Duration;
+
+use crate::operations::{
+    commands::utils::{run_command, CommandOptions, CommandOutcome, CommandResult},
+    fs::utils::ensure_relative,
+};
+
+const GIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The git subcommand being run, skipping any leading `-c key=value` settings
+fn subcommand<'a>(args: &[&'a str]) -> &'a str {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "-c" => {
+                args.next();
+            }
+            arg => return arg,
+        }
+    }
+    ""
+}
+
+// Run git in the workspace, returning stdout or an error carrying git's own message
+pub async fn run_git(args: &[&str]) -> Result<String, Box<dyn Error>> {
+    run_git_with(args, &CommandOptions::default()).await
+}
+
+// run_git with extra environment (e.g. GIT_INDEX_FILE) or stdin
+pub async fn run_git_with(
+    args: &[&str],
+    options: &CommandOptions,
+) -> Result<String, Box<dyn Error>> {
+    let CommandResult {
+        stdout,
+        stderr,
+        outcome,
+        exit_status,
+        signal,
+        ..
+    } = run_command("git", args, options, GIT_TIMEOUT).await?;
+    match (outcome, exit_status) {
+        (CommandOutcome::Exited, Some(0)) => Ok(stdout),
+        (CommandOutcome::Exited, _) => {
+            // Some failures, like "nothing to commit", are only reported on stdout
+            let message = match stderr.trim() {
+                "" => stdout.trim(),
+                stderr => stderr,
+            };
+            Err(format!("git {} failed: {}", subcommand(args), message).into())
+        }
+        (CommandOutcome::TimedOut, _) => Err(format!("git {} timed out", subcommand(args)).into()),
+        (CommandOutcome::Signaled, _) => Err(format!(
+            "git {} was killed by signal {}",
+            subcommand(args),
+            signal.unwrap_or_default()
+        )
+        .into()),
+        (CommandOutcome::KilledByLimit, _) => {
+            Err(format!("git {} printed too much output", subcommand(args)).into())
+        }
+    }
+}
+
+// Revisions and branch names end up as git arguments, don't let them turn into options
+pub fn ensure_not_option(value: &str) -> Result<(), Box<dyn Error>> {
+    match value.is_empty() || value.starts_with('-') {
+        true => Err(format!("Invalid git revision or branch name {:?}", value).into()),
+        false => Ok(()),
+    }
+}
+
+// Turn request paths into pathspecs, relative to the workspace
+pub async fn pathspecs(paths: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut specs = Vec::new();
+    for path in paths {
+        let path = ensure_relative(PathBuf::from(path)).await?;
+        specs.push(path.to_string_lossy().to_string());
+    }
+    Ok(specs)
+}
+
+// Name of the checked out branch, None when HEAD is detached (or there are no commits yet and
+// git can't tell)
+pub async fn current_branch() -> Option<String> {
+    let branch = run_git(&["symbolic-ref", "--quiet", "--short", "HEAD"])
+        .await
+        .ok()?;
+    Some(branch.trim().to_string())
+}
+
+pub async fn head_hash() -> Result<String, Box<dyn Error>> {
+    Ok(run_git(&["rev-parse", "HEAD"]).await?.trim().to_string())
+}
+
+// A repository in the current directory with an identity set, for tests
+#[cfg(test)]
+pub async fn init_repo() {
+    run_git(&["init", "-q", "-b", "main"]).await.unwrap();
+    run_git(&["config", "user.name", "Test"]).await.unwrap();
+    run_git(&["config", "user.email", "test@example.com"])
+        .await
+        .unwrap();
+}
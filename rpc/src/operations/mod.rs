@@ -1,3 +1,4 @@
 pub mod commands;
 pub mod fs;
+pub mod git;
 pub mod time;
@@ -0,0 +1,129 @@
+//! Settings for operations that the Agent reads from its config at startup, e.g. which
+//! programs RunCommand may run. Operations read them with `get`, so they can't be changed over
+//! RPC by the LLM.
+use std::{collections::HashMap, sync::RwLock};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    static ref SETTINGS: RwLock<OperationSettings> = RwLock::new(OperationSettings::default());
+}
+
+// A program RunCommand is allowed to run, optionally limited to arguments matching a pattern
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandRule {
+    pub program: String,
+    // Regex the arguments (joined by single spaces) have to match in full, any arguments if unset
+    #[serde(default)]
+    pub args_pattern: Option<String>,
+    // Environment variables a request may set for the command, none if unset. Variables like
+    // RUSTC_WRAPPER, NODE_OPTIONS or BASH_ENV make a program run something else, so each has to
+    // be allowed explicitly
+    #[serde(default)]
+    pub allowed_env: Vec<String>,
+}
+
+impl CommandRule {
+    pub fn matches(&self, program: &str, args: &[String]) -> bool {
+        if self.program != program {
+            return false;
+        }
+        match &self.args_pattern {
+            None => true,
+            Some(pattern) => match Regex::new(&format!("^(?:{})$", pattern)) {
+                Ok(regex) => regex.is_match(&args.join(" ")),
+                // A broken pattern in the config shouldn't allow anything
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OperationSettings {
+    pub allowed_commands: Vec<CommandRule>,
+    // Shells open at the same time, ShellOpen fails past this. 0 by default: a shell runs
+    // anything, so it has to be turned on explicitly
+    pub max_shells: usize,
+    // Shells unused for this long are closed
+    pub shell_idle_timeout_secs: u64,
+    // What RunPython runs, e.g. a project's .venv/bin/python (relative to the workspace)
+    pub python_interpreter: String,
+    // Format's command per file extension, e.g. "rs" = ["rustfmt", "--edition", "2021"]. The
+    // file's content is piped to it and the formatted content read back from stdout, with
+    // `{path}` in the arguments replaced by the file's path
+    pub formatters: HashMap<String, Vec<String>>,
+}
+
+impl Default for OperationSettings {
+    fn default() -> Self {
+        Self {
+            allowed_commands: vec![],
+            max_shells: 0,
+            shell_idle_timeout_secs: 600,
+            python_interpreter: "python".to_string(),
+            formatters: default_formatters(),
+        }
+    }
+}
+
+fn default_formatters() -> HashMap<String, Vec<String>> {
+    let command = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<String>>();
+    let mut formatters = HashMap::new();
+    formatters.insert("rs".to_string(), command(&["rustfmt", "--edition", "2021"]));
+    let black = command(&["black", "--quiet", "--stdin-filename", "{path}", "-"]);
+    formatters.insert("py".to_string(), black);
+    for extension in ["js", "jsx", "ts", "tsx", "json", "css"] {
+        let prettier = command(&["prettier", "--stdin-filepath", "{path}"]);
+        formatters.insert(extension.to_string(), prettier);
+    }
+    formatters
+}
+
+pub fn set(settings: OperationSettings) {
+    *SETTINGS.write().unwrap() = settings;
+}
+
+pub fn get() -> OperationSettings {
+    SETTINGS.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_command_rule() {
+        let any = CommandRule {
+            program: "make".to_string(),
+            args_pattern: None,
+            allowed_env: vec![],
+        };
+        assert!(any.matches("make", &args(&["clean", "all"])));
+        assert!(!any.matches("rm", &args(&["-rf", "."])));
+
+        let cargo = CommandRule {
+            program: "cargo".to_string(),
+            args_pattern: Some("(build|test|check)( .*)?".to_string()),
+            allowed_env: vec![],
+        };
+        assert!(cargo.matches("cargo", &args(&["test", "--workspace"])));
+        assert!(cargo.matches("cargo", &args(&["check"])));
+        assert!(!cargo.matches("cargo", &args(&["install", "evil"])));
+        assert!(!cargo.matches("cargo", &args(&["xtest"])));
+
+        let broken = CommandRule {
+            program: "cargo".to_string(),
+            args_pattern: Some("(".to_string()),
+            allowed_env: vec![],
+        };
+        assert!(!broken.matches("cargo", &args(&["test"])));
+    }
+}